@@ -0,0 +1,281 @@
+use std::ops::Range;
+use std::sync::OnceLock;
+
+/// A single annotated interval: its half-open `[start, end)` span plus a
+/// caller-supplied payload (e.g. a gene number), mirroring the `(range,
+/// data)` pairs this replaces `bio::data_structures::interval_tree::
+/// IntervalTree` with.
+#[derive(Debug, Clone)]
+struct Raw<D> {
+    start: u32,
+    end: u32,
+    data: D,
+}
+
+/// One level of the Augmented Interval List (AIList, ScAIList-style):
+/// intervals sorted by start, with a running maximum-end prefix so a query
+/// can binary-search to the first interval that could possibly overlap and
+/// then stop as soon as `max_end` falls before the query start.
+struct Component<D> {
+    entries: Vec<Raw<D>>,
+    /// `max_ends[i]` is `max(entries[0..=i].end)`, i.e. the running maximum.
+    max_ends: Vec<u32>,
+}
+
+impl<D> Component<D> {
+    fn new(mut entries: Vec<Raw<D>>) -> Self {
+        entries.sort_by(|a, b| a.start.cmp(&b.start).then_with(|| a.end.cmp(&b.end)));
+        let mut max_ends = Vec::with_capacity(entries.len());
+        let mut running_max = 0u32;
+        for e in &entries {
+            running_max = running_max.max(e.end);
+            max_ends.push(running_max);
+        }
+        Component { entries, max_ends }
+    }
+
+    /// Finds every interval in this component overlapping `query`, passing
+    /// each to `visit`. Binary-searches to the last interval that starts
+    /// before `query.end`, then walks backwards, stopping as soon as
+    /// `max_ends` shows no earlier interval can reach into the query either.
+    fn find(&self, query: &Range<u32>, visit: &mut impl FnMut(&D, Range<u32>)) {
+        let mut i = self.entries.partition_point(|e| e.start < query.end);
+        while i > 0 {
+            i -= 1;
+            if self.max_ends[i] <= query.start {
+                // no interval at or before `i` can reach past query.start.
+                break;
+            }
+            let e = &self.entries[i];
+            if e.end > query.start && e.start < query.end {
+                visit(&e.data, e.start..e.end);
+            }
+        }
+    }
+}
+
+const DEFAULT_ENGULFED_THRESHOLD: usize = 20;
+const DEFAULT_MAX_LEVELS: usize = 8;
+
+/// Scans `sorted` (already sorted by start) once, marking an interval as
+/// "engulfed" when more than `threshold` of the intervals following it in
+/// start order begin inside its span and reach at least as far as its end.
+/// Returns `(engulfed, remaining)`, both still sorted by start.
+fn extract_engulfed<D>(sorted: Vec<Raw<D>>, threshold: usize) -> (Vec<Raw<D>>, Vec<Raw<D>>) {
+    let mut engulfed_flags = vec![false; sorted.len()];
+    for i in 0..sorted.len() {
+        let mut covering = 0usize;
+        for j in (i + 1)..sorted.len() {
+            if sorted[j].start >= sorted[i].end {
+                // successors only start later, and this one already starts
+                // past entry i's end, so it (and everything after) can't
+                // contain i either.
+                break;
+            }
+            if sorted[j].end >= sorted[i].end {
+                // successor j starts at/after i but extends at least as far,
+                // i.e. i is contained within j.
+                covering += 1;
+                if covering > threshold {
+                    break;
+                }
+            }
+        }
+        if covering > threshold {
+            engulfed_flags[i] = true;
+        }
+    }
+    let mut engulfed = Vec::new();
+    let mut remaining = Vec::new();
+    for (entry, flag) in sorted.into_iter().zip(engulfed_flags) {
+        if flag {
+            engulfed.push(entry);
+        } else {
+            remaining.push(entry);
+        }
+    }
+    (engulfed, remaining)
+}
+
+fn build_components<D>(mut remaining: Vec<Raw<D>>) -> Vec<Component<D>> {
+    remaining.sort_by(|a, b| a.start.cmp(&b.start).then_with(|| a.end.cmp(&b.end)));
+    let mut components = Vec::new();
+    for _ in 0..DEFAULT_MAX_LEVELS {
+        if remaining.is_empty() {
+            break;
+        }
+        let (engulfed, rest) = extract_engulfed(remaining, DEFAULT_ENGULFED_THRESHOLD);
+        remaining = rest;
+        if engulfed.is_empty() {
+            // no further peeling possible - keep the rest as the last component.
+            break;
+        }
+        components.push(Component::new(engulfed));
+    }
+    if !remaining.is_empty() {
+        components.push(Component::new(remaining));
+    }
+    components
+}
+
+/// A single hit returned from [`AIList::find`], mirroring the `Entry` type
+/// `IntervalTree::find` used to return: `.data()` for the payload,
+/// `.interval()` for the `[start, end)` span of the annotation feature that
+/// overlapped (not the query).
+pub struct Hit<D> {
+    interval: Range<u32>,
+    data: D,
+}
+
+impl<D> Hit<D> {
+    pub fn data(&self) -> &D {
+        &self.data
+    }
+
+    pub fn interval(&self) -> Range<u32> {
+        self.interval.clone()
+    }
+}
+
+/// Augmented Interval List (AIList / ScAIList) backend for per-chromosome
+/// annotation overlap queries, replacing `bio::data_structures::
+/// interval_tree::IntervalTree` as `engine::OurTree` - bounds worst-case
+/// query cost when a handful of very long features (e.g. a genome-spanning
+/// annotation, or a gene whose introns engulf dozens of smaller genes) would
+/// otherwise force a plain sorted-by-start + `max_end` index to scan past
+/// all of them on every query.
+///
+/// Construction recursively peels off intervals that are "engulfed" -
+/// contained within more than `threshold` of the intervals immediately
+/// following them in start order - into a separate sub-list, and repeats on
+/// the remainder up to `max_levels` times. Each resulting list is a
+/// [`Component`] queried independently; a query touches every component,
+/// but in practice only the components actually containing long engulfing
+/// features stay large, so most queries resolve against a small top
+/// component without being slowed down by the long tail.
+///
+/// Matches `IntervalTree`'s build-once-query-many usage in
+/// `engine::build_trees_from_gtf`/`build_trees_from_gtf_merged`: `insert`
+/// appends to a pending list, and the component layout above is built
+/// lazily on the first `find` and cached in a `OnceLock` so `find` can
+/// stay `&self` like `IntervalTree::find` did, and the list can be shared
+/// by the engine's worker threads; a later `insert` (there
+/// isn't one once quantification starts, but nothing here assumes it)
+/// invalidates the cache so the next `find` rebuilds from the full pending
+/// set rather than silently missing entries added after the first query.
+pub struct AIList<D> {
+    pending: Vec<Raw<D>>,
+    built: OnceLock<Vec<Component<D>>>,
+}
+
+impl<D> Default for AIList<D> {
+    fn default() -> Self {
+        AIList {
+            pending: Vec::new(),
+            built: OnceLock::new(),
+        }
+    }
+}
+
+impl<D> AIList<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, range: Range<u32>, data: D) {
+        self.pending.push(Raw {
+            start: range.start,
+            end: range.end,
+            data,
+        });
+        self.built = OnceLock::new();
+    }
+}
+
+impl<D: Clone> AIList<D> {
+    /// Visits every interval overlapping `query`, mirroring
+    /// `IntervalTree::find`'s iterator-of-`Entry` API.
+    pub fn find(&self, query: Range<u32>) -> Vec<Hit<D>> {
+        let components = self
+            .built
+            .get_or_init(|| build_components(self.pending.clone()));
+        let mut hits = Vec::new();
+        for component in components {
+            component.find(&query, &mut |data, interval| {
+                hits.push(Hit {
+                    interval,
+                    data: data.clone(),
+                });
+            });
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sorted_hits(list: &AIList<&'static str>, query: Range<u32>) -> Vec<(Range<u32>, &'static str)> {
+        let mut hits: Vec<_> = list
+            .find(query)
+            .into_iter()
+            .map(|h| (h.interval(), *h.data()))
+            .collect();
+        hits.sort_by_key(|(iv, _)| iv.start);
+        hits
+    }
+
+    #[test]
+    fn test_find_overlapping() {
+        let mut list = AIList::new();
+        list.insert(10..20, "a");
+        list.insert(15..25, "b");
+        list.insert(100..200, "c");
+
+        assert_eq!(sorted_hits(&list, 16..17), vec![(10..20, "a"), (15..25, "b")]);
+        assert_eq!(sorted_hits(&list, 0..5), vec![]);
+        assert_eq!(sorted_hits(&list, 150..160), vec![(100..200, "c")]);
+    }
+
+    #[test]
+    fn test_half_open_boundaries_are_exclusive() {
+        let mut list = AIList::new();
+        list.insert(10..20, "a");
+
+        // touches only at the boundary - [10,20) and [20,30) don't overlap.
+        assert_eq!(sorted_hits(&list, 20..30), vec![]);
+        assert_eq!(sorted_hits(&list, 0..10), vec![]);
+        assert_eq!(sorted_hits(&list, 19..20), vec![(10..20, "a")]);
+    }
+
+    #[test]
+    fn test_engulfed_interval_still_found_once_peeled_into_its_own_component() {
+        // `short` starts before more than `DEFAULT_ENGULFED_THRESHOLD` other
+        // intervals that each reach far beyond its own end, so
+        // `extract_engulfed` peels it into a separate component from the
+        // `long_*` ones - exercising the multi-component `find` path, not
+        // just a single `Component::find`.
+        let mut list = AIList::new();
+        list.insert(0..10, "short");
+        for _ in 0..25u32 {
+            list.insert(0..1_000_000, "long");
+        }
+
+        let hits = sorted_hits(&list, 5..6);
+        assert_eq!(hits.iter().filter(|(_, d)| *d == "short").count(), 1);
+        assert_eq!(hits.iter().filter(|(_, d)| *d == "long").count(), 25);
+    }
+
+    #[test]
+    fn test_rebuilds_after_insert_following_a_find() {
+        let mut list = AIList::new();
+        list.insert(10..20, "a");
+        assert_eq!(sorted_hits(&list, 12..13), vec![(10..20, "a")]);
+
+        // a `find` after this insert must see "b" too, not a stale cache
+        // from the first `find` above.
+        list.insert(12..18, "b");
+        assert_eq!(sorted_hits(&list, 12..13), vec![(10..20, "a"), (12..18, "b")]);
+    }
+}
@@ -1,70 +1,241 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use rust_htslib::bam;
-use rust_htslib::bam::ext::BamRecordExtensions as htslib_record_extensions;
 
 pub trait BamRecordExtensions {
     fn blocks(&self) -> Vec<(u32, u32)>;
+    /// zero-allocation version of `blocks()`, walking the CIGAR lazily
+    fn blocks_iter(&self) -> impl Iterator<Item = (u32, u32)> + '_;
     ///find intron positions (start, stop)
     #[allow(dead_code)] //todo: check
     fn introns(&self) -> Vec<(u32, u32)>;
+    /// zero-allocation version of `introns()`, walking the CIGAR lazily
+    fn introns_iter(&self) -> impl Iterator<Item = (u32, u32)> + '_;
 
     ///return None on unaligned reads (pos = -1...)
-    fn corrected_pos(&self, max_skip_len: u32) -> Option<i32>;
+    fn corrected_pos(&self, max_skip_len: u32) -> Result<Option<i32>>;
+
+    ///symmetric counterpart to `corrected_pos`: extends the alignment end by
+    ///trailing clips. Returns None on unaligned reads (pos = -1...)
+    fn corrected_end(&self, max_skip_len: u32) -> Result<Option<i32>>;
+
+    /// Yields one entry per read base in query order, mapping it to its
+    /// reference coordinate (or `None` for insertions/soft-clips),
+    /// analogous to pysam's `get_reference_positions(full_length=True)`.
+    /// The per-base primitive underlying UMI/position dedup and coverage
+    /// profiles, neither of which can be derived from the block-level
+    /// `blocks()`/`introns()` output.
+    fn reference_positions(&self) -> impl Iterator<Item = Option<i64>> + '_;
+
+    fn no_of_alignments(&self) -> u32 {
+        self.no_of_alignments_via(&[b"NH".as_ref(), b"IH".as_ref()], 1)
+    }
+
+    /// Consults `tags` in order, returning the first one present as an
+    /// alignment count (accepting U8/U16/U32/I8/I16/I32 representations, and
+    /// panicking on a negative or non-integer value), or `default` if none
+    /// of `tags` is present on this read.
+    fn no_of_alignments_via(&self, tags: &[&[u8]], default: u32) -> u32;
+
+    /// Fractional weight to assign this read under EM/fractional multi-mapper
+    /// counting, i.e. `1.0 / no_of_alignments()`.
+    fn multimapper_weight(&self) -> f64 {
+        1.0 / f64::from(self.no_of_alignments())
+    }
 
-    fn no_of_alignments(&self) -> u32;
     fn replace_aux(&mut self, tag: &[u8], value: bam::record::Aux) -> Result<()>;
 }
 
+/// Walks a record's CIGAR, tracking a running reference position and emitting
+/// one `(start, stop)` span per contiguous run of `M`/`=`/`X` ops. A `D` (and
+/// `N`) op closes the current span without starting an intron - `D` is purely
+/// a block boundary, matching `blocks()`'s existing behaviour of splitting on
+/// deletions (see e.g. the `7M2D44M` case in the test suite below).
+fn cigar_blocks(record: &bam::Record) -> impl Iterator<Item = (u32, u32)> + '_ {
+    let mut ref_pos = record.pos() as u32;
+    let mut cigar = record.cigar().into_iter();
+    let mut current: Option<(u32, u32)> = None;
+    std::iter::from_fn(move || loop {
+        match cigar.next() {
+            Some(op) => match op {
+                bam::record::Cigar::Match(len)
+                | bam::record::Cigar::Equal(len)
+                | bam::record::Cigar::Diff(len) => {
+                    let (start, _) = current.unwrap_or((ref_pos, ref_pos));
+                    ref_pos += len;
+                    current = Some((start, ref_pos));
+                }
+                bam::record::Cigar::RefSkip(len) | bam::record::Cigar::Del(len) => {
+                    ref_pos += len;
+                    if let Some(block) = current.take() {
+                        return Some(block);
+                    }
+                }
+                _ => {}
+            },
+            None => return current.take(),
+        }
+    })
+}
+
+/// Walks a record's CIGAR, tracking a running reference position and
+/// emitting one `(start, stop)` span per `N` (`RefSkip`) op - i.e. the
+/// intron itself, not the gap between blocks in general (a `D` closes a
+/// block but is not an intron).
+fn cigar_introns(record: &bam::Record) -> impl Iterator<Item = (u32, u32)> + '_ {
+    let mut ref_pos = record.pos() as u32;
+    let mut cigar = record.cigar().into_iter();
+    std::iter::from_fn(move || loop {
+        match cigar.next() {
+            Some(bam::record::Cigar::RefSkip(len)) => {
+                let start = ref_pos;
+                ref_pos += len;
+                return Some((start, ref_pos));
+            }
+            Some(
+                bam::record::Cigar::Match(len)
+                | bam::record::Cigar::Equal(len)
+                | bam::record::Cigar::Diff(len)
+                | bam::record::Cigar::Del(len),
+            ) => {
+                ref_pos += len;
+            }
+            Some(_) => {}
+            None => return None,
+        }
+    })
+}
+
+/// Walks a record's CIGAR base by base in query order, advancing the
+/// reference cursor on `M`/`=`/`X`/`D`/`N` and the query cursor on
+/// `M`/`=`/`X`/`I`/`S`, emitting the current reference position for query
+/// bases that consume the reference (`M`/`=`/`X`) and `None` for query bases
+/// that don't (`I`/`S`). `D`/`N` consume the reference but have no
+/// corresponding query base, so they emit nothing; `H`/`P` consume neither.
+fn cigar_reference_positions(record: &bam::Record) -> impl Iterator<Item = Option<i64>> + '_ {
+    let mut ref_pos = record.pos();
+    let mut cigar = record.cigar().into_iter();
+    let mut remaining: u32 = 0;
+    let mut current_is_aligned = false;
+    std::iter::from_fn(move || loop {
+        if remaining > 0 {
+            remaining -= 1;
+            if current_is_aligned {
+                let pos = ref_pos;
+                ref_pos += 1;
+                return Some(Some(pos));
+            }
+            return Some(None);
+        }
+        match cigar.next() {
+            Some(
+                bam::record::Cigar::Match(len)
+                | bam::record::Cigar::Equal(len)
+                | bam::record::Cigar::Diff(len),
+            ) => {
+                remaining = len;
+                current_is_aligned = true;
+            }
+            Some(bam::record::Cigar::Ins(len) | bam::record::Cigar::SoftClip(len)) => {
+                remaining = len;
+                current_is_aligned = false;
+            }
+            Some(bam::record::Cigar::Del(len) | bam::record::Cigar::RefSkip(len)) => {
+                ref_pos += len as i64;
+            }
+            Some(_) => {} // HardClip, Pad: consume neither cursor
+            None => return None,
+        }
+    })
+}
+
 impl BamRecordExtensions for bam::Record {
     fn blocks(&self) -> Vec<(u32, u32)> {
-        self.aligned_blocks()
-            .map(|x| (x[0] as u32, x[1] as u32))
-            .collect()
+        self.blocks_iter().collect()
+    }
+
+    fn blocks_iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        cigar_blocks(self)
     }
 
     fn introns(&self) -> Vec<(u32, u32)> {
-        htslib_record_extensions::introns(self)
-            .map(|x| (x[0] as u32, x[1] as u32))
-            .collect()
+        self.introns_iter().collect()
+    }
+
+    fn introns_iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        cigar_introns(self)
     }
 
     /// correct the position to what it would have if there was on clipping
     /// ie. if they were treated as mismatches
     /// i32 is ok, sam raneg is 0..2^31-1, and we can have negative corrected positions
     /// if it's aligned to the start
-    fn corrected_pos(&self, max_skip_len: u32) -> Option<i32> {
-        let p:i32 = self.pos().try_into().expect("bam pos exceeded i32?");
+    fn corrected_pos(&self, max_skip_len: u32) -> Result<Option<i32>> {
+        let p: i32 = self.pos().try_into().context("bam pos exceeded i32?")?;
         if p < 0 {
-            None
-        } else {
-            //it's always the leading ones... since the seq gets flipped
+            return Ok(None);
+        }
+        //it's always the leading ones... since the seq gets flipped
+        let cigar = self.cigar();
+        let skip: i32 = (cigar.leading_softclips() + cigar.leading_hardclips())
+            .try_into()
+            .context("leading clip length exceeded i32")?;
+        if skip > max_skip_len.try_into().unwrap() {
+            bail!(
+                "Read {} has leading clips > max_skip_len ({skip}>{max_skip_len}). Increase the setting via input.max_skip_length. Or filter the reads?",
+                String::from_utf8_lossy(self.qname()),
+            )
+        }
+        Ok(Some(p.saturating_sub(skip)))
+    }
 
-            let skip: i32 = self.cigar().leading_softclips().try_into().expect("softclip exceeded i64");
-            if skip > max_skip_len.try_into().unwrap(){
-                panic!("Your reads have skipped regions > max_skip_len ({skip}>{max_skip_len}). Increase the setting via input.max_skip_length. Or filter the reads?")
-            }
-            Some(p.saturating_sub(skip))
+    /// symmetric counterpart to `corrected_pos`: extends the alignment end
+    /// by trailing clips (soft and hard), so reads near contig boundaries
+    /// can be assigned consistently on both strands.
+    fn corrected_end(&self, max_skip_len: u32) -> Result<Option<i32>> {
+        let p: i32 = self.pos().try_into().context("bam pos exceeded i32?")?;
+        if p < 0 {
+            return Ok(None);
         }
+        let cigar = self.cigar();
+        let end: i32 = cigar.end_pos().try_into().context("end pos exceeded i32")?;
+        let skip: i32 = (cigar.trailing_softclips() + cigar.trailing_hardclips())
+            .try_into()
+            .context("trailing clip length exceeded i32")?;
+        if skip > max_skip_len.try_into().unwrap() {
+            bail!(
+                "Read {} has trailing clips > max_skip_len ({skip}>{max_skip_len}). Increase the setting via input.max_skip_length. Or filter the reads?",
+                String::from_utf8_lossy(self.qname()),
+            )
+        }
+        Ok(Some(end.saturating_add(skip)))
     }
-    /// try to retrieve the number of mapping coordinates
-    /// for this read. Uses the NH tag. defaults to 1
-    fn no_of_alignments(&self) -> u32 {
-        //let's try the NH tag, as by the tag spec
-        if let Ok(nh) = self.aux(b"NH") {
-            match nh {
-                bam::record::Aux::U8(x) => x as u32,
-                bam::record::Aux::U16(x) => x as u32,
-                bam::record::Aux::U32(x) => x,
-                /* bam::record::Aux::I8(x) => x as u32,
-                bam::record::Aux::I16(x) => x as u32,
-                bam::record::Aux::I32(x) => x, */
-                _ => {
-                    panic!("Mapping coordinate tag NH wasn't an unsigned int.");
-                }
+
+    fn reference_positions(&self) -> impl Iterator<Item = Option<i64>> + '_ {
+        cigar_reference_positions(self)
+    }
+    /// try to retrieve the number of mapping coordinates for this read,
+    /// consulting `tags` in order and falling back to `default` if none of
+    /// them are present (as by the NH/IH tag spec)
+    fn no_of_alignments_via(&self, tags: &[&[u8]], default: u32) -> u32 {
+        for tag in tags {
+            if let Ok(value) = self.aux(tag) {
+                return match value {
+                    bam::record::Aux::U8(x) => x as u32,
+                    bam::record::Aux::U16(x) => x as u32,
+                    bam::record::Aux::U32(x) => x,
+                    bam::record::Aux::I8(x) => u32::try_from(x)
+                        .unwrap_or_else(|_| panic!("Mapping coordinate tag was negative.")),
+                    bam::record::Aux::I16(x) => u32::try_from(x)
+                        .unwrap_or_else(|_| panic!("Mapping coordinate tag was negative.")),
+                    bam::record::Aux::I32(x) => u32::try_from(x)
+                        .unwrap_or_else(|_| panic!("Mapping coordinate tag was negative.")),
+                    _ => {
+                        panic!("Mapping coordinate tag wasn't an integer.");
+                    }
+                };
             }
-        } else {
-            1 // we can't tell.
         }
+        default // we can't tell.
     }
 
     fn replace_aux(&mut self, tag: &[u8], value: bam::record::Aux) -> Result<()> {
@@ -1,5 +1,8 @@
 use anyhow::{Context, Result};
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
 
 use crate::extractors::{self, UMIExtractor};
 use serde::Deserializer;
@@ -41,6 +44,15 @@ where
 
 type Whitelist = HashSet<Vec<u8>>;
 
+/// Per-segment whitelist(s) a cell barcode is corrected against (e.g. the
+/// 10x barcode list). `whitelist_files` empty means "no whitelist
+/// configured", in which case `correct`/`correct_read` pass the observed
+/// barcode through unchanged. Otherwise an exact whitelist hit is used
+/// verbatim, and anything else goes through `find_closest_by_hamming`,
+/// which rescues it to the unambiguous closest entry within `max_hamming`
+/// substitutions (`max_hamming` defaults to 0, which disables rescue) and
+/// falls back to `BarcodeNotInWhitelist`/`barcode_not_in_whitelist`
+/// accounting when no single whitelist entry is close enough.
 #[derive(serde::Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct CellBarcodes {
@@ -54,11 +66,39 @@ pub struct CellBarcodes {
 
     #[serde(skip)]
     whitelists: Vec<Whitelist>,
+
+    /// For each whitelist, maps every sequence that is exactly one A/C/G/T
+    /// substitution away from a whitelist entry to the list of whitelist
+    /// entries it was derived from. Lets the (by far most common)
+    /// distance-<=1 correction be a hash lookup instead of a linear scan
+    /// over the whole whitelist.
+    #[serde(skip)]
+    neighbor_maps: Vec<HashMap<Vec<u8>, Vec<Vec<u8>>>>,
+}
+
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+fn build_neighbor_map(whitelist: &Whitelist) -> HashMap<Vec<u8>, Vec<Vec<u8>>> {
+    let mut neighbors: HashMap<Vec<u8>, Vec<Vec<u8>>> = HashMap::new();
+    for entry in whitelist.iter() {
+        for pos in 0..entry.len() {
+            let original = entry[pos];
+            for &base in BASES.iter() {
+                if base == original {
+                    continue;
+                }
+                let mut mutated = entry.clone();
+                mutated[pos] = base;
+                neighbors.entry(mutated).or_default().push(entry.clone());
+            }
+        }
+    }
+    neighbors
 }
 
 impl CellBarcodes {
     pub fn init(&mut self) -> Result<()> {
-        let wl: Result<_> = self
+        let wl: Result<Vec<Whitelist>> = self
             .whitelist_files
             .iter()
             .map(|file| {
@@ -69,7 +109,9 @@ impl CellBarcodes {
                     .collect::<HashSet<_>>())
             })
             .collect();
-        self.whitelists = wl?;
+        let wl = wl?;
+        self.neighbor_maps = wl.iter().map(build_neighbor_map).collect();
+        self.whitelists = wl;
         Ok(())
     }
 
@@ -81,7 +123,33 @@ impl CellBarcodes {
         self.extract.extract(read)
     }
 
+    /// Base qualities aligned with `extract()`'s output, when the extractor
+    /// is precise enough to know where the barcode sits in the read (only
+    /// `ReadRegion` currently is). `None` means quality-aware disambiguation
+    /// is unavailable and ties must be rejected outright.
+    fn extract_qualities(&self, read: &rust_htslib::bam::record::Record) -> Option<Vec<u8>> {
+        match &self.extract {
+            extractors::UMIExtraction::ReadRegion(rr) => {
+                let (start, stop) = rr.range();
+                Some(read.qual()[start as usize..stop as usize].to_vec())
+            }
+            _ => None,
+        }
+    }
+
     pub fn correct(&self, barcode: &[u8]) -> Option<Vec<u8>> {
+        self.correct_with_qualities(barcode, None)
+    }
+
+    pub fn correct_read(
+        &self,
+        barcode: &[u8],
+        read: &rust_htslib::bam::record::Record,
+    ) -> Option<Vec<u8>> {
+        self.correct_with_qualities(barcode, self.extract_qualities(read).as_deref())
+    }
+
+    fn correct_with_qualities(&self, barcode: &[u8], qualities: Option<&[u8]>) -> Option<Vec<u8>> {
         // possibly microopt: use cow...
         if self.whitelists.is_empty() {
             if barcode.is_empty() {
@@ -92,14 +160,19 @@ impl CellBarcodes {
         }
         let parts = barcode.split(|&b| b == self.separator_char);
         let mut out = Vec::new();
-        for (part, whitelist) in parts.zip(self.whitelists.iter()) {
+        let mut offset = 0usize;
+        for (part, (whitelist, neighbors)) in
+            parts.zip(self.whitelists.iter().zip(self.neighbor_maps.iter()))
+        {
+            let part_quality = qualities.map(|q| &q[offset..offset + part.len()]);
+            offset += part.len() + 1; // +1 accounts for the separator we consumed
             if whitelist.contains(part) {
                 if !out.is_empty() {
                     out.push(self.separator_char);
                 }
                 out.extend(part);
             } else {
-                match self.find_closest_by_hamming(part, whitelist) {
+                match self.find_closest_by_hamming(part, part_quality, whitelist, neighbors) {
                     Some(corrected) => {
                         if !out.is_empty() {
                             out.push(self.separator_char);
@@ -113,18 +186,201 @@ impl CellBarcodes {
         Some(out)
     }
 
-    fn find_closest_by_hamming<'a>(
+    /// Finds the unambiguous closest whitelist entry for `part`. Rejects
+    /// (returns `None`) whenever two or more whitelist entries tie at the
+    /// minimum distance found - unless that distance is 1 and base qualities
+    /// are available, in which case the 10x/CellRanger rule applies: pick the
+    /// candidate whose single mismatching base has the lowest quality, and
+    /// still reject if that, too, is ambiguous.
+    fn find_closest_by_hamming(
         &self,
         part: &[u8],
-        whitelist: &'a Whitelist,
-    ) -> Option<&'a [u8]> {
+        part_quality: Option<&[u8]>,
+        whitelist: &Whitelist,
+        neighbors: &HashMap<Vec<u8>, Vec<Vec<u8>>>,
+    ) -> Option<Vec<u8>> {
         use bio::alignment::distance::hamming;
         if self.max_hamming == 0 {
             return None; // No correction allowed
         }
-        whitelist
-            .iter()
-            .find(|&entry| hamming(entry, part) <= self.max_hamming as u64)
-            .map(|v| v.as_slice())
+        // Fast path: hash lookup for the overwhelmingly common distance-1 case.
+        if let Some(candidates) = neighbors.get(part) {
+            return match candidates.as_slice() {
+                [single] => Some(single.clone()),
+                multiple => pick_by_lowest_quality_mismatch(part, part_quality, multiple),
+            };
+        }
+        if self.max_hamming <= 1 {
+            return None;
+        }
+        // Slow path: no distance-1 candidate, but a larger max_hamming was
+        // configured, so fall back to a full scan for the minimum distance.
+        let mut best_distance = u64::MAX;
+        let mut best: Vec<&Vec<u8>> = Vec::new();
+        for entry in whitelist.iter() {
+            let d = hamming(entry, part);
+            if d > self.max_hamming as u64 {
+                continue;
+            }
+            match d.cmp(&best_distance) {
+                std::cmp::Ordering::Less => {
+                    best_distance = d;
+                    best = vec![entry];
+                }
+                std::cmp::Ordering::Equal => best.push(entry),
+                std::cmp::Ordering::Greater => {}
+            }
+        }
+        match best.as_slice() {
+            [single] => Some((*single).clone()),
+            _ => None, // no candidate, or an ambiguous tie at the minimum distance
+        }
+    }
+}
+
+/// Disambiguates several whitelist entries that are all exactly one
+/// substitution away from `part`, using the CellRanger rule: the correction
+/// is only unambiguous if exactly one candidate's mismatching base has
+/// strictly the lowest quality among the candidates.
+fn pick_by_lowest_quality_mismatch(
+    part: &[u8],
+    part_quality: Option<&[u8]>,
+    candidates: &[Vec<u8>],
+) -> Option<Vec<u8>> {
+    let qualities = part_quality?;
+    let mismatch_quality = |candidate: &Vec<u8>| -> u8 {
+        part.iter()
+            .zip(candidate.iter())
+            .zip(qualities.iter())
+            .find(|((a, b), _)| a != b)
+            .map(|(_, &q)| q)
+            .unwrap_or(u8::MAX)
+    };
+    let mut by_quality: Vec<(u8, &Vec<u8>)> = candidates
+        .iter()
+        .map(|c| (mismatch_quality(c), c))
+        .collect();
+    by_quality.sort_by_key(|(q, _)| *q);
+    let lowest = by_quality[0].0;
+    let tied_at_lowest = by_quality.iter().filter(|(q, _)| *q == lowest).count();
+    if tied_at_lowest == 1 {
+        Some(by_quality[0].1.clone())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Builds a single-segment `CellBarcodes` against `entries`, bypassing
+    /// `init()`'s file I/O - `extract` is never exercised by these tests
+    /// (they call `correct`/`correct_with_qualities` directly), so a
+    /// `ReadRegion` built via `toml::from_str` just satisfies the field.
+    fn make(entries: &[&str], separator_char: u8, max_hamming: u16) -> CellBarcodes {
+        let whitelist: Whitelist = entries.iter().map(|s| s.as_bytes().to_vec()).collect();
+        let neighbor_map = build_neighbor_map(&whitelist);
+        CellBarcodes {
+            extract: extractors::UMIExtraction::ReadRegion(
+                toml::from_str("start = 0\nstop = 4").unwrap(),
+            ),
+            separator_char,
+            max_hamming,
+            whitelist_files: Vec::new(),
+            whitelists: vec![whitelist],
+            neighbor_maps: vec![neighbor_map],
+        }
+    }
+
+    #[test]
+    fn test_exact_whitelist_hit_passes_through_unchanged() {
+        let cb = make(&["AAAA", "CCCC"], b'-', 1);
+        assert_eq!(cb.correct(b"AAAA"), Some(b"AAAA".to_vec()));
+    }
+
+    #[test]
+    fn test_unambiguous_hamming_one_correction() {
+        let cb = make(&["AAAA", "TTTT"], b'-', 1);
+        // "ACAA" is 1 substitution from "AAAA" and 3 from "TTTT".
+        assert_eq!(cb.correct(b"ACAA"), Some(b"AAAA".to_vec()));
+    }
+
+    #[test]
+    fn test_equidistant_tie_rejected_without_qualities() {
+        let cb = make(&["CAAA", "ACAA"], b'-', 1);
+        // "AAAA" is 1 substitution from both whitelist entries (at
+        // different positions), and no base qualities are available to
+        // break the tie.
+        assert_eq!(cb.correct(b"AAAA"), None);
+    }
+
+    #[test]
+    fn test_quality_aware_tiebreak_picks_lowest_quality_mismatch() {
+        let cb = make(&["CAAA", "ACAA"], b'-', 1);
+        // mismatch against "CAAA" is at position 0 (quality 10), against
+        // "ACAA" at position 1 (quality 30) - the lower-quality mismatch
+        // wins, since that base is the more plausible sequencing error.
+        assert_eq!(
+            cb.correct_with_qualities(b"AAAA", Some(&[10, 30, 40, 40])),
+            Some(b"CAAA".to_vec())
+        );
+        assert_eq!(
+            cb.correct_with_qualities(b"AAAA", Some(&[30, 10, 40, 40])),
+            Some(b"ACAA".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_quality_tie_still_rejected() {
+        let cb = make(&["CAAA", "ACAA"], b'-', 1);
+        assert_eq!(
+            cb.correct_with_qualities(b"AAAA", Some(&[20, 20, 40, 40])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_max_hamming_zero_disables_correction() {
+        let cb = make(&["AAAA"], b'-', 0);
+        assert_eq!(cb.correct(b"AAAA"), Some(b"AAAA".to_vec()));
+        assert_eq!(cb.correct(b"ACAA"), None);
+    }
+
+    #[test]
+    fn test_multi_segment_barcode_corrected_independently() {
+        let whitelist_a: Whitelist = ["AAAA"].iter().map(|s| s.as_bytes().to_vec()).collect();
+        let whitelist_b: Whitelist = ["GGGG"].iter().map(|s| s.as_bytes().to_vec()).collect();
+        let cb = CellBarcodes {
+            extract: extractors::UMIExtraction::ReadRegion(
+                toml::from_str("start = 0\nstop = 4").unwrap(),
+            ),
+            separator_char: b'-',
+            max_hamming: 1,
+            whitelist_files: Vec::new(),
+            neighbor_maps: vec![
+                build_neighbor_map(&whitelist_a),
+                build_neighbor_map(&whitelist_b),
+            ],
+            whitelists: vec![whitelist_a, whitelist_b],
+        };
+        // first segment exact, second segment a 1-substitution correction.
+        assert_eq!(cb.correct(b"AAAA-GGGT"), Some(b"AAAA-GGGG".to_vec()));
+    }
+
+    #[test]
+    fn test_no_whitelist_passes_barcode_through_unchanged() {
+        let cb = CellBarcodes {
+            extract: extractors::UMIExtraction::ReadRegion(
+                toml::from_str("start = 0\nstop = 4").unwrap(),
+            ),
+            separator_char: b'-',
+            max_hamming: 1,
+            whitelist_files: Vec::new(),
+            whitelists: Vec::new(),
+            neighbor_maps: Vec::new(),
+        };
+        assert_eq!(cb.correct(b"ANYTHING"), Some(b"ANYTHING".to_vec()));
+        assert_eq!(cb.correct(b""), None);
     }
 }
@@ -1,6 +1,7 @@
 use anyhow::{bail, Context, Result};
+use serde::Deserialize;
 use std::fs::{self, DirEntry};
-use std::io::Read;
+use std::io::{Read, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process;
@@ -8,19 +9,266 @@ use tempfile::TempDir;
 
 const CLI_UNDER_TEST: &str = "mbf-bam-quantifier";
 
+/// How a test case's expected-output files should be reconciled against the
+/// freshly produced actual ones, mirroring compiletest/ui_test's bless
+/// workflow. `All` (`--bless`) makes the expected directory an exact mirror
+/// of the actual run (overwrite mismatches, add new files, delete stale
+/// ones); `New` (`--bless-new`) only adds expected files for output the test
+/// produces that nothing tracked yet, leaving existing mismatches failing so
+/// a bulk run can't silently paper over a real regression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bless {
+    Off,
+    New,
+    All,
+}
+
+/// One normalization rule, as shipped in a test case's `normalize.toml`:
+/// `regex` runs `pattern` as a regular expression over the text and
+/// replaces every match (`replacement` may use `$1`-style capture group
+/// references), `exact` does a plain literal substring replace, and `path`
+/// is like `exact` but canonicalizes `\` to `/` in both the haystack and
+/// `pattern` first, so a rule written against a `/`-separated path still
+/// matches output produced with backslashes.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum NormalizeKind {
+    Regex,
+    Exact,
+    Path,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct NormalizeRule {
+    kind: NormalizeKind,
+    pattern: String,
+    replacement: String,
+}
+
+impl NormalizeRule {
+    fn exact(pattern: impl Into<String>, replacement: impl Into<String>) -> Self {
+        NormalizeRule {
+            kind: NormalizeKind::Exact,
+            pattern: pattern.into(),
+            replacement: replacement.into(),
+        }
+    }
+
+    fn apply(&self, text: &str) -> Result<String> {
+        Ok(match self.kind {
+            NormalizeKind::Exact => text.replace(&self.pattern, &self.replacement),
+            NormalizeKind::Path => text
+                .replace('\\', "/")
+                .replace(&self.pattern.replace('\\', "/"), &self.replacement),
+            NormalizeKind::Regex => {
+                let re = regex::Regex::new(&self.pattern).with_context(|| {
+                    format!("Invalid normalize.toml regex pattern: {}", self.pattern)
+                })?;
+                re.replace_all(text, self.replacement.as_str()).to_string()
+            }
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct NormalizeConfig {
+    #[serde(default)]
+    rule: Vec<NormalizeRule>,
+}
+
+/// Builds the ordered list of normalization rules for one test run: the two
+/// built-ins (temp-dir-path substitution, then path-separator
+/// canonicalization) always run first, followed by whatever `normalize.toml`
+/// in the test case directory adds, so a test's own rules can further
+/// normalize text that already had the randomized temp dir replaced out.
+fn load_normalize_rules(test_case_dir: &Path, temp_dir_path: &Path) -> Result<Vec<NormalizeRule>> {
+    let mut rules = vec![
+        NormalizeRule::exact(temp_dir_path.to_string_lossy().to_string(), "$TMPDIR"),
+        NormalizeRule {
+            kind: NormalizeKind::Path,
+            pattern: String::new(),
+            replacement: String::new(),
+        },
+    ];
+    let normalize_toml = test_case_dir.join("normalize.toml");
+    if normalize_toml.exists() {
+        let raw = fs::read_to_string(&normalize_toml).context("Read normalize.toml")?;
+        let config: NormalizeConfig =
+            toml::from_str(&raw).context("Parse normalize.toml")?;
+        rules.extend(config.rule);
+    }
+    Ok(rules)
+}
+
+fn apply_normalize_rules(text: &str, rules: &[NormalizeRule]) -> Result<String> {
+    let mut text = text.to_string();
+    for rule in rules {
+        text = rule.apply(&text)?;
+    }
+    Ok(text)
+}
+
+/// Machine-readable result output requested via `--report <format>`,
+/// produced in addition to (not instead of) the human console output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReportFormat {
+    /// A JUnit XML file (`test-results.xml` in the working directory), one
+    /// `<testcase>` per `TestCase`.
+    Junit,
+    /// GitHub Actions workflow-command annotations
+    /// (`::group::`/`::error file=...::`/`::endgroup::`) printed to stdout.
+    Github,
+}
+
+/// One test case's concluded outcome, collected alongside the normal
+/// console reporting so `--report junit`/`--report github` can be produced
+/// from the same run without re-executing anything.
+enum TestOutcome {
+    Passed,
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        message: String,
+        /// `(actual_path, expected_path)` pairs, as in `TestOutput`, so a
+        /// GitHub annotation can point at the specific expected file.
+        mismatched_files: Vec<(String, String)>,
+    },
+}
+
+struct TestReportEntry {
+    name: String,
+    elapsed: std::time::Duration,
+    outcome: TestOutcome,
+}
+
+/// Renders one test's GitHub Actions workflow-command block as a single
+/// string so it can be printed with one `print!` call - multiple smaller
+/// prints from concurrent workers could otherwise interleave mid-group.
+fn github_annotation_block(entry: &TestReportEntry) -> String {
+    let mut out = format!("::group::{}\n", entry.name);
+    match &entry.outcome {
+        TestOutcome::Passed => {
+            out.push_str(&format!("passed ({:.3}s)\n", entry.elapsed.as_secs_f64()));
+        }
+        TestOutcome::Skipped { reason } => {
+            out.push_str(&format!("skipped: {reason}\n"));
+        }
+        TestOutcome::Failed {
+            message,
+            mismatched_files,
+        } => {
+            for (_actual_path, expected_path) in mismatched_files {
+                out.push_str(&format!(
+                    "::error file={expected_path}::output mismatch for {}\n",
+                    entry.name
+                ));
+            }
+            out.push_str(&format!("::error::{}\n", message.replace('\n', "%0A")));
+        }
+    }
+    out.push_str("::endgroup::\n");
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes a JUnit XML report - one `<testcase>` per `TestReportEntry`, with
+/// `<failure>` carrying the formatted assertion failure for failed cases
+/// and a plain `<skipped>` for skipped ones - to `path`.
+fn write_junit_report(entries: &[TestReportEntry], path: &Path) -> Result<()> {
+    let failures = entries
+        .iter()
+        .filter(|e| matches!(e.outcome, TestOutcome::Failed { .. }))
+        .count();
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuite name=\"{CLI_UNDER_TEST}\" tests=\"{}\" failures=\"{}\">\n",
+        entries.len(),
+        failures
+    );
+    for entry in entries {
+        xml.push_str(&format!(
+            "  <testcase name=\"{}\" time=\"{:.3}\">\n",
+            xml_escape(&entry.name),
+            entry.elapsed.as_secs_f64()
+        ));
+        match &entry.outcome {
+            TestOutcome::Passed => {}
+            TestOutcome::Skipped { reason } => {
+                xml.push_str(&format!(
+                    "    <skipped message=\"{}\"/>\n",
+                    xml_escape(reason)
+                ));
+            }
+            TestOutcome::Failed { message, .. } => {
+                xml.push_str(&format!(
+                    "    <failure message=\"{}\">{}</failure>\n",
+                    xml_escape(message.lines().next().unwrap_or(message.as_str())),
+                    xml_escape(message)
+                ));
+            }
+        }
+        xml.push_str("  </testcase>\n");
+    }
+    xml.push_str("</testsuite>\n");
+    fs::write(path, xml).with_context(|| format!("Write JUnit report to {}", path.display()))
+}
+
 fn main() -> Result<()> {
     //human_panic::setup_panic!();
-    for test_dir in std::env::args().skip(1).filter(|x| !x.starts_with("--")) {
-        run_tests(PathBuf::from(test_dir), false)?
+    let bless = if std::env::args().any(|a| a == "--bless") {
+        Bless::All
+    } else if std::env::args().any(|a| a == "--bless-new") {
+        Bless::New
+    } else {
+        Bless::Off
+    };
+    // `--jobs N` picks the worker pool size; 0 (the default) leaves it to
+    // rayon, which sizes the pool off the available cores.
+    let jobs: usize = std::env::args()
+        .collect::<Vec<_>>()
+        .windows(2)
+        .find_map(|w| (w[0] == "--jobs").then(|| w[1].parse().ok()).flatten())
+        .unwrap_or(0);
+    let report: Option<ReportFormat> = std::env::args()
+        .collect::<Vec<_>>()
+        .windows(2)
+        .find_map(|w| (w[0] == "--report").then(|| w[1].clone()))
+        .map(|format| match format.as_str() {
+            "junit" => Ok(ReportFormat::Junit),
+            "github" => Ok(ReportFormat::Github),
+            other => bail!("Unknown --report format: {other} (expected junit or github)"),
+        })
+        .transpose()?;
+    for test_dir in std::env::args().skip(1).filter(|x| {
+        !x.starts_with("--")
+            && x != "--jobs"
+            && x != "--report"
+            && x != "junit"
+            && x != "github"
+            && x.parse::<usize>().is_err()
+    }) {
+        run_tests(PathBuf::from(test_dir), false, bless, jobs, report)?;
     }
     if std::env::args().count() < 2 {
         let test_dir = std::env::args().nth(1).unwrap_or("test_cases".to_string());
-        run_tests(PathBuf::from(test_dir), false)?
+        run_tests(PathBuf::from(test_dir), false, bless, jobs, report)?;
     }
     Ok(())
 }
 
-fn run_tests(test_dir: impl AsRef<Path>, continue_upon_failure: bool) -> Result<()> {
+fn run_tests(
+    test_dir: impl AsRef<Path>,
+    continue_upon_failure: bool,
+    bless: Bless,
+    jobs: usize,
+    report: Option<ReportFormat>,
+) -> Result<()> {
     let last_failed_filename: PathBuf =
         format!("/tmp/.{CLI_UNDER_TEST}-test-runner-last-failed").into();
     let last_failed: Option<PathBuf> = if last_failed_filename.exists() {
@@ -42,31 +290,95 @@ fn run_tests(test_dir: impl AsRef<Path>, continue_upon_failure: bool) -> Result<
     let mut rng = rand::rng();
     test_cases.shuffle(&mut rng);
 
-    if let Some(last_failed) = last_failed {
-        //put last failed test to the front - if present
+    // Run the last-failed test case first and on its own, ahead of the
+    // pool, so a test case someone is actively iterating on reports back
+    // immediately instead of waiting behind whatever the pool happens to
+    // schedule first.
+    let priority_case = last_failed.and_then(|last_failed| {
         if test_cases.iter().any(|x| x.dir == last_failed) {
             println!(
                 "Found last failed test case: {}. Running it first.",
                 last_failed.display()
             );
             test_cases.retain(|x| x.dir != last_failed);
-            test_cases.insert(0, TestCase::new(last_failed));
+            Some(TestCase::new(last_failed))
+        } else {
+            None
         }
-    }
+    });
 
-    let mut passed = 0;
-    let mut failed = 0;
+    let passed = std::sync::atomic::AtomicUsize::new(0);
+    let failed = std::sync::atomic::AtomicUsize::new(0);
+    let stop = std::sync::atomic::AtomicBool::new(false);
+    let last_failed_write_lock = std::sync::Mutex::new(());
+    let report_entries: std::sync::Mutex<Vec<TestReportEntry>> = std::sync::Mutex::new(Vec::new());
     let processor_path = find_processor()?;
     let start = std::time::Instant::now();
 
-    println!("Found {} test cases", test_cases.len());
-    for test_case in test_cases {
+    println!(
+        "Found {} test cases",
+        test_cases.len() + priority_case.is_some() as usize
+    );
+
+    let record = |name: String, elapsed: std::time::Duration, outcome: TestOutcome| {
+        if report.is_none() {
+            return;
+        }
+        let entry = TestReportEntry {
+            name,
+            elapsed,
+            outcome,
+        };
+        if report == Some(ReportFormat::Github) {
+            print!("{}", github_annotation_block(&entry));
+        }
+        report_entries.lock().unwrap().push(entry);
+    };
+
+    let run_one = |test_case: &TestCase| {
         if test_case.dir.join("skip").exists() {
             println!(
-                "Skipping test case: {} (skip file present)",
+                "⏭️  {} (skip file present)",
                 test_case.dir.display()
             );
-            continue;
+            record(
+                test_case.dir.to_string_lossy().to_string(),
+                std::time::Duration::ZERO,
+                TestOutcome::Skipped {
+                    reason: "skip file present".to_string(),
+                },
+            );
+            return;
+        }
+
+        match load_requirements(&test_case.dir) {
+            Ok(requirements) => {
+                if let Some(reason) = unmet_requirement_reason(&requirements) {
+                    println!("⏭️  {} ({reason})", test_case.dir.display());
+                    record(
+                        test_case.dir.to_string_lossy().to_string(),
+                        std::time::Duration::ZERO,
+                        TestOutcome::Skipped { reason },
+                    );
+                    return;
+                }
+            }
+            Err(e) => {
+                failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                println!("❌ {} (bad requirements: {e:?})", test_case.dir.display());
+                record(
+                    test_case.dir.to_string_lossy().to_string(),
+                    std::time::Duration::ZERO,
+                    TestOutcome::Failed {
+                        message: format!("{e:?}"),
+                        mismatched_files: Vec::new(),
+                    },
+                );
+                if !continue_upon_failure {
+                    stop.store(true, std::sync::atomic::Ordering::Relaxed);
+                }
+                return;
+            }
         }
 
         let repeat_count = fs::read_to_string(test_case.dir.join("repeat"))
@@ -77,50 +389,94 @@ fn run_tests(test_dir: impl AsRef<Path>, continue_upon_failure: bool) -> Result<
             })
             .unwrap_or(1);
 
+        let case_start = std::time::Instant::now();
         for repeat in 0..repeat_count {
-            let start = std::time::Instant::now();
             let test_result = if test_case.is_panic {
-                print!("\n  Running panic test: {} {}", test_case.dir.display(), repeat);
-                run_panic_test(&test_case, processor_path.as_ref())
+                run_panic_test(test_case, processor_path.as_ref(), bless)
             } else {
-                print!("\n  Running regular test: {} {}", test_case.dir.display(), repeat);
-                run_output_test(&test_case, processor_path.as_ref())
+                run_output_test(test_case, processor_path.as_ref(), bless)
             };
-            let elapsed = start.elapsed();
-            print!(" ({}.{:03}s)", elapsed.as_secs(), elapsed.subsec_millis());
-
-            match test_result {
-                Ok(()) => {
-                    //put checkmark before last line written
-                    //so we need minimal lines, but report what we're running
-                    print!("\r✅");
 
-                    //println!("✅ Output test passed");
-                    passed += 1;
-                }
-                Err(e) => {
-                    //write last failed to file
-                    std::fs::write(
-                        &last_failed_filename,
-                        test_case.dir.to_string_lossy().to_string(),
-                    )
-                    .ok();
-                    print!("\r❌");
-                    print!("\n{:?}", e);
-                    failed += 1;
-                    break; // no more repeats for this one
+            let (message, mismatched_files) = match test_result {
+                Ok((_rr, None)) => {
+                    passed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    continue;
                 }
+                Ok((rr, Some(message))) => (message, rr.mismatched_files),
+                Err(e) => (format!("{e:?}"), Vec::new()),
+            };
+
+            {
+                let _guard = last_failed_write_lock.lock().unwrap();
+                std::fs::write(
+                    &last_failed_filename,
+                    test_case.dir.to_string_lossy().to_string(),
+                )
+                .ok();
             }
-        }
-        if failed > 0 && !continue_upon_failure {
+            failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let elapsed = case_start.elapsed();
             println!(
-                "Stopping due to failure in test: {}",
-                test_case.dir.display()
+                "❌ {} (repeat {repeat}, {}.{:03}s)\n{}",
+                test_case.dir.display(),
+                elapsed.as_secs(),
+                elapsed.subsec_millis(),
+                message
             );
-            break;
+            record(
+                test_case.dir.to_string_lossy().to_string(),
+                elapsed,
+                TestOutcome::Failed {
+                    message,
+                    mismatched_files,
+                },
+            );
+            if !continue_upon_failure {
+                stop.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+            return; // no more repeats for this one
         }
+        let elapsed = case_start.elapsed();
+        println!(
+            "✅ {} ({}.{:03}s)",
+            test_case.dir.display(),
+            elapsed.as_secs(),
+            elapsed.subsec_millis()
+        );
+        record(
+            test_case.dir.to_string_lossy().to_string(),
+            elapsed,
+            TestOutcome::Passed,
+        );
+    };
+
+    if let Some(priority_case) = &priority_case {
+        run_one(priority_case);
+    }
+
+    if priority_case.is_none() || continue_upon_failure || !stop.load(std::sync::atomic::Ordering::Relaxed) {
+        use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .context("Build test-runner thread pool")?;
+        pool.install(|| {
+            test_cases.par_iter().for_each(|test_case| {
+                if !continue_upon_failure && stop.load(std::sync::atomic::Ordering::Relaxed) {
+                    return;
+                }
+                run_one(test_case);
+            });
+        });
+    }
+
+    if report == Some(ReportFormat::Junit) {
+        let entries = report_entries.into_inner().unwrap();
+        write_junit_report(&entries, Path::new("test-results.xml"))?;
     }
 
+    let passed = passed.load(std::sync::atomic::Ordering::Relaxed);
+    let failed = failed.load(std::sync::atomic::Ordering::Relaxed);
     let elapsed = start.elapsed();
     println!(
         "\nTest results: {} passed, {} failed. Took {}.{:03}s.",
@@ -207,11 +563,80 @@ struct TestCase {
 
 impl TestCase {
     fn new(dir: PathBuf) -> Self {
-        let is_panic = dir.join("expected_panic.txt").exists();
+        let is_panic =
+            dir.join("expected_panic.txt").exists() || dir.join("expected_panic.toml").exists();
         TestCase { dir, is_panic }
     }
 }
 
+/// Conditional-skip directives for a test case, sourced from a
+/// `requirements.toml` in the test directory, or failing that from `# mbf:
+/// key = value` comment lines at the top of `input.toml`. Any unmet
+/// requirement turns the test into a skip rather than a failure, so tests
+/// depending on optional external tools/platforms don't break CI on
+/// machines lacking them.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Requirements {
+    #[serde(default, rename = "only-os")]
+    only_os: Vec<String>,
+    #[serde(default, rename = "ignore-os")]
+    ignore_os: Vec<String>,
+    #[serde(default, rename = "needs-env")]
+    needs_env: Vec<String>,
+    #[serde(default, rename = "needs-binary")]
+    needs_binary: Vec<String>,
+}
+
+fn load_requirements(test_dir: &Path) -> Result<Requirements> {
+    let requirements_toml = test_dir.join("requirements.toml");
+    if requirements_toml.exists() {
+        let raw = fs::read_to_string(&requirements_toml).context("Read requirements.toml")?;
+        return toml::from_str(&raw).context("Parse requirements.toml");
+    }
+    let input_toml = test_dir.join("input.toml");
+    if input_toml.exists() {
+        let raw = fs::read_to_string(&input_toml).context("Read input.toml")?;
+        let directive_lines: String = raw
+            .lines()
+            .take_while(|line| line.trim().is_empty() || line.trim_start().starts_with('#'))
+            .filter_map(|line| line.trim_start().strip_prefix('#')?.trim_start().strip_prefix("mbf:"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !directive_lines.trim().is_empty() {
+            return toml::from_str(&directive_lines)
+                .context("Parse '# mbf:' directives at the top of input.toml");
+        }
+    }
+    Ok(Requirements::default())
+}
+
+/// Returns why `requirements` can't be satisfied on this machine, or `None`
+/// if the test case may run.
+fn unmet_requirement_reason(requirements: &Requirements) -> Option<String> {
+    let os = std::env::consts::OS;
+    if !requirements.only_os.is_empty() && !requirements.only_os.iter().any(|x| x == os) {
+        return Some(format!(
+            "requires OS in {:?}, running on {os}",
+            requirements.only_os
+        ));
+    }
+    if requirements.ignore_os.iter().any(|x| x == os) {
+        return Some(format!("disabled on OS {os}"));
+    }
+    for var in &requirements.needs_env {
+        if std::env::var(var).is_err() {
+            return Some(format!("requires environment variable {var} to be set"));
+        }
+    }
+    for bin in &requirements.needs_binary {
+        if find_in_path(bin).is_none() {
+            return Some(format!("requires binary '{bin}' in PATH"));
+        }
+    }
+    None
+}
+
 fn discover_test_cases(dir: &Path) -> Result<Vec<TestCase>> {
     if !dir.exists() {
         anyhow::bail!("Test directory does not exist: {}", dir.display());
@@ -259,39 +684,141 @@ struct TestOutput {
     missing_files: Vec<String>,
     mismatched_files: Vec<(String, String)>,
     unexpected_files: Vec<String>,
+    /// The rules this run normalized `stdout`/`stderr` and the common-file
+    /// comparisons with, kept around so callers comparing against their own
+    /// expected text (e.g. `run_panic_test`'s expected-panic substring) can
+    /// normalize it the same way.
+    normalize_rules: Vec<NormalizeRule>,
+}
+
+/// One ordered assertion against stderr from an `expected_panic.toml`,
+/// compiletest-annotation-style: `contains`/`not-contains` are plain
+/// substring checks, `regex` is compiled and matched against stderr.
+/// Exactly one of the three should be set per `[[assert]]` table.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+struct PanicAssertion {
+    #[serde(default)]
+    contains: Option<String>,
+    #[serde(default, rename = "not-contains")]
+    not_contains: Option<String>,
+    #[serde(default)]
+    regex: Option<String>,
+}
+
+/// The full set of expectations for a panic test: an optional exact
+/// `exit-code` (defaulting to "any nonzero code"), followed by the ordered
+/// `[[assert]]` list, all of which must hold.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+struct PanicExpectation {
+    #[serde(default, rename = "exit-code")]
+    exit_code: Option<i32>,
+    #[serde(default, rename = "assert")]
+    assertions: Vec<PanicAssertion>,
 }
 
-fn run_panic_test(the_test: &TestCase, processor_cmd: &Path) -> Result<()> {
-    let rr = perform_test(the_test, processor_cmd)?;
-    if rr.return_code == 0 {
-        bail!("No panic occurred, but expected one.");
+/// Loads a test case's panic expectation, preferring `expected_panic.toml`
+/// (the richer, multi-assertion form) and falling back to the original
+/// single-substring `expected_panic.txt` - normalized the same way stderr
+/// already was - as one `contains` assertion, so existing test cases keep
+/// working unchanged.
+fn load_panic_expectation(test_dir: &Path, normalize_rules: &[NormalizeRule]) -> Result<PanicExpectation> {
+    let toml_path = test_dir.join("expected_panic.toml");
+    if toml_path.exists() {
+        let raw = fs::read_to_string(&toml_path).context("Read expected_panic.toml")?;
+        return toml::from_str(&raw).context("Parse expected_panic.toml");
     }
-    let expected_panic_file = the_test.dir.join("expected_panic.txt");
-    let expected_panic_content = fs::read_to_string(&expected_panic_file)
+    let txt_path = test_dir.join("expected_panic.txt");
+    let content = fs::read_to_string(&txt_path)
         .context("Read expected panic file")?
         .trim()
         .to_string();
+    let content = apply_normalize_rules(&content, normalize_rules)
+        .context("Normalize expected panic content")?;
+    Ok(PanicExpectation {
+        exit_code: None,
+        assertions: vec![PanicAssertion {
+            contains: Some(content),
+            ..Default::default()
+        }],
+    })
+}
 
-    if !rr.stderr.contains(&expected_panic_content) {
-        anyhow::bail!(
-            "{CLI_UNDER_TEST} did not panic as expected.\nExpected panic: {}\nActual stderr: '{}'",
-            expected_panic_content,
-            rr.stderr
-        );
+/// Runs `the_test` and checks its panic assertion, returning the raw
+/// `TestOutput` alongside `None` (passed) or `Some(failure message)` -
+/// rather than bailing straight out of the failure case - so the caller can
+/// still report per-file detail (e.g. `--report github` annotations) for a
+/// test that failed its assertion rather than erroring out of `perform_test`
+/// itself.
+fn run_panic_test(
+    the_test: &TestCase,
+    processor_cmd: &Path,
+    bless: Bless,
+) -> Result<(TestOutput, Option<String>)> {
+    let rr = perform_test(the_test, processor_cmd, bless)?;
+    let expectation = load_panic_expectation(&the_test.dir, &rr.normalize_rules)?;
+
+    if let Some(exit_code) = expectation.exit_code {
+        if rr.return_code != exit_code {
+            let failure = format!(
+                "expected exit code {exit_code}, got {}\nActual stderr: '{}'",
+                rr.return_code, rr.stderr
+            );
+            return Ok((rr, Some(failure)));
+        }
+    } else if rr.return_code == 0 {
+        return Ok((rr, Some("No panic occurred, but expected one.".to_string())));
     }
-    Ok(())
+
+    for (i, assertion) in expectation.assertions.iter().enumerate() {
+        if let Some(pattern) = &assertion.contains {
+            if !rr.stderr.contains(pattern) {
+                let failure = format!(
+                    "assertion #{i} (contains {pattern:?}) failed.\nActual stderr: '{}'",
+                    rr.stderr
+                );
+                return Ok((rr, Some(failure)));
+            }
+        }
+        if let Some(pattern) = &assertion.not_contains {
+            if rr.stderr.contains(pattern) {
+                let failure = format!(
+                    "assertion #{i} (not-contains {pattern:?}) failed.\nActual stderr: '{}'",
+                    rr.stderr
+                );
+                return Ok((rr, Some(failure)));
+            }
+        }
+        if let Some(pattern) = &assertion.regex {
+            let re = regex::Regex::new(pattern)
+                .with_context(|| format!("Invalid expected_panic.toml regex: {pattern}"))?;
+            if !re.is_match(&rr.stderr) {
+                let failure = format!(
+                    "assertion #{i} (regex {pattern:?}) failed.\nActual stderr: '{}'",
+                    rr.stderr
+                );
+                return Ok((rr, Some(failure)));
+            }
+        }
+    }
+
+    Ok((rr, None))
 }
 
-fn run_output_test(test_case: &TestCase, processor_cmd: &Path) -> Result<()> {
-    let rr = perform_test(test_case, processor_cmd)?;
+fn run_output_test(
+    test_case: &TestCase,
+    processor_cmd: &Path,
+    bless: Bless,
+) -> Result<(TestOutput, Option<String>)> {
+    let rr = perform_test(test_case, processor_cmd, bless)?;
 
     if rr.return_code != 0 {
-        anyhow::bail!(
+        let failure = format!(
             "{CLI_UNDER_TEST} failed with return code: {}\nstdout: {}\nstderr: {}",
-            rr.return_code,
-            rr.stdout,
-            rr.stderr
+            rr.return_code, rr.stdout, rr.stderr
         );
+        return Ok((rr, Some(failure)));
     }
 
     let mut msg = String::new();
@@ -311,9 +838,10 @@ fn run_output_test(test_case: &TestCase, processor_cmd: &Path) -> Result<()> {
         msg.push_str(&format!("\t- {} (mismatched)\n", actual_path));
     }
     if !msg.is_empty() {
-        anyhow::bail!("\toutput files failed verification.\n{}", msg);
+        let failure = format!("\toutput files failed verification.\n{}", msg);
+        return Ok((rr, Some(failure)));
     }
-    Ok(())
+    Ok((rr, None))
 }
 
 fn visit_dirs(dir: &Path, cb: &mut dyn FnMut(&DirEntry) -> Result<()>) -> Result<()> {
@@ -370,7 +898,7 @@ fn scan_dir<F: Fn(&str, &str) -> bool>(dir: &Path, callback: F) -> Result<Vec<(P
     Ok(files)
 }
 
-fn perform_test(test_case: &TestCase, processor_cmd: &Path) -> Result<TestOutput> {
+fn perform_test(test_case: &TestCase, processor_cmd: &Path, bless: Bless) -> Result<TestOutput> {
     let mut result = TestOutput {
         stdout: String::new(),
         stderr: String::new(),
@@ -378,6 +906,7 @@ fn perform_test(test_case: &TestCase, processor_cmd: &Path) -> Result<TestOutput
         missing_files: Vec::new(),
         mismatched_files: Vec::new(),
         unexpected_files: Vec::new(),
+        normalize_rules: Vec::new(),
     };
 
     let actual_dir = test_case.dir.join("actual");
@@ -396,6 +925,8 @@ fn perform_test(test_case: &TestCase, processor_cmd: &Path) -> Result<TestOutput
     })?;
 
     let temp_dir = setup_test_environment(input_files).context("Setup test dir")?;
+    let normalize_rules = load_normalize_rules(test_case.dir.as_path(), temp_dir.path())
+        .context("Load normalize.toml")?;
 
     // Run the processor
     let config_file = temp_dir.path().join("input.toml");
@@ -440,6 +971,12 @@ fn perform_test(test_case: &TestCase, processor_cmd: &Path) -> Result<TestOutput
     fs::write(actual_dir.as_path().join("stderr"), stderr.as_bytes())
         .context("Failed to write stderr to file")?;
 
+    result.stdout =
+        apply_normalize_rules(&result.stdout, &normalize_rules).context("Normalize stdout")?;
+    result.stderr =
+        apply_normalize_rules(&result.stderr, &normalize_rules).context("Normalize stderr")?;
+    result.normalize_rules = normalize_rules;
+
     let output_files_in_temp_dir = scan_dir(temp_dir.path(), |relative_path, _| {
         !relative_path.starts_with("input")
     })?;
@@ -467,6 +1004,7 @@ fn perform_test(test_case: &TestCase, processor_cmd: &Path) -> Result<TestOutput
         if !files_equal(
             test_case.dir.join(&relative_filename),
             temp_dir.path().join(&relative_filename),
+            &result.normalize_rules,
         )
         .unwrap()
         {
@@ -488,6 +1026,11 @@ fn perform_test(test_case: &TestCase, processor_cmd: &Path) -> Result<TestOutput
     result.unexpected_files = unexpected_files;
     result.mismatched_files = missmatched_files;
 
+    if bless != Bless::Off {
+        bless_test_case(test_case, temp_dir.path(), &mut result, bless)
+            .context("Failed to bless test case")?;
+    }
+
     // First, check all files in the temp directory that should match expected outputs
 
     if !(result.missing_files.is_empty()
@@ -521,7 +1064,103 @@ fn perform_test(test_case: &TestCase, processor_cmd: &Path) -> Result<TestOutput
     Ok(result)
 }
 
-fn files_equal(file_a: PathBuf, file_b: PathBuf) -> Result<bool> {
+/// Reconciles `test_case.dir`'s expected files against the actual run
+/// staged in `temp_dir`, mutating `result` to drop whichever entries were
+/// resolved so `run_output_test`'s pass/fail check sees a clean result.
+/// Files guarded by a `compare_<name>` script (same convention as
+/// `files_equal`) are left as-is in both modes - the script, not a
+/// byte-exact expected file, owns equality for them.
+fn bless_test_case(
+    test_case: &TestCase,
+    temp_dir: &Path,
+    result: &mut TestOutput,
+    bless: Bless,
+) -> Result<()> {
+    // New output the test produces that nothing expects yet: always adopted
+    // as new expected files, in both bless modes.
+    for relative_path in std::mem::take(&mut result.unexpected_files) {
+        bless_copy(
+            &temp_dir.join(&relative_path),
+            &test_case.dir.join(&relative_path),
+        )?;
+    }
+
+    if bless != Bless::All {
+        return Ok(());
+    }
+
+    // Expected files the test no longer produces: the expected snapshot is
+    // stale, so drop it.
+    for relative_path in std::mem::take(&mut result.missing_files) {
+        let expected_path = test_case.dir.join(&relative_path);
+        if expected_path.exists() {
+            fs::remove_file(&expected_path).with_context(|| {
+                format!(
+                    "Failed to remove stale expected file: {}",
+                    expected_path.display()
+                )
+            })?;
+        }
+    }
+
+    // Files both present but differing: overwrite expected with actual,
+    // except where a `compare_<name>` script owns equality for this file.
+    let mut still_mismatched = Vec::new();
+    for (actual_path, expected_path) in std::mem::take(&mut result.mismatched_files) {
+        let expected_path_buf = PathBuf::from(&expected_path);
+        let comparison_script = expected_path_buf.with_file_name(format!(
+            "compare_{}",
+            expected_path_buf.file_name().unwrap().to_string_lossy()
+        ));
+        if comparison_script.exists() {
+            still_mismatched.push((actual_path, expected_path));
+            continue;
+        }
+        bless_copy(Path::new(&actual_path), &expected_path_buf)?;
+    }
+    result.mismatched_files = still_mismatched;
+
+    Ok(())
+}
+
+/// Copies `actual` onto `expected`, decompressing/recompressing through
+/// `read_compressed` for `.gz` files instead of a raw byte copy, so the
+/// blessed file round-trips the same way `files_equal` reads it back.
+fn bless_copy(actual: &Path, expected: &Path) -> Result<()> {
+    if let Some(parent) = expected.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    if expected.extension() == Some(std::ffi::OsStr::new("gz")) {
+        let content = read_compressed(actual)
+            .with_context(|| format!("Failed to decompress {}", actual.display()))?;
+        write_compressed(expected, &content)
+            .with_context(|| format!("Failed to write blessed gz file {}", expected.display()))?;
+    } else {
+        fs::copy(actual, expected).with_context(|| {
+            format!(
+                "Failed to bless {} from {}",
+                expected.display(),
+                actual.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+fn write_compressed(filename: impl AsRef<Path>, content: &str) -> Result<()> {
+    let fh = std::fs::File::create(filename.as_ref())
+        .with_context(|| format!("Could not create file {:?}", filename.as_ref()))?;
+    let mut wrapped = niffler::send::get_writer(
+        Box::new(fh),
+        niffler::Format::Gzip,
+        niffler::Level::Nine,
+    )?;
+    wrapped.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+fn files_equal(file_a: PathBuf, file_b: PathBuf, normalize_rules: &[NormalizeRule]) -> Result<bool> {
     let content_a = ex::fs::read(&file_a).unwrap();
     let content_b = ex::fs::read(&file_b).unwrap();
     if content_a == content_b {
@@ -532,7 +1171,24 @@ fn files_equal(file_a: PathBuf, file_b: PathBuf) -> Result<bool> {
     {
         let uncompressed_a = read_compressed(&file_a)?;
         let uncompressed_b = read_compressed(&file_b)?;
-        return Ok(uncompressed_a == uncompressed_b);
+        if uncompressed_a == uncompressed_b {
+            return Ok(true);
+        }
+        let normalized_a = apply_normalize_rules(&uncompressed_a, normalize_rules)?;
+        let normalized_b = apply_normalize_rules(&uncompressed_b, normalize_rules)?;
+        return Ok(normalized_a == normalized_b);
+    }
+    // Only normalize text content: a binary file that differs byte-for-byte
+    // either gets a comparison script below, or is a genuine mismatch.
+    if let (Ok(text_a), Ok(text_b)) = (
+        std::str::from_utf8(&content_a),
+        std::str::from_utf8(&content_b),
+    ) {
+        let normalized_a = apply_normalize_rules(text_a, normalize_rules)?;
+        let normalized_b = apply_normalize_rules(text_b, normalize_rules)?;
+        if normalized_a == normalized_b {
+            return Ok(true);
+        }
     }
     let comparison_script = file_a.with_file_name(format!(
         "compare_{}",
@@ -1,10 +1,22 @@
 use std::collections::HashMap;
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
+/// A string-interning column: `cat_from_value`/`push` are O(1) via `names`
+/// below. There is deliberately no cross-instance `merge`/`apply_remap` here
+/// - `Input::read_gtf` parses the whole GTF once upfront into a single
+/// `Categorical` per column, so there's never a per-chunk instance for a
+/// parallel reducer to fold back together. A `merge`/`apply_remap` pair was
+/// added speculatively (d67ac49) and removed (f136106) for having no caller
+/// and no per-chunk `Categorical` to call it on; "mergeable across chunks"
+/// does not describe anything this type needs to do.
 #[derive(Debug, Clone)]
 pub struct Categorical {
     pub values: Vec<u32>,
     pub cats: HashMap<String, u32>,
+    /// `names[id]` is the category name for id `id` - the reverse of
+    /// `cats`, kept in sync by `push` so `cat_from_value` is an O(1) index
+    /// instead of a linear scan over `cats`.
+    names: Vec<String>,
     last: String,
     last_no: u32,
 }
@@ -16,6 +28,7 @@ impl Categorical {
         Categorical {
             values: xs,
             cats: hm,
+            names: Vec::new(),
             last: "".to_string(),
             last_no: 0,
         }
@@ -25,6 +38,7 @@ impl Categorical {
         let mut res = Categorical::new();
         if count > 0 {
             res.cats.insert("".to_string(), 0);
+            res.names.push("".to_string());
             res.values.resize(count as usize, 0);
         }
         res
@@ -40,7 +54,10 @@ impl Categorical {
             // this little trick saves 2 allocations and about 2 seconds
             let next = self.cats.len() as u32;
             let no = match self.cats.entry(value.to_string()) {
-                Vacant(entry) => entry.insert(next),
+                Vacant(entry) => {
+                    self.names.push(value.to_string());
+                    entry.insert(next)
+                }
                 Occupied(entry) => entry.into_mut(),
             };
             self.values.push(*no);
@@ -54,11 +71,7 @@ impl Categorical {
     /// retrieve the name of a category from it's index.
     /// Will panic if the index is out of bounds.
     pub fn cat_from_value(&self, value: u32) -> String {
-        self.cats
-            .iter()
-            .find(|(_, v)| **v == value)
-            .map(|(k, _)| k.clone())
-            .unwrap()
+        self.names[value as usize].clone()
     }
 
     pub fn len(&self) -> usize {
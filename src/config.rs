@@ -41,6 +41,10 @@ fn default_correct_reads_for_clipping() -> bool {
     true // this is the default in umi-tools
 }
 
+fn default_max_chunk_size() -> u32 {
+    10_000_000 // also the bp window ChunkedGenomeIterator cuts chunks at
+}
+
 #[derive(Deserialize, Debug, Clone, Serialize, Validate)]
 #[serde(deny_unknown_fields)]
 pub struct Input {
@@ -51,6 +55,50 @@ pub struct Input {
     pub source: Source,
     #[serde(default = "default_max_skip_length")]
     pub max_skip_length: u32,
+    /// Reference FASTA used to decode CRAM input (required by htslib whenever
+    /// `bam` points at a `.cram` file and no embedded/`.fai`-resolvable
+    /// reference is available).
+    #[serde(default)]
+    pub reference_fasta: Option<PathBuf>,
+    /// Optional `"chr:start-end"` genomic intervals (0-based, half-open) to
+    /// restrict quantification to, e.g. a targeted gene panel read off a BED
+    /// file. When set, only chunks overlapping one of these regions are
+    /// fetched from the BAI index instead of scanning the whole BAM.
+    /// Overlapping regions are merged before use.
+    #[serde(default)]
+    pub regions: Vec<String>,
+    /// When set, a truncated/corrupt BAM record no longer aborts the whole
+    /// run: it is skipped, tallied into the `corrupt_records` stat, and
+    /// quantification continues from the next readable record.
+    #[serde(default)]
+    pub tolerate_corrupt: bool,
+    /// Upper bound (in bases) on a single genome chunk before it gets split
+    /// for parallelism: it is the window `ChunkedGenome` cuts GTF- and
+    /// bin-based chunks at, and chunks generated from an uneven reference
+    /// set (e.g. one huge chromosome among many tiny contigs) are subdivided
+    /// to this size so rayon threads stay balanced. That subdivision step is
+    /// skipped when deduplication uses `DeduplicationBucket::PerReference`;
+    /// the `ChunkedGenome` window still applies there.
+    #[serde(default = "default_max_chunk_size")]
+    pub max_chunk_size: u32,
+    /// When set, genome chunking (GTF- and bin-based quantification) aims for
+    /// roughly this many reads per chunk instead of a fixed bp window:
+    /// each reference's average read density (from the BAM index's
+    /// per-reference mapped totals) shrinks the window below
+    /// `max_chunk_size` over dense loci (e.g. mitochondria, rRNA) so
+    /// parallel workers stay balanced even when reads are clustered rather
+    /// than spread evenly across the genome. `None` keeps the fixed
+    /// `max_chunk_size` window.
+    #[serde(default)]
+    pub target_reads_per_chunk: Option<u32>,
+    /// How many chunks ahead of the one currently being counted a
+    /// background thread may prefetch (open its own `IndexedReader`, fetch
+    /// the chunk's region, and drain it) so the BGZF decode for chunk N+k
+    /// overlaps the compute for chunk N instead of each chunk stalling on
+    /// its own fetch. `0` (default) disables prefetching and keeps the
+    /// plain synchronous per-chunk fetch.
+    #[serde(default)]
+    pub prefetch_depth: u32,
 }
 
 impl Input {
@@ -70,6 +118,27 @@ pub enum Source {
     BamReferences,
     #[serde(alias = "bam_tag")]
     BamTag(BamTag),
+    /// Annotation-free genome-wide coverage bins: every chromosome with
+    /// reads is cut into fixed-width `bin_width` windows, each becoming its
+    /// own counting unit named `chr:binstart-binstop` - useful for coverage
+    /// profiling, copy-number-style signal, or quantifying libraries with
+    /// no GTF at all.
+    #[serde(alias = "bins")]
+    Bins(BinsConfig),
+    /// Splice-junction quantification: tabulates, per `(tid, donor, acceptor,
+    /// strand)`, the number of (uniquely-mapped) spanning reads and the
+    /// maximum flanking overhang, writing an SJ table instead of a per-feature
+    /// `counts.tsv`. Bypasses the GTF/engine-based counting pipeline
+    /// entirely - see `splice_junctions::write_splice_junctions`.
+    #[serde(alias = "splice_junctions")]
+    SpliceJunctions,
+    /// 3'-tag RNA-seq quantification: assigns a read to a gene by the
+    /// genomic position of its 3' end falling inside that gene's
+    /// (extended) transcript-end window, rather than by full overlap.
+    /// Bypasses the GTF/engine-based counting pipeline entirely - see
+    /// `three_prime::quantify_three_prime`.
+    #[serde(alias = "three_prime")]
+    ThreePrime(ThreePrimeConfig),
 }
 
 impl Source {
@@ -87,11 +156,67 @@ impl Source {
                 }
                 Ok(())
             }
+            Source::Bins(bins_config) => {
+                if bins_config.bin_width == 0 {
+                    bail!("bin_width must be greater than zero");
+                }
+                Ok(())
+            }
             _ => Ok(()),
         }
     }
 }
 
+#[derive(Deserialize, Debug, Clone, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BinsConfig {
+    /// Width, in bp, of each genome-wide counting bin.
+    pub bin_width: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThreePrimeConfig {
+    pub filename: String,
+    #[serde(default)]
+    pub subformat: GTFFormat,
+    /// GTF feature whose rows define each transcript's span, e.g.
+    /// `"transcript"` - its max `end` (min for `-` strand genes) becomes the
+    /// annotated 3' end the window is extended from.
+    pub feature: String,
+    pub id_attribute: String,
+    /// How far downstream of the annotated transcript end a read's 3' end
+    /// may fall and still be assigned to that gene, to catch reads past the
+    /// annotated poly-A site.
+    pub downstream_extension: i64,
+    /// Bucket width (bp) for collapsing nearby 3' positions together before
+    /// UMI dedup, so PCR/sequencing jitter in the observed 3' end doesn't
+    /// split one molecule into several.
+    #[serde(default = "default_three_prime_bucket_width")]
+    pub bucket_width: i64,
+    /// BAM tag holding the (already-extracted) UMI sequence, e.g. `"UB"`.
+    #[serde(deserialize_with = "deser_tag")]
+    pub umi_tag: [u8; 2],
+    /// When set, discard reads whose 3' end looks like internal priming off
+    /// a genomic A-rich stretch rather than a true poly-A tail.
+    #[serde(default)]
+    pub internal_priming: Option<InternalPrimingConfig>,
+}
+
+fn default_three_prime_bucket_width() -> i64 {
+    1
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct InternalPrimingConfig {
+    /// How many downstream bases to inspect.
+    pub window_len: usize,
+    /// Minimum number of `A`/`T` (strand-dependent) bases among those
+    /// `window_len` to call a read internally primed.
+    pub min_a_bases: usize,
+}
+
 pub fn deser_tag<'de, D>(deserializer: D) -> core::result::Result<[u8; 2], D::Error>
 where
     D: Deserializer<'de>,
@@ -129,7 +254,7 @@ pub enum DuplicateHandling {
     Rename,
 }
 
-#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Serialize, Default)]
 #[serde(deny_unknown_fields)]
 pub enum OverlapMode {
     #[serde(alias = "union")]
@@ -149,6 +274,28 @@ pub enum MultiRegionHandling {
     #[serde(alias = "count_both")]
     #[default]
     CountBoth,
+    /// Assign the read to the single gene with the best alignment score
+    /// inside its overlapping region, instead of discarding it or counting
+    /// it for every gene it overlaps. Falls back to `Drop` semantics on a
+    /// tie between the best-scoring genes. See `Strategy::match_score` et al.
+    /// Scoring only happens with `overlap = IntersectionStrict` or
+    /// `IntersectionNonEmpty`; under `Union` (the default) no scores are
+    /// computed and every candidate gene is kept, as with `CountBoth`.
+    #[serde(alias = "resolve")]
+    Resolve,
+    /// Like `CountBoth`, but a read's weight across its ambiguous genes
+    /// isn't decided immediately: the read's equivalence class (its sorted
+    /// set of overlapping genes) is tallied instead, and once every chunk
+    /// has been merged, `em::resolve` estimates each gene's true abundance
+    /// from all tallied classes and that estimate is folded into the
+    /// reported counts - multimapper rescue via expectation-maximization,
+    /// as opposed to `em_rescue`'s informational-only sidecar. This is the
+    /// EM-based multimapper rescue that the orphaned, never-wired
+    /// `src/quantification/em_counter.rs` `Quant` impl (deleted in
+    /// `bc4c9cf`) was attempting against dead code; this variant is that
+    /// capability, implemented against the live counting path instead.
+    #[serde(alias = "defer")]
+    Defer,
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize, Default)]
@@ -163,6 +310,26 @@ pub enum MatchDirection {
     Ignore,
 }
 
+/// How much weight a single `Counted` read contributes to each gene it hits,
+/// when it hits more than one (featureCounts' `-O`/`--fraction` behaviour).
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub enum OverlapWeightMode {
+    /// Every overlapping gene gets a full +1, so a read hitting N genes
+    /// contributes N total counts - featureCounts' default
+    /// `--allowMultiOverlap` behaviour, and this crate's behaviour prior to
+    /// this option existing.
+    #[serde(alias = "full")]
+    #[serde(alias = "allow_multi_overlap")]
+    #[default]
+    Full,
+    /// Each overlapping gene gets `1/N` instead, so a read hitting N genes
+    /// still contributes exactly 1 total count, split evenly across them -
+    /// featureCounts' `--fraction`.
+    #[serde(alias = "fraction")]
+    Fraction,
+}
+
 #[derive(Deserialize, Debug, Clone, Serialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Strategy {
@@ -170,8 +337,74 @@ pub struct Strategy {
     pub overlap: OverlapMode,
     #[serde(default)]
     pub multi_region: MultiRegionHandling,
+    /// How much weight a read hitting multiple genes contributes to each -
+    /// see `OverlapWeightMode`. Has no effect under `multi_region = Drop`, or
+    /// under `Resolve` with an intersection `overlap`, since those never let
+    /// a read count towards more than one gene; `Resolve` with
+    /// `overlap = Union` keeps every candidate gene, so it applies there.
+    #[serde(default)]
+    pub overlap_weight: OverlapWeightMode,
     #[serde(default)]
     pub direction: MatchDirection,
+    /// Instead of trusting `direction`, sample `auto_detect_sample_size`
+    /// unambiguous reads up front, tally how many are explained by a
+    /// forward vs. reverse protocol, and use whichever one the evidence
+    /// supports (falling back to `Ignore` when roughly balanced) for the
+    /// rest of the run - overriding `direction` - so users no longer need
+    /// to know their protocol's strand orientation in advance.
+    #[serde(default)]
+    pub auto_detect_strandedness: bool,
+    /// How many unambiguous reads to sample when `auto_detect_strandedness`
+    /// is set.
+    #[serde(default = "default_auto_detect_sample_size")]
+    pub auto_detect_sample_size: usize,
+    /// Extend a read's first/last exon block by its leading/trailing
+    /// soft-clip length before testing for feature overlap, so clipped bases
+    /// still count towards matching htseq-count-style union/intersection
+    /// semantics at contig/exon boundaries.
+    #[serde(default)]
+    pub extend_span_by_softclips: bool,
+    /// Drop exon blocks (as split by `D`/`N` CIGAR ops) shorter than this
+    /// many matched bases before testing overlap, so e.g. a single stray
+    /// matched base next to a big deletion doesn't count as a hit.
+    #[serde(default)]
+    pub min_block_overlap: u32,
+    /// When merging a gene's overlapping blocks for the
+    /// `IntersectionStrict`/`IntersectionNonEmpty` containment tests,
+    /// coalesce blocks separated by up to this many bases into one run
+    /// instead of requiring true overlap/touching. Lets a read spanning a
+    /// tiny annotation gap (adjacent exons, a fragmented feature record)
+    /// still count as fully contained.
+    #[serde(default)]
+    pub max_gap_merge: u32,
+    /// Score awarded per matched (`M`/`=`) aligned base when
+    /// `multi_region = Resolve` picks between candidate genes.
+    #[serde(default = "default_match_score")]
+    pub match_score: f64,
+    /// Score subtracted per mismatched (`X`) aligned base when
+    /// `multi_region = Resolve` picks between candidate genes.
+    #[serde(default = "default_diff_score")]
+    pub diff_score: f64,
+    /// Score subtracted per inserted/deleted base when
+    /// `multi_region = Resolve` picks between candidate genes.
+    #[serde(default = "default_indel_score")]
+    pub indel_score: f64,
+}
+
+fn default_auto_detect_sample_size() -> usize {
+    10_000
+}
+
+fn default_match_score() -> f64 {
+    1.0
+}
+
+fn default_diff_score() -> f64 {
+    1.0
+}
+
+fn default_indel_score() -> f64 {
+    2.0
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize, Copy, Default)]
@@ -196,6 +429,18 @@ pub struct GTFConfig {
     pub duplicate_handling: DuplicateHandling,
 }
 
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub enum AnnotatedBamFormat {
+    #[serde(alias = "bam")]
+    #[default]
+    Bam,
+    /// Reference-compressed output; requires `Input::reference_fasta` to be
+    /// set so htslib can decode/encode against it.
+    #[serde(alias = "cram")]
+    Cram,
+}
+
 #[derive(Deserialize, Debug, Clone, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Output {
@@ -204,6 +449,121 @@ pub struct Output {
     pub write_annotated_bam: bool,
     #[serde(default)]
     pub only_correct: bool,
+    /// Gzip-compress the SingleCell matrix/features/barcodes output files
+    /// (10x-style `.tsv.gz`/`.mtx.gz`). Ignored for `PerRegion` output.
+    #[serde(default)]
+    pub compress_out: bool,
+    /// Container format for the annotated output BAM/CRAM (when
+    /// `write_annotated_bam` is set). `Cram` needs
+    /// `Input::reference_fasta` and produces `annotated.cram` instead of
+    /// `annotated.bam`.
+    #[serde(default)]
+    pub annotated_bam_format: AnnotatedBamFormat,
+    /// When set (and `write_annotated_bam` is also set), reads rejected as
+    /// `Filtered`/`NoBarcode`/`NoUMI`/`BarcodeNotInWhitelist` are additionally
+    /// written to per-category gzipped FASTQ files (`filtered.fastq.gz`,
+    /// `no_barcode.fastq.gz`, `no_umi.fastq.gz`,
+    /// `barcode_not_in_whitelist.fastq.gz`) alongside the annotated output,
+    /// so the unassigned fraction can be re-run through an alternate
+    /// whitelist or demultiplexer without re-filtering the whole BAM.
+    #[serde(default)]
+    pub write_rejected_fastq: bool,
+    /// After deduplication, additionally emit every surviving (non-duplicate)
+    /// `AnnotatedRead::Counted` read as a gzipped FASTQ record
+    /// (`dedup_1.fastq.gz`, plus `dedup_2.fastq.gz` for the mate of paired
+    /// reads), sequence/qualities reverse-complemented when the alignment
+    /// was on the reverse strand, with the extracted UMI and assigned
+    /// feature id appended to the description line. Lets the deduplicated
+    /// fraction be fed into another pipeline (e.g. re-alignment or QC)
+    /// without re-running the whole quantification. Independent of
+    /// `write_annotated_bam`/`write_rejected_fastq`.
+    #[serde(default)]
+    pub write_dedup_fastq: bool,
+    /// Alongside the normal (integer) `counts.tsv`, resolve reads that hit
+    /// more than one feature into fractional per-feature abundances via
+    /// expectation-maximization (RSEM/salmon-style) and write them to
+    /// `counts.tsv.em_rescue.tsv`. Purely additive: the main `counts.tsv`
+    /// and its `MultiRegionHandling` behaviour are unaffected. Ignored for
+    /// `SingleCell` output. See `em::resolve`.
+    #[serde(default)]
+    pub write_em_rescue: bool,
+    /// Emit an extra length-normalized column alongside each feature's raw
+    /// count in `counts.tsv`: `Rpkm`/`Fpkm` (reads/fragments per kilobase
+    /// per million mapped) or `Tpm` (transcripts per million, the
+    /// recommended one - columns sum to one million). `None` (the default)
+    /// keeps `counts.tsv` exactly as before. Ignored for `SingleCell`
+    /// output. See `engine::normalize_counts`.
+    #[serde(default)]
+    pub normalize: Option<NormalizationMode>,
+    /// Encoding of `counts.tsv`: plain tab-separated text (the default), or
+    /// a self-describing tagged/length-prefixed encoding that carries each
+    /// value's type (unsigned int, text, ...) so downstream parsers don't
+    /// have to guess column types. See `typed_format`. The `ThreePrime`
+    /// source always writes a plain TSV `counts.tsv`.
+    #[serde(default)]
+    pub counts_format: CountsFormat,
+    /// Encoding of the `SingleCell` feature x barcode matrix: 10x-style
+    /// `matrix.mtx` MatrixMarket text (the default), or a compact
+    /// self-describing binary triple stream (`matrix.bin`) that supports
+    /// random column access without scanning the whole file. Ignored for
+    /// `PerRegion` output. See `sparse_matrix`.
+    #[serde(default)]
+    pub matrix_format: MatrixFormat,
+}
+
+/// Output encoding for the `SingleCell` matrix. `MatrixMarket` is the
+/// 10x-compatible plain-text format this crate has always written.
+/// `Binary` writes `sparse_matrix::write_binary_matrix`'s compact
+/// little-endian triple format instead, readable back via
+/// `sparse_matrix::BinaryMatrixReader`.
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, Default)]
+#[serde(deny_unknown_fields)]
+pub enum MatrixFormat {
+    #[serde(alias = "matrix_market")]
+    #[default]
+    MatrixMarket,
+    #[serde(alias = "binary")]
+    Binary,
+}
+
+/// Output encoding for the counts table. `Tsv` is the plain
+/// `feature\tcount\n` format this crate has always written. `TypedText` and
+/// `TypedBinary` write the same netencode-like `<tag><len>:<payload>`
+/// framing from `typed_format` - `TypedText` one record per line for easy
+/// eyeballing/diffing, `TypedBinary` the compact wire form - both
+/// unambiguous and round-trip losslessly without a schema file.
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, Default)]
+#[serde(deny_unknown_fields)]
+pub enum CountsFormat {
+    #[serde(alias = "tsv")]
+    #[default]
+    Tsv,
+    #[serde(alias = "typed_text")]
+    TypedText,
+    #[serde(alias = "typed_binary")]
+    TypedBinary,
+}
+
+/// Length-normalized expression units `Output::normalize` can add to
+/// `counts.tsv`. All three need each feature's effective length in bp
+/// (`GTFEntrys` exon span sum, or reference length for contig/BamReferences
+/// quantification).
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum NormalizationMode {
+    /// reads per kilobase of feature length per million mapped reads.
+    #[serde(alias = "rpkm")]
+    Rpkm,
+    /// same formula and same counts as RPKM: every counted BAM record
+    /// contributes, and the mates of a pair are not merged into one
+    /// fragment, so the values equal RPKM's.
+    #[serde(alias = "fpkm")]
+    Fpkm,
+    /// transcripts per million: length-normalize first, then scale so the
+    /// column sums to one million - comparable across samples, unlike
+    /// RPKM/FPKM.
+    #[serde(alias = "tpm")]
+    Tpm,
 }
 
 impl Config {
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::bail;
 use rust_htslib::bam;
@@ -20,18 +20,39 @@ impl DeduplicationStrategy {
             DeduplicationMode::NoDedup => DedupPerBucket::None,
             DeduplicationMode::Umi => DedupPerBucket::Umi(HashMap::new()),
             DeduplicationMode::SingleCell => DedupPerBucket::SingleCell(HashMap::new()),
+            DeduplicationMode::Directional { threshold } => DedupPerBucket::Directional {
+                counts: HashMap::new(),
+                threshold: *threshold,
+            },
+            DeduplicationMode::SingleCellDirectional { threshold } => {
+                DedupPerBucket::SingleCellDirectional {
+                    counts: HashMap::new(),
+                    threshold: *threshold,
+                }
+            }
+            DeduplicationMode::Cluster { threshold } => DedupPerBucket::Cluster {
+                counts: HashMap::new(),
+                threshold: *threshold,
+            },
+            DeduplicationMode::Adjacency { threshold } => DedupPerBucket::Adjacency {
+                counts: HashMap::new(),
+                threshold: *threshold,
+            },
         }
     }
 
     pub fn check(&self, config: &Config) -> anyhow::Result<()> {
         match self.mode {
             DeduplicationMode::NoDedup => {}
-            DeduplicationMode::Umi => {
+            DeduplicationMode::Umi
+            | DeduplicationMode::Directional { .. }
+            | DeduplicationMode::Cluster { .. }
+            | DeduplicationMode::Adjacency { .. } => {
                 if config.umi.is_none() {
                     bail!("UMI deduplication quantification requires UMI extraction to be defined in the configuration.");
                 }
             }
-            DeduplicationMode::SingleCell => {
+            DeduplicationMode::SingleCell | DeduplicationMode::SingleCellDirectional { .. } => {
                 if config.cell_barcodes.is_none() {
                     bail!("SingleCell quantification requires cell barcodes to be defined in the configuration.");
                 }
@@ -64,6 +85,50 @@ pub enum DeduplicationMode {
     #[serde(alias = "singlecell")]
     #[serde(alias = "sc")]
     SingleCell,
+
+    /// UMI-tools' "directional" network method: UMIs at one exact position/gene
+    /// key that are within `threshold` Hamming distance of each other, with
+    /// read counts consistent with a sequencing-error relationship, are
+    /// collapsed into a single molecule. This is umi-tools' default and
+    /// recommended method.
+    #[serde(alias = "directional")]
+    Directional {
+        #[serde(default = "default_umi_distance_threshold")]
+        threshold: u32,
+    },
+
+    /// Like `Directional`, but the network is built independently within each
+    /// cell barcode, so UMIs from different cells at the same position never
+    /// collapse into each other.
+    #[serde(alias = "singlecell_directional")]
+    #[serde(alias = "sc_directional")]
+    SingleCellDirectional {
+        #[serde(default = "default_umi_distance_threshold")]
+        threshold: u32,
+    },
+
+    /// Collapses every connected component of the within-`threshold` Hamming
+    /// graph over a bucket's distinct UMIs into one molecule, regardless of
+    /// read count - umi-tools' "cluster" method.
+    #[serde(alias = "cluster")]
+    Cluster {
+        #[serde(default = "default_umi_distance_threshold")]
+        threshold: u32,
+    },
+
+    /// Greedily picks the highest-count UMI remaining in a bucket, collapses
+    /// it with every remaining UMI within `threshold` Hamming distance as one
+    /// molecule, removes them all, and repeats - umi-tools' "adjacency"
+    /// method.
+    #[serde(alias = "adjacency")]
+    Adjacency {
+        #[serde(default = "default_umi_distance_threshold")]
+        threshold: u32,
+    },
+}
+
+fn default_umi_distance_threshold() -> u32 {
+    1
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -91,6 +156,31 @@ pub enum DedupPerBucket {
     None,
     Umi(HashMap<Vec<u8>, (usize, MappingQuality)>),
     SingleCell(HashMap<(Vec<u8>, Vec<u8>), (usize, MappingQuality)>),
+    /// Like `Umi`, but also tallies how many reads were seen per exact UMI,
+    /// so that `directional_network_losers` can later merge near-identical
+    /// UMIs that differ only by sequencing error.
+    Directional {
+        counts: HashMap<Vec<u8>, (usize, MappingQuality, usize)>,
+        threshold: u32,
+    },
+    /// Like `Directional`, but UMI counts are tallied separately per cell
+    /// barcode, so the network merge never crosses cells.
+    SingleCellDirectional {
+        counts: HashMap<Vec<u8>, HashMap<Vec<u8>, (usize, MappingQuality, usize)>>,
+        threshold: u32,
+    },
+    /// Same per-UMI counting as `Directional`; only the post-hoc network
+    /// built by `network_losers` differs (`cluster_network_losers`).
+    Cluster {
+        counts: HashMap<Vec<u8>, (usize, MappingQuality, usize)>,
+        threshold: u32,
+    },
+    /// Same per-UMI counting as `Directional`; only the post-hoc network
+    /// built by `network_losers` differs (`adjacency_network_losers`).
+    Adjacency {
+        counts: HashMap<Vec<u8>, (usize, MappingQuality, usize)>,
+        threshold: u32,
+    },
 }
 
 pub enum AcceptReadResult {
@@ -171,6 +261,348 @@ impl DedupPerBucket {
                     }
                 }
             }
+            DedupPerBucket::Directional { counts, .. }
+            | DedupPerBucket::Cluster { counts, .. }
+            | DedupPerBucket::Adjacency { counts, .. } => {
+                let umi = umi
+                    .expect("UMI should be extracted before deduplication")
+                    .as_slice();
+                let this_mq = MappingQuality {
+                    no_of_alignments: read.no_of_alignments().try_into().unwrap_or(255),
+                    mapq: read.mapq(),
+                };
+                let hit = counts.get_mut(umi);
+                match hit {
+                    Some((old_index, mq, count)) => {
+                        *count += 1;
+                        if this_mq > *mq {
+                            *mq = this_mq;
+                            let result = AcceptReadResult::DuplicateButPrefered(*old_index);
+                            *old_index = this_index;
+                            result
+                        } else {
+                            AcceptReadResult::Duplicated
+                        }
+                    }
+                    None => {
+                        counts.insert(umi.to_vec(), (this_index, this_mq, 1));
+                        AcceptReadResult::New
+                    }
+                }
+            }
+            DedupPerBucket::SingleCellDirectional { counts, .. } => {
+                let umi = umi
+                    .expect("UMI should be extracted before deduplication")
+                    .as_slice();
+                let barcode = barcode
+                    .expect("Barcode should be extracted before deduplication")
+                    .as_slice();
+                let this_mq = MappingQuality {
+                    no_of_alignments: read.no_of_alignments().try_into().unwrap_or(255),
+                    mapq: read.mapq(),
+                };
+                let map = counts.entry(barcode.to_vec()).or_default();
+                let hit = map.get_mut(umi);
+                match hit {
+                    Some((old_index, mq, count)) => {
+                        *count += 1;
+                        if this_mq > *mq {
+                            *mq = this_mq;
+                            let result = AcceptReadResult::DuplicateButPrefered(*old_index);
+                            *old_index = this_index;
+                            result
+                        } else {
+                            AcceptReadResult::Duplicated
+                        }
+                    }
+                    None => {
+                        map.insert(umi.to_vec(), (this_index, this_mq, 1));
+                        AcceptReadResult::New
+                    }
+                }
+            }
+        }
+    }
+
+    /// For `Directional`/`Cluster`/`Adjacency` buckets, collapses UMIs that
+    /// only differ by sequencing error into a single molecule and returns
+    /// the `this_index` values of the reads that lost out and should be
+    /// converted from `Counted` to `Duplicate`. A no-op for every other
+    /// bucket kind.
+    pub fn network_losers(&self) -> HashSet<usize> {
+        match self {
+            DedupPerBucket::Directional { counts, threshold } => {
+                directional_network_losers(counts, *threshold)
+            }
+            DedupPerBucket::SingleCellDirectional { counts, threshold } => counts
+                .values()
+                .flat_map(|by_umi| directional_network_losers(by_umi, *threshold))
+                .collect(),
+            DedupPerBucket::Cluster { counts, threshold } => {
+                cluster_network_losers(counts, *threshold)
+            }
+            DedupPerBucket::Adjacency { counts, threshold } => {
+                adjacency_network_losers(counts, *threshold)
+            }
+            _ => HashSet::new(),
         }
     }
 }
+
+/// Whether `a` and `b` are within `threshold` Hamming distance, abandoning
+/// the comparison as soon as the running mismatch count exceeds `threshold`
+/// rather than always walking the full UMI. UMIs of differing length are
+/// never within threshold.
+fn hamming_within_threshold(a: &[u8], b: &[u8], threshold: u32) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut mismatches = 0u32;
+    for (x, y) in a.iter().zip(b) {
+        if x != y {
+            mismatches += 1;
+            if mismatches > threshold {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Buckets UMI indices by byte length, since UMIs of differing length never
+/// connect under `hamming_within_threshold` and the per-method graphs below
+/// only need to consider same-length pairs instead of the full cross product.
+fn bucket_indices_by_length(umis: &[&Vec<u8>]) -> Vec<Vec<usize>> {
+    let mut by_length: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, umi) in umis.iter().enumerate() {
+        by_length.entry(umi.len()).or_default().push(i);
+    }
+    by_length.into_values().collect()
+}
+
+/// Picks, for each component of UMI indices, the highest-count node (ties
+/// broken by the lexicographically smaller UMI, so output is reproducible)
+/// as the molecule's representative, and returns the `this_index` of every
+/// other node in the component - the ones that lost out and should be
+/// marked duplicate.
+fn losers_from_components(
+    umi_counts: &HashMap<Vec<u8>, (usize, MappingQuality, usize)>,
+    umis: &[&Vec<u8>],
+    components: Vec<Vec<usize>>,
+) -> HashSet<usize> {
+    let mut losers = HashSet::new();
+    for component in components {
+        let best = *component
+            .iter()
+            .max_by(|&&a, &&b| {
+                let count_a = umi_counts[umis[a]].2;
+                let count_b = umi_counts[umis[b]].2;
+                count_a.cmp(&count_b).then_with(|| umis[b].cmp(umis[a]))
+            })
+            .expect("component is never empty");
+
+        for node in component {
+            if node != best {
+                losers.insert(umi_counts[umis[node]].0);
+            }
+        }
+    }
+    losers
+}
+
+/// Implements the UMI-tools "directional" network method over the UMIs that
+/// survived exact-match deduplication within one position/gene bucket: an
+/// edge A <-> B exists iff `hamming(A, B) <= threshold` and one of the two
+/// UMIs has a read count consistent with the other being its sequencing
+/// error (`count(A) >= 2*count(B) - 1` or vice versa). The weakly-connected
+/// components of the resulting graph are the true molecules.
+fn directional_network_losers(
+    umi_counts: &HashMap<Vec<u8>, (usize, MappingQuality, usize)>,
+    threshold: u32,
+) -> HashSet<usize> {
+    let umis: Vec<&Vec<u8>> = umi_counts.keys().collect();
+    let n = umis.len();
+    let mut connected: Vec<HashSet<usize>> = vec![HashSet::new(); n];
+    for bucket in bucket_indices_by_length(&umis) {
+        for (bi, &i) in bucket.iter().enumerate() {
+            for &j in &bucket[bi + 1..] {
+                if !hamming_within_threshold(umis[i], umis[j], threshold) {
+                    continue;
+                }
+                let count_i = umi_counts[umis[i]].2 as i64;
+                let count_j = umi_counts[umis[j]].2 as i64;
+                if count_i >= 2 * count_j - 1 || count_j >= 2 * count_i - 1 {
+                    connected[i].insert(j);
+                    connected[j].insert(i);
+                }
+            }
+        }
+    }
+
+    let mut visited = vec![false; n];
+    let mut components = Vec::new();
+    for start in 0..n {
+        if visited[start] {
+            continue;
+        }
+        let mut component = vec![start];
+        visited[start] = true;
+        let mut stack = vec![start];
+        while let Some(node) = stack.pop() {
+            for &neighbour in &connected[node] {
+                if !visited[neighbour] {
+                    visited[neighbour] = true;
+                    stack.push(neighbour);
+                    component.push(neighbour);
+                }
+            }
+        }
+        components.push(component);
+    }
+    losers_from_components(umi_counts, &umis, components)
+}
+
+/// Implements the UMI-tools "cluster" method: the weakly-connected
+/// components of the within-`threshold` Hamming graph over a bucket's
+/// distinct UMIs, regardless of read count, are each collapsed into one
+/// molecule.
+fn cluster_network_losers(
+    umi_counts: &HashMap<Vec<u8>, (usize, MappingQuality, usize)>,
+    threshold: u32,
+) -> HashSet<usize> {
+    let umis: Vec<&Vec<u8>> = umi_counts.keys().collect();
+    let n = umis.len();
+
+    fn find(parent: &mut [usize], i: usize) -> usize {
+        if parent[i] != i {
+            parent[i] = find(parent, parent[i]);
+        }
+        parent[i]
+    }
+
+    let mut parent: Vec<usize> = (0..n).collect();
+    for bucket in bucket_indices_by_length(&umis) {
+        for (bi, &i) in bucket.iter().enumerate() {
+            for &j in &bucket[bi + 1..] {
+                if hamming_within_threshold(umis[i], umis[j], threshold) {
+                    let (ri, rj) = (find(&mut parent, i), find(&mut parent, j));
+                    if ri != rj {
+                        parent[ri] = rj;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut components: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..n {
+        let root = find(&mut parent, i);
+        components.entry(root).or_default().push(i);
+    }
+    losers_from_components(umi_counts, &umis, components.into_values().collect())
+}
+
+/// Implements the UMI-tools "adjacency" method: greedily picks the
+/// highest-count UMI remaining in a bucket, collapses it with every
+/// remaining UMI within `threshold` Hamming distance as one molecule,
+/// removes them all, and repeats.
+fn adjacency_network_losers(
+    umi_counts: &HashMap<Vec<u8>, (usize, MappingQuality, usize)>,
+    threshold: u32,
+) -> HashSet<usize> {
+    let umis: Vec<&Vec<u8>> = umi_counts.keys().collect();
+    let mut components: Vec<Vec<usize>> = Vec::new();
+    for bucket in bucket_indices_by_length(&umis) {
+        let mut remaining = bucket;
+        remaining.sort_by_key(|&i| std::cmp::Reverse(umi_counts[umis[i]].2));
+
+        while let Some(&best) = remaining.first() {
+            let mut component = vec![best];
+            let mut rest = Vec::new();
+            for &other in &remaining[1..] {
+                if hamming_within_threshold(umis[best], umis[other], threshold) {
+                    component.push(other);
+                } else {
+                    rest.push(other);
+                }
+            }
+            components.push(component);
+            remaining = rest;
+        }
+    }
+    losers_from_components(umi_counts, &umis, components)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn counts(entries: &[(&[u8], usize)]) -> HashMap<Vec<u8>, (usize, MappingQuality, usize)> {
+        entries
+            .iter()
+            .enumerate()
+            .map(|(idx, (umi, count))| {
+                (
+                    umi.to_vec(),
+                    (
+                        idx,
+                        MappingQuality {
+                            no_of_alignments: 1,
+                            mapq: 60,
+                        },
+                        *count,
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_directional_merges_sequencing_error_neighbour() {
+        // "AAAA" (count 10) and "AAAT" (count 1, hamming distance 1) should
+        // collapse into one molecule, with the low-count UMI losing out.
+        let by_umi = counts(&[(b"AAAA", 10), (b"AAAT", 1)]);
+        let losers = directional_network_losers(&by_umi, 1);
+        assert_eq!(losers, HashSet::from([by_umi[b"AAAT".as_slice()].0]));
+    }
+
+    #[test]
+    fn test_directional_keeps_unrelated_umis_separate() {
+        // Two UMIs with comparable counts but too far apart in Hamming
+        // distance to plausibly be the same molecule.
+        let by_umi = counts(&[(b"AAAA", 5), (b"CCCC", 5)]);
+        let losers = directional_network_losers(&by_umi, 1);
+        assert!(losers.is_empty());
+    }
+
+    #[test]
+    fn test_directional_ignores_different_length_umis() {
+        let by_umi = counts(&[(b"AAAA", 10), (b"AAA", 1)]);
+        let losers = directional_network_losers(&by_umi, 1);
+        assert!(losers.is_empty());
+    }
+
+    #[test]
+    fn test_cluster_merges_regardless_of_count_ratio() {
+        // "cluster" has no count-ratio requirement, so two close-count UMIs
+        // within threshold still merge, unlike "directional".
+        let by_umi = counts(&[(b"AAAA", 5), (b"AAAT", 4)]);
+        let losers = cluster_network_losers(&by_umi, 1);
+        assert_eq!(losers, HashSet::from([by_umi[b"AAAT".as_slice()].0]));
+    }
+
+    #[test]
+    fn test_adjacency_absorbs_from_highest_count_down() {
+        let by_umi = counts(&[(b"AAAA", 10), (b"AAAT", 3), (b"AAAC", 3)]);
+        // Both neighbours of "AAAA" get absorbed by it in one pass, even
+        // though they are themselves within threshold of each other too.
+        let losers = adjacency_network_losers(&by_umi, 1);
+        assert_eq!(
+            losers,
+            HashSet::from([
+                by_umi[b"AAAT".as_slice()].0,
+                by_umi[b"AAAC".as_slice()].0
+            ])
+        );
+    }
+}
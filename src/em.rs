@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+/// One equivalence class: a set of genes a group of reads hit ambiguously,
+/// and how many reads fell into exactly that set. Reads that hit a single
+/// gene form a class of size one.
+#[derive(Debug, Clone)]
+pub struct EquivalenceClass {
+    pub genes: Vec<String>,
+    pub count: usize,
+}
+
+/// Resolves multi-gene (ambiguous) reads into fractional per-gene abundances
+/// via expectation-maximization, the way RSEM/salmon resolve multi-mapping
+/// reads: each iteration's E-step splits every equivalence class's reads
+/// across its genes in proportion to the current abundance estimate, and the
+/// M-step re-estimates abundance from those fractional assignments. Iterates
+/// until the largest per-gene change drops below `tolerance` or
+/// `max_iterations` is hit, whichever comes first. Singleton classes (reads
+/// that hit exactly one gene) are left to converge naturally; they dominate
+/// the denominator, so in practice they anchor the estimate within a handful
+/// of iterations.
+pub fn resolve(
+    classes: &[EquivalenceClass],
+    tolerance: f64,
+    max_iterations: usize,
+) -> HashMap<String, f64> {
+    let mut seen = std::collections::HashSet::new();
+    let mut genes: Vec<&str> = Vec::new();
+    for class in classes {
+        for gene in &class.genes {
+            if seen.insert(gene.as_str()) {
+                genes.push(gene.as_str());
+            }
+        }
+    }
+    if genes.is_empty() {
+        return HashMap::new();
+    }
+
+    let mut theta: HashMap<&str, f64> = genes.iter().map(|g| (*g, 1.0 / genes.len() as f64)).collect();
+
+    for _ in 0..max_iterations {
+        let mut next = HashMap::new();
+        for class in classes {
+            if class.genes.len() == 1 {
+                *next.entry(class.genes[0].as_str()).or_insert(0.0) += class.count as f64;
+                continue;
+            }
+            let total: f64 = class.genes.iter().map(|g| theta[g.as_str()]).sum();
+            if total <= 0.0 {
+                // no prior mass on any gene in this class (shouldn't happen once
+                // singletons have run); split evenly rather than divide by zero.
+                let share = class.count as f64 / class.genes.len() as f64;
+                for gene in &class.genes {
+                    *next.entry(gene.as_str()).or_insert(0.0) += share;
+                }
+                continue;
+            }
+            for gene in &class.genes {
+                let share = class.count as f64 * theta[gene.as_str()] / total;
+                *next.entry(gene.as_str()).or_insert(0.0) += share;
+            }
+        }
+        let total_reads: f64 = next.values().sum();
+        let mut max_delta = 0.0f64;
+        let mut normalized = HashMap::new();
+        for gene in &genes {
+            let new_theta = if total_reads > 0.0 {
+                next.get(*gene).copied().unwrap_or(0.0) / total_reads
+            } else {
+                0.0
+            };
+            max_delta = max_delta.max((new_theta - theta[gene]).abs());
+            normalized.insert(*gene, new_theta);
+        }
+        theta = normalized;
+        if max_delta < tolerance {
+            break;
+        }
+    }
+
+    // Report absolute (not normalized-to-one) fractional read counts per gene.
+    let total_reads: f64 = classes.iter().map(|c| c.count as f64).sum();
+    theta
+        .into_iter()
+        .map(|(gene, frac)| (gene.to_string(), frac * total_reads))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn class(genes: &[&str], count: usize) -> EquivalenceClass {
+        EquivalenceClass {
+            genes: genes.iter().map(|g| g.to_string()).collect(),
+            count,
+        }
+    }
+
+    #[test]
+    fn test_empty_input_resolves_to_empty() {
+        let resolved = resolve(&[], 1e-6, 1000);
+        assert!(resolved.is_empty());
+    }
+
+    #[test]
+    fn test_singletons_only_pass_through_unchanged() {
+        let classes = vec![class(&["a"], 10), class(&["b"], 5)];
+        let resolved = resolve(&classes, 1e-6, 1000);
+        assert_eq!(resolved.len(), 2);
+        assert!((resolved["a"] - 10.0).abs() < 1e-6);
+        assert!((resolved["b"] - 5.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_ambiguous_class_splits_evenly_with_equal_singleton_support() {
+        // "a" and "b" get equal singleton support, so the ambiguous reads
+        // shared between them should split 50/50.
+        let classes = vec![class(&["a"], 10), class(&["b"], 10), class(&["a", "b"], 20)];
+        let resolved = resolve(&classes, 1e-9, 10_000);
+        assert!((resolved["a"] - 20.0).abs() < 1e-3);
+        assert!((resolved["b"] - 20.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_ambiguous_class_biased_toward_better_supported_gene() {
+        // "a" has far more singleton support than "b", so the ambiguous
+        // reads should mostly be attributed to "a".
+        let classes = vec![
+            class(&["a"], 90),
+            class(&["b"], 10),
+            class(&["a", "b"], 100),
+        ];
+        let resolved = resolve(&classes, 1e-9, 10_000);
+        assert!(resolved["a"] > resolved["b"]);
+        // total reads conserved across genes.
+        assert!((resolved["a"] + resolved["b"] - 200.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_converges_within_max_iterations_for_three_way_ambiguity() {
+        let classes = vec![
+            class(&["a"], 50),
+            class(&["b"], 30),
+            class(&["c"], 10),
+            class(&["a", "b", "c"], 60),
+        ];
+        let resolved = resolve(&classes, 1e-9, 10_000);
+        let total: f64 = resolved.values().sum();
+        assert!((total - 150.0).abs() < 1e-3);
+        // better-supported genes still end up with more of the shared mass.
+        assert!(resolved["a"] > resolved["b"]);
+        assert!(resolved["b"] > resolved["c"]);
+    }
+}
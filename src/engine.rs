@@ -1,6 +1,6 @@
 use ex::Wrapper;
 use std::collections::{BTreeMap, HashMap, HashSet};
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use string_interner::symbol::SymbolU32;
@@ -14,22 +14,65 @@ use crate::extractors::UMIExtractor;
 use crate::filters::ReadFilter;
 use crate::gtf::Strand;
 use anyhow::{bail, Context, Result};
-use bio::data_structures::interval_tree::IntervalTree;
 use chunked_genome::{Chunk, ChunkedGenome};
+use fixedbitset::FixedBitSet;
 use itertools::{izip, Itertools};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rust_htslib::bam::{self, Read as ReadTrait};
 
 use crate::gtf::GTFEntrys;
-pub type OurTree = IntervalTree<u32, (u32, Strand)>;
+/// Backing structure for per-chromosome annotation overlap queries. Backed
+/// by `crate::ailist::AIList`, an Augmented Interval List (AIList/ScAIList)
+/// that bounds worst-case query cost when a few very long features engulf
+/// many small ones - the plain `bio::data_structures::interval_tree::
+/// IntervalTree` this replaced had no such bound. See `ailist::AIList`'s
+/// doc comment for how the query split works.
+pub type OurTree = crate::ailist::AIList<(u32, Strand)>;
 
 pub type OurInterner = StringInterner<string_interner::backend::StringBackend>;
 
+/// Per-chromosome BITS (Binary Interval Search) overlap counter: two
+/// independently-sorted arrays of interval starts and ends, answering "how
+/// many annotation features does this query range touch" via two binary
+/// searches instead of materializing the overlapping set. Used to cheaply
+/// detect clearly-unambiguous reads before paying for the full per-gene
+/// interval accumulation in `TreeMatcher::hits()`.
+#[derive(Debug, Clone, Default)]
+pub struct BitsIndex {
+    starts: Vec<u32>,
+    ends: Vec<u32>,
+}
+
+impl BitsIndex {
+    fn new(intervals: impl Iterator<Item = (u32, u32)>) -> Self {
+        let mut starts = Vec::new();
+        let mut ends = Vec::new();
+        for (start, end) in intervals {
+            starts.push(start);
+            ends.push(end);
+        }
+        starts.sort_unstable();
+        ends.sort_unstable();
+        BitsIndex { starts, ends }
+    }
+
+    /// Counts how many indexed intervals overlap `[query.start, query.end)`:
+    /// the number of interval starts strictly before `query.end`, minus the
+    /// number of interval ends at or before `query.start` (those have
+    /// already closed before the query begins).
+    pub fn count_overlaps(&self, query: std::ops::Range<u32>) -> usize {
+        let starts_before_end = self.starts.partition_point(|&s| s < query.end);
+        let ends_at_or_before_start = self.ends.partition_point(|&e| e <= query.start);
+        starts_before_end - ends_at_or_before_start
+    }
+}
+
 pub fn build_trees_from_gtf(
     id_attribute: &str,
     gtf_entries: &GTFEntrys,
-) -> Result<HashMap<String, (OurTree, Vec<String>)>> {
+) -> Result<HashMap<String, (OurTree, Vec<String>, BitsIndex)>> {
     let mut trees: HashMap<u32, OurTree> = HashMap::new();
+    let mut intervals_by_chr: HashMap<u32, Vec<(u32, u32)>> = HashMap::new();
     let mut gene_nos_by_chr = HashMap::new();
     for (seq_name_cat_id, gene_id, start, end, strand) in izip!(
         gtf_entries.seqname.values.iter(),
@@ -56,6 +99,10 @@ pub fn build_trees_from_gtf(
             .context("Start value is not a valid u64")?;
         let end: u32 = (*end).try_into().context("End value is not a valid u64")?;
 
+        intervals_by_chr
+            .entry(*seq_name_cat_id)
+            .or_default()
+            .push((start, end));
         tree.insert(
             start..end, //these are already 0-based
             (*gene_no as u32, *strand),
@@ -68,7 +115,13 @@ pub fn build_trees_from_gtf(
             let gene_nos = gene_nos_by_chr
                 .remove(&seq_name_cat_id)
                 .context("Missing gene numbers for sequence name")?;
-            Ok((seq_name, (tree, gene_nos.1)))
+            let bits = BitsIndex::new(
+                intervals_by_chr
+                    .remove(&seq_name_cat_id)
+                    .unwrap_or_default()
+                    .into_iter(),
+            );
+            Ok((seq_name, (tree, gene_nos.1, bits)))
         })
         .collect();
 
@@ -79,7 +132,7 @@ pub fn build_trees_from_gtf(
 pub fn build_trees_from_gtf_merged(
     id_attribute: &str,
     gtf_entries: &GTFEntrys,
-) -> Result<HashMap<String, (OurTree, Vec<String>)>> {
+) -> Result<HashMap<String, (OurTree, Vec<String>, BitsIndex)>> {
     let mut intervals: HashMap<u32, HashMap<String, (u32, u32)>> = HashMap::new();
 
     for (seq_name_cat_id, gene_id, start, end) in izip!(
@@ -119,11 +172,90 @@ pub fn build_trees_from_gtf_merged(
         for (start, stop) in intervals.values() {
             tree.insert(*start..*stop, (0, Strand::Unstranded));
         }
-        res.insert(seq_name, (tree, vec!["ignored".to_string()]));
+        let bits = BitsIndex::new(intervals.values().copied());
+        res.insert(seq_name, (tree, vec!["ignored".to_string()], bits));
     }
     Ok(res)
 }
 
+/// Samples up to `sample_size` unambiguous reads from `bam_path` (primary,
+/// mapped, and whose aligned span - first to last aligned base, introns
+/// included - overlaps annotation features under only
+/// one strand assumption) and tallies how many are explained by a forward
+/// vs. a reverse protocol, i.e. `Strategy::auto_detect_strandedness`'s
+/// implementation. Returns `Forward`/`Reverse` when one explains at least
+/// 80% of the classified sample, else `Ignore` (roughly balanced, i.e.
+/// unstranded). Logs the decision and the vote counts it was based on.
+pub fn detect_strandedness(
+    bam_path: &str,
+    reference_to_count_trees: &HashMap<String, (OurTree, Vec<String>, BitsIndex)>,
+    sample_size: usize,
+) -> Result<crate::config::MatchDirection> {
+    let mut bam =
+        bam::Reader::from_path(bam_path).context("Failed to open BAM for strandedness detection")?;
+    let header = bam.header().to_owned();
+    let mut forward_votes = 0usize;
+    let mut reverse_votes = 0usize;
+    let max_records_scanned = sample_size.saturating_mul(50).max(100_000);
+    let mut records_scanned = 0usize;
+    for record in bam.records() {
+        if forward_votes + reverse_votes >= sample_size || records_scanned >= max_records_scanned {
+            break;
+        }
+        records_scanned += 1;
+        let record = record.context("Failed to read BAM record during strandedness detection")?;
+        if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        let tid = record.tid();
+        if tid < 0 {
+            continue;
+        }
+        let chr = std::str::from_utf8(header.tid2name(tid as u32))
+            .context("reference name wasn't utf8")?
+            .to_string();
+        let Some((tree, _gene_ids, _bits)) = reference_to_count_trees.get(&chr) else {
+            continue;
+        };
+        let mut forward_hit = false;
+        let mut reverse_hit = false;
+        for r in tree.find(record.pos() as u32..record.cigar().end_pos() as u32) {
+            let entry = r.data();
+            let region_strand = entry.1;
+            match (record.is_reverse(), region_strand) {
+                (false, Strand::Forward) | (true, Strand::Reverse) => forward_hit = true,
+                (false, Strand::Reverse) | (true, Strand::Forward) => reverse_hit = true,
+                (_, Strand::Unstranded) => {}
+            }
+        }
+        if forward_hit != reverse_hit {
+            // only unambiguous (single-direction) evidence counts as a vote.
+            if forward_hit {
+                forward_votes += 1;
+            } else {
+                reverse_votes += 1;
+            }
+        }
+    }
+    let total = forward_votes + reverse_votes;
+    let direction = if total == 0 {
+        crate::config::MatchDirection::Ignore
+    } else {
+        let forward_fraction = forward_votes as f64 / total as f64;
+        if forward_fraction >= 0.8 {
+            crate::config::MatchDirection::Forward
+        } else if forward_fraction <= 0.2 {
+            crate::config::MatchDirection::Reverse
+        } else {
+            crate::config::MatchDirection::Ignore
+        }
+    };
+    eprintln!(
+        "Auto-detected library strandedness: {direction:?} ({forward_votes} forward, {reverse_votes} reverse out of {total} classified reads)",
+    );
+    Ok(direction)
+}
+
 #[derive(Debug)]
 pub enum AnnotatedRead {
     Filtered,
@@ -150,6 +282,10 @@ pub struct AnnotatedReadInfo {
     pub hits: Hits,
     pub umi: Option<Vec<u8>>,     // Optional: What's it's UMI. 24 bytes
     pub barcode: Option<Vec<u8>>, // Optional: What's it's cell-barcode 24 bytes
+    /// Whether `barcode` differs from what was read off the record, i.e. it
+    /// was recovered via `CellBarcodes::correct_read` rather than an exact
+    /// whitelist hit.
+    pub barcode_was_corrected: bool,
     pub mapping_priority: (u8, u8),
     pub reverse: bool,
 }
@@ -158,6 +294,7 @@ pub enum ReadToGeneMatcher {
     TreeMatcher(TreeMatcher),
     TagMatcher(TagMatcher),
     ReferenceMatcher(ReferenceMatcher),
+    BinMatcher(BinMatcher),
 }
 
 impl ReadToGeneMatcher {
@@ -166,6 +303,7 @@ impl ReadToGeneMatcher {
             ReadToGeneMatcher::TreeMatcher(matcher) => matcher.generate_chunks(bam),
             ReadToGeneMatcher::TagMatcher(matcher) => matcher.generate_chunks(bam),
             ReadToGeneMatcher::ReferenceMatcher(matcher) => matcher.generate_chunks(bam),
+            ReadToGeneMatcher::BinMatcher(matcher) => matcher.generate_chunks(bam),
         }
     }
 
@@ -182,40 +320,117 @@ impl ReadToGeneMatcher {
             ReadToGeneMatcher::TreeMatcher(matcher) => matcher.hits(chunk, read, interner),
             ReadToGeneMatcher::TagMatcher(matcher) => matcher.hits(chunk, read, interner),
             ReadToGeneMatcher::ReferenceMatcher(matcher) => matcher.hits(chunk, read, interner),
+            ReadToGeneMatcher::BinMatcher(matcher) => matcher.hits(chunk, read, interner),
+        }
+    }
+
+    /// How much weight a read hitting multiple genes should contribute to
+    /// each, per `Strategy::overlap_weight` - only `TreeMatcher` carries a
+    /// `Strategy` today, so every other variant counts each hit in full.
+    fn overlap_weight(&self) -> crate::config::OverlapWeightMode {
+        match self {
+            ReadToGeneMatcher::TreeMatcher(matcher) => matcher.count_strategy.overlap_weight,
+            ReadToGeneMatcher::TagMatcher(_)
+            | ReadToGeneMatcher::ReferenceMatcher(_)
+            | ReadToGeneMatcher::BinMatcher(_) => crate::config::OverlapWeightMode::Full,
+        }
+    }
+
+    /// Whether ambiguous (multi-gene) reads are being held out of `counter`
+    /// to be resolved by EM and folded back in at `Output::finish`, per
+    /// `MultiRegionHandling::Defer` - only `TreeMatcher` carries a `Strategy`,
+    /// so every other variant never defers.
+    fn defers_ambiguous(&self) -> bool {
+        match self {
+            ReadToGeneMatcher::TreeMatcher(matcher) => matches!(
+                matcher.count_strategy.multi_region,
+                crate::config::MultiRegionHandling::Defer
+            ),
+            ReadToGeneMatcher::TagMatcher(_)
+            | ReadToGeneMatcher::ReferenceMatcher(_)
+            | ReadToGeneMatcher::BinMatcher(_) => false,
         }
     }
 }
 
 pub enum CounterPerChunk {
     PerRegion {
-        counter: HashMap<string_interner::symbol::SymbolU32, (usize, usize)>,
+        /// `(count_correct, count_reverse)`. `f64` rather than `usize`
+        /// because `OverlapWeightMode::Fraction` splits a single read's
+        /// weight across every gene it hits instead of awarding each a
+        /// full +1.
+        counter: HashMap<string_interner::symbol::SymbolU32, (f64, f64)>,
         stat_counter: HashMap<String, usize>,
+        /// Multi-gene equivalence classes seen in this chunk, keyed by the
+        /// sorted set of symbols a `Counted` read's `hits.correct` resolved
+        /// to (populated whether or not `Output::PerRegion::em_rescue` is set).
+        /// See `Output::PerRegion::ambiguous_classes`.
+        ambiguous_classes: HashMap<Vec<string_interner::symbol::SymbolU32>, usize>,
     },
     SingleCell {
         stat_counter: HashMap<String, usize>,
+        // keyed on (gene, barcode) rather than collapsed into a single
+        // per-gene total, so `Output::count_reads`/`finish` can emit the
+        // 10x-style gene x cell matrix.mtx triplet instead of one flat
+        // counts.tsv column.
         counter: HashMap<(string_interner::symbol::SymbolU32, Vec<u8>), usize>,
     },
 }
 
 impl CounterPerChunk {
-    fn count_reads(&mut self, annotated_reads: &Vec<(AnnotatedRead, usize)>) -> Result<()> {
+    /// Tallies BAM records that `tolerate_corrupt` let us skip past in this
+    /// chunk, so the corruption is visible in the final stats file instead of
+    /// silently vanishing.
+    fn record_corrupt(&mut self, n: usize) {
+        let stat_counter = match self {
+            CounterPerChunk::PerRegion { stat_counter, .. } => stat_counter,
+            CounterPerChunk::SingleCell { stat_counter, .. } => stat_counter,
+        };
+        *stat_counter.entry("corrupt_records".to_string()).or_default() += n;
+    }
+
+    fn count_reads(
+        &mut self,
+        annotated_reads: &Vec<(AnnotatedRead, usize)>,
+        overlap_weight: crate::config::OverlapWeightMode,
+        defer_ambiguous: bool,
+    ) -> Result<()> {
         match self {
             CounterPerChunk::PerRegion {
                 counter,
                 stat_counter,
-                ..
+                ambiguous_classes,
             } => {
                 for (read, _org_index) in annotated_reads {
                     let count_as = match read {
                         AnnotatedRead::Counted(info) => {
                             let hits = &info.hits;
-                            for gene in &hits.correct {
-                                let entry = counter.entry(*gene).or_insert((0, 0));
-                                entry.0 = entry.0.saturating_add(1)
+                            let is_deferred = defer_ambiguous && hits.correct.len() > 1;
+                            let weight_of = |n: usize| match overlap_weight {
+                                crate::config::OverlapWeightMode::Full => 1.0,
+                                crate::config::OverlapWeightMode::Fraction => {
+                                    1.0 / (n.max(1) as f64)
+                                }
+                            };
+                            let weight_correct = weight_of(hits.correct.len());
+                            let weight_reverse = weight_of(hits.reverse.len());
+                            if !is_deferred {
+                                for gene in &hits.correct {
+                                    let entry = counter.entry(*gene).or_insert((0.0, 0.0));
+                                    entry.0 += weight_correct;
+                                }
                             }
                             for gene in &hits.reverse {
-                                let entry = counter.entry(*gene).or_insert((0, 0));
-                                entry.1 = entry.1.saturating_add(1)
+                                let entry = counter.entry(*gene).or_insert((0.0, 0.0));
+                                entry.1 += weight_reverse;
+                            }
+                            if hits.correct.len() > 1 {
+                                // Tracked unconditionally (not just when
+                                // deferring) so `write_em_rescue`'s sidecar
+                                // still works under `CountBoth`/`Resolve` too.
+                                let mut class: Vec<_> = hits.correct.clone();
+                                class.sort_unstable();
+                                *ambiguous_classes.entry(class).or_insert(0) += 1;
                             }
                             match (hits.correct.is_empty(), hits.reverse.is_empty()) {
                                 (true, true) => "outside",
@@ -239,6 +454,11 @@ impl CounterPerChunk {
                         //todo: preinsert values
                         *stat_counter.entry(count_as.to_string()).or_default() += 1;
                     }
+                    if let AnnotatedRead::Counted(info) = read {
+                        if info.barcode_was_corrected {
+                            *stat_counter.entry("barcode_corrected".to_string()).or_default() += 1;
+                        }
+                    }
                 }
             }
             CounterPerChunk::SingleCell {
@@ -282,6 +502,11 @@ impl CounterPerChunk {
                         //todo: preinsert values
                         *stat_counter.entry(count_as.to_string()).or_default() += 1;
                     }
+                    if let AnnotatedRead::Counted(info) = read {
+                        if info.barcode_was_corrected {
+                            *stat_counter.entry("barcode_corrected".to_string()).or_default() += 1;
+                        }
+                    }
                 }
             }
         }
@@ -292,28 +517,105 @@ impl CounterPerChunk {
 pub enum Output {
     PerRegion {
         output_filename: PathBuf,
-        counter: HashMap<String, (usize, usize)>,
+        /// `(count_correct, count_reverse)`, see
+        /// `CounterPerChunk::PerRegion::counter`.
+        counter: HashMap<String, (f64, f64)>,
         stat_counter: HashMap<String, usize>,
         sorted_keys: Option<Vec<String>>,
         first_column_only: bool,
         id_attribute: String,
+        /// Effective length (bp) of each feature, needed only when
+        /// `normalization` is set. For GTF sources this is the summed span
+        /// of that feature's exons; for `BamReferences` it's the reference
+        /// length from the BAM header.
+        feature_lengths: Option<HashMap<String, u64>>,
+        normalization: Option<crate::config::NormalizationMode>,
+        /// Encoding to write `output_filename` in - plain TSV, or one of
+        /// the self-describing `typed_format` encodings.
+        counts_format: crate::config::CountsFormat,
+        /// Whether to additionally resolve multi-gene equivalence classes
+        /// via EM and write `<output_filename>.em_rescue.tsv`. See
+        /// `Output::write_em_rescue`.
+        em_rescue: bool,
+        /// Multi-gene equivalence classes accumulated across every chunk
+        /// (one entry per distinct sorted gene set a `Counted` read's
+        /// `hits.correct` resolved to, counting identical sets together),
+        /// populated whether or not `em_rescue` is set. Fed to `em::resolve` in
+        /// `finish`.
+        ambiguous_classes: HashMap<Vec<String>, usize>,
     },
     SingleCell {
         output_prefix: PathBuf,
         stat_counter: HashMap<String, usize>,
         features: HashMap<String, usize>,
-        barcodes: HashSet<Vec<u8>>,
+        /// Maps a barcode to a provisional index, assigned in insertion
+        /// order as new barcodes are seen (same scheme as `features`). This
+        /// is NOT the final column index written to `barcodes.tsv`/the
+        /// matrix - `finish` remaps it to a barcode's position in sorted
+        /// order, since the per-chunk temp files (written incrementally,
+        /// chunk by chunk) can't know the final sort order up front.
+        barcode_to_index: HashMap<Vec<u8>, u32>,
         matrix_temp_dir: PathBuf,
         entry_count: usize,
+        /// Whether `features.tsv`/`barcodes.tsv`/`matrix.mtx` are
+        /// gzip-compressed, 10x-style (`features.tsv.gz` etc). Ignored for
+        /// the `matrix_format: Binary` final matrix, whose random-access
+        /// index requires a seekable, uncompressed file; the per-chunk temp
+        /// files are still compressed when this is set.
+        compress_out: bool,
+        /// Encoding of the final feature x barcode matrix. See
+        /// `crate::config::MatrixFormat`.
+        matrix_format: crate::config::MatrixFormat,
     },
 }
 
+/// Opens `path` for writing, gzip-compressing the stream on the fly (and
+/// appending `.gz` to the filename) when `compress` is set. Used for the
+/// SingleCell matrix/features/barcodes output, which downstream tools (e.g.
+/// scanpy, Seurat) happily read either way.
+fn create_output_writer(path: &Path, compress: bool) -> Result<Box<dyn Write>> {
+    if compress {
+        let mut path = path.as_os_str().to_owned();
+        path.push(".gz");
+        let file = ex::fs::File::create(&path)?.into_inner();
+        Ok(Box::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        )))
+    } else {
+        Ok(Box::new(ex::fs::File::create(path)?.into_inner()))
+    }
+}
+
 impl Output {
     pub fn new_per_region(
         output_filename: PathBuf,
         first_column_only: bool,
         sorted_keys: Option<Vec<String>>,
         id_attribute: String,
+    ) -> Self {
+        Self::new_per_region_normalized(
+            output_filename,
+            first_column_only,
+            sorted_keys,
+            id_attribute,
+            None,
+            None,
+            crate::config::CountsFormat::Tsv,
+            false,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_per_region_normalized(
+        output_filename: PathBuf,
+        first_column_only: bool,
+        sorted_keys: Option<Vec<String>>,
+        id_attribute: String,
+        feature_lengths: Option<HashMap<String, u64>>,
+        normalization: Option<crate::config::NormalizationMode>,
+        counts_format: crate::config::CountsFormat,
+        em_rescue: bool,
     ) -> Self {
         let stat_counter = [
             ("ambiguous", 0),
@@ -324,6 +626,8 @@ impl Output {
             ("no_barcode", 0),
             ("no_umi", 0),
             ("barcode_not_in_whitelist", 0),
+            ("barcode_corrected", 0),
+            ("corrupt_records", 0),
             ("filtered", 0),
         ]
         .into_iter()
@@ -337,12 +641,19 @@ impl Output {
             sorted_keys,
             first_column_only,
             id_attribute,
+            feature_lengths,
+            normalization,
+            counts_format,
+            em_rescue,
+            ambiguous_classes: HashMap::new(),
         }
     }
 
     pub fn new_singlecell(
         output_prefix: PathBuf,
         sorted_keys: Option<Vec<String>>,
+        compress_out: bool,
+        matrix_format: crate::config::MatrixFormat,
     ) -> Result<Self> {
         let matrix_temp_dir = output_prefix.join("matrix.mtx.temp");
         if matrix_temp_dir.exists() {
@@ -368,6 +679,8 @@ impl Output {
             ("no_barcode", 0),
             ("no_umi", 0),
             ("barcode_not_in_whitelist", 0),
+            ("barcode_corrected", 0),
+            ("corrupt_records", 0),
             ("filtered", 0),
         ]
         .into_iter()
@@ -378,9 +691,11 @@ impl Output {
             output_prefix,
             stat_counter,
             features,
-            barcodes: HashSet::new(),
+            barcode_to_index: HashMap::new(),
             matrix_temp_dir,
             entry_count: 0,
+            compress_out,
+            matrix_format,
         })
     }
 
@@ -389,6 +704,7 @@ impl Output {
             Output::PerRegion { .. } => CounterPerChunk::PerRegion {
                 counter: HashMap::new(),
                 stat_counter: HashMap::new(),
+                ambiguous_classes: HashMap::new(),
             },
             Output::SingleCell { .. } => CounterPerChunk::SingleCell {
                 counter: HashMap::new(),
@@ -408,23 +724,32 @@ impl Output {
             Output::PerRegion {
                 counter,
                 stat_counter,
+                ambiguous_classes,
                 ..
             } => {
                 if let CounterPerChunk::PerRegion {
                     counter: incoming_counter,
                     stat_counter: incoming_stat_counter,
+                    ambiguous_classes: incoming_ambiguous_classes,
                 } = output_catcher
                 {
                     for (k, v) in incoming_counter.iter() {
                         let entry = counter
                             .entry(interner.resolve(*k).unwrap().to_string())
-                            .or_insert((0, 0));
+                            .or_insert((0.0, 0.0));
                         entry.0 += v.0;
                         entry.1 += v.1;
                     }
                     for (k, v) in incoming_stat_counter {
                         *stat_counter.entry(k.clone()).or_default() += v;
                     }
+                    for (k, v) in incoming_ambiguous_classes {
+                        let resolved: Vec<String> = k
+                            .iter()
+                            .map(|sym| interner.resolve(*sym).unwrap().to_string())
+                            .collect();
+                        *ambiguous_classes.entry(resolved).or_insert(0) += v;
+                    }
                 } else {
                     unreachable!();
                 }
@@ -432,9 +757,10 @@ impl Output {
             Output::SingleCell {
                 matrix_temp_dir,
                 features,
-                barcodes,
+                barcode_to_index,
                 stat_counter,
                 entry_count,
+                compress_out,
                 ..
             } => {
                 //we want a consistent output order
@@ -448,11 +774,17 @@ impl Output {
                         *stat_counter.entry(k.clone()).or_default() += v;
                     }
 
-                    let mut matrix_handle = BufWriter::new(
-                        ex::fs::File::create(matrix_temp_dir.join(chunk.str_id()))?.into_inner(),
-                    );
-
-                    //now these genes are fully measured, write them out
+                    let mut matrix_handle = BufWriter::new(create_output_writer(
+                        &matrix_temp_dir.join(chunk.str_id()),
+                        *compress_out,
+                    )?);
+
+                    //now these genes are fully measured, write them out as
+                    //fixed-width (u32 feature_idx, u32 barcode_idx, u32 value)
+                    //binary triples - both indices are interned here, at
+                    //write time, rather than re-encoding the feature/barcode
+                    //strings as UTF-8 on every entry (barcode_idx is
+                    //provisional; see the `barcode_to_index` doc comment).
                     for ((feature_ref, barcode), value) in incoming_counter.into_iter() {
                         let feature_str = interner.resolve(feature_ref).unwrap();
                         let features_len = features.len();
@@ -464,19 +796,20 @@ impl Output {
                                 new_index
                             }
                         };
-                        barcodes.insert(barcode.clone());
+                        let barcodes_len = barcode_to_index.len() as u32;
+                        let barcode_idx = match barcode_to_index.entry(barcode) {
+                            std::collections::hash_map::Entry::Occupied(e) => *e.get(),
+                            std::collections::hash_map::Entry::Vacant(e) => {
+                                e.insert(barcodes_len);
+                                barcodes_len
+                            }
+                        };
                         *entry_count += 1;
                         matrix_handle
-                            .write_all(
-                                format!(
-                                    "{} {} {}\n",
-                                    feature_idx + 1, // MatrixMarket is 1-based
-                                    std::str::from_utf8(&barcode).unwrap(),
-                                    value
-                                )
-                                .as_bytes(),
-                            )
-                            .context("Failed to write to matrix file")?;
+                            .write_all(&(feature_idx as u32).to_le_bytes())
+                            .and_then(|_| matrix_handle.write_all(&barcode_idx.to_le_bytes()))
+                            .and_then(|_| matrix_handle.write_all(&(value as u32).to_le_bytes()))
+                            .context("Failed to write triple to matrix temp file")?;
                     }
                 }
             }
@@ -484,64 +817,156 @@ impl Output {
         Ok(())
     }
 
-    fn finish(self, chunk_names: &[String]) -> Result<()> {
+    fn finish(self, chunk_names: &[String], fold_deferred_into_counts: bool) -> Result<()> {
         measure_time::info_time!("Preparing final output");
         match self {
             Output::PerRegion {
                 output_filename,
-                counter,
+                mut counter,
                 stat_counter,
                 first_column_only,
                 sorted_keys,
                 id_attribute,
+                feature_lengths,
+                normalization,
+                counts_format,
+                em_rescue,
+                ambiguous_classes,
             } => {
                 ex::fs::create_dir_all(output_filename.parent().unwrap())?;
+
+                // `MultiRegionHandling::Defer` holds ambiguous reads out of
+                // `counter` entirely instead of resolving them immediately in
+                // `apply_count_strategy` (see `ReadToGeneMatcher::defers_ambiguous`),
+                // so their weight needs folding back in here, now that every
+                // chunk's `ambiguous_classes` has been merged - unlike
+                // `write_em_rescue`, which only ever writes an informational
+                // sidecar without ever touching `counter`/`counts.tsv`.
+                if fold_deferred_into_counts && !ambiguous_classes.is_empty() {
+                    const TOLERANCE: f64 = 1e-6;
+                    const MAX_ITERATIONS: usize = 1000;
+                    let classes: Vec<crate::em::EquivalenceClass> = ambiguous_classes
+                        .iter()
+                        .map(|(genes, count)| crate::em::EquivalenceClass {
+                            genes: genes.clone(),
+                            count: *count,
+                        })
+                        .collect();
+                    let resolved = crate::em::resolve(&classes, TOLERANCE, MAX_ITERATIONS);
+                    for (gene, weight) in resolved {
+                        counter.entry(gene).or_insert((0.0, 0.0)).0 += weight;
+                    }
+                }
+
                 let sorted_keys = sorted_keys.unwrap_or_else(|| {
                     let mut keys: Vec<_> = counter.keys().map(|x| x.to_string()).collect();
                     keys.sort();
                     keys
                 });
 
+                let normalized = normalization.map(|mode| {
+                    normalize_counts(
+                        mode,
+                        &sorted_keys,
+                        &counter,
+                        feature_lengths.as_ref(),
+                        first_column_only,
+                    )
+                });
+
+                if !matches!(counts_format, crate::config::CountsFormat::Tsv) {
+                    return Self::write_per_region_typed(
+                        &output_filename,
+                        &counter,
+                        &stat_counter,
+                        &sorted_keys,
+                        first_column_only,
+                        &id_attribute,
+                        normalization,
+                        normalized.as_ref(),
+                        counts_format,
+                    );
+                }
+
                 let output_file = ex::fs::File::create(&output_filename)?;
                 let mut out_buffer = std::io::BufWriter::new(output_file);
+                let normalization_header = match normalization {
+                    Some(crate::config::NormalizationMode::Rpkm) => "\trpkm",
+                    Some(crate::config::NormalizationMode::Fpkm) => "\tfpkm",
+                    Some(crate::config::NormalizationMode::Tpm) => "\ttpm",
+                    None => "",
+                };
                 if first_column_only {
                     out_buffer
-                        .write_all(format!("{}\tcount\n", id_attribute).as_bytes())
+                        .write_all(
+                            format!("{}\tcount{}\n", id_attribute, normalization_header)
+                                .as_bytes(),
+                        )
                         .context("Failed to write header to output file")?;
-                    for key in sorted_keys {
-                        let count = counter.get(&key).unwrap_or(&(0, 0)).0;
-                        out_buffer
-                            .write_all(format!("{}\t{}\n", key, count).as_bytes())
-                            .context("Failed to write counts to output file")?;
+                    for (ii, key) in sorted_keys.iter().enumerate() {
+                        let count = counter.get(key).unwrap_or(&(0.0, 0.0)).0;
+                        match normalized.as_ref() {
+                            Some(values) => out_buffer
+                                .write_all(
+                                    format!("{}\t{}\t{}\n", key, count, values[ii]).as_bytes(),
+                                )
+                                .context("Failed to write counts to output file")?,
+                            None => out_buffer
+                                .write_all(format!("{}\t{}\n", key, count).as_bytes())
+                                .context("Failed to write counts to output file")?,
+                        }
                     }
                 } else {
                     out_buffer
                         .write_all(
-                            format!("{}\tcount_correct\tcount_reverse\n", id_attribute).as_bytes(),
+                            format!(
+                                "{}\tcount_correct\tcount_reverse{}\n",
+                                id_attribute, normalization_header
+                            )
+                            .as_bytes(),
                         )
                         .context("Failed to write header to output file")?;
 
-                    for key in sorted_keys {
-                        let (count_correct, count_reverse) = counter.get(&key).unwrap_or(&(0, 0));
-                        out_buffer
-                            .write_all(
-                                format!("{}\t{}\t{}\n", key, count_correct, count_reverse)
+                    for (ii, key) in sorted_keys.iter().enumerate() {
+                        let (count_correct, count_reverse) =
+                            counter.get(key).unwrap_or(&(0.0, 0.0));
+                        match normalized.as_ref() {
+                            Some(values) => out_buffer
+                                .write_all(
+                                    format!(
+                                        "{}\t{}\t{}\t{}\n",
+                                        key, count_correct, count_reverse, values[ii]
+                                    )
                                     .as_bytes(),
-                            )
-                            .context("Failed to write counts to output file")?;
+                                )
+                                .context("Failed to write counts to output file")?,
+                            None => out_buffer
+                                .write_all(
+                                    format!("{}\t{}\t{}\n", key, count_correct, count_reverse)
+                                        .as_bytes(),
+                                )
+                                .context("Failed to write counts to output file")?,
+                        }
                     }
                 }
 
                 Self::write_stats(&output_filename, &stat_counter)
                     .context("Failed to write stats file")?;
+
+                if em_rescue && !ambiguous_classes.is_empty() {
+                    Self::write_em_rescue(&output_filename, &ambiguous_classes)
+                        .context("Failed to write EM rescue file")?;
+                }
             }
             Output::SingleCell {
                 output_prefix,
                 features,
-                barcodes,
+                barcode_to_index,
                 matrix_temp_dir,
                 entry_count,
                 stat_counter,
+                compress_out,
+                matrix_format,
             } => {
                 let features_filename = output_prefix.join("features.tsv");
                 let feature_len = features.len();
@@ -554,90 +979,130 @@ impl Output {
                     temp
                 };
                 let mut feature_file =
-                    BufWriter::new(ex::fs::File::create(&features_filename)?.into_inner());
+                    BufWriter::new(create_output_writer(&features_filename, compress_out)?);
                 for (_, feature) in sorted_features {
+                    // 10x-style features.tsv: id + name columns. We only have
+                    // one identifier (the aggregation id), so it is repeated
+                    // in both columns, matching how featureCounts-style tools
+                    // emit this file when no separate display name exists.
                     feature_file
-                        .write_all(format!("{}\n", feature).as_bytes())
+                        .write_all(format!("{feature}\t{feature}\n").as_bytes())
                         .context("Failed to write features to file")?;
                 }
 
+                // The per-chunk temp files were written with a provisional,
+                // insertion-order barcode index (see `barcode_to_index`'s
+                // doc comment); remap it here to each barcode's position in
+                // alphabetically sorted order, which is both the order
+                // `barcodes.tsv` has always been written in and the index
+                // the final matrix has always used.
                 let barcodes_filename = output_prefix.join("barcodes.tsv");
-                let barcode_len = barcodes.len();
-                let (barcodes, barcode_to_index) = {
-                    let mut temp: Vec<_> = barcodes.into_iter().collect();
-                    temp.sort();
-                    let lookup: HashMap<Vec<u8>, usize> = temp
-                        .iter()
-                        .enumerate()
-                        .map(|(i, b)| (b.clone(), i + 1))
-                        .collect();
-
-                    (temp, lookup)
-                };
+                let barcode_len = barcode_to_index.len();
+                let mut sorted_barcodes: Vec<Vec<u8>> =
+                    barcode_to_index.keys().cloned().collect();
+                sorted_barcodes.sort();
+                let mut provisional_to_final = vec![0u32; barcode_len];
+                for (final_idx, barcode) in sorted_barcodes.iter().enumerate() {
+                    provisional_to_final[barcode_to_index[barcode] as usize] = final_idx as u32;
+                }
                 let mut barcode_file =
-                    BufWriter::new(ex::fs::File::create(&barcodes_filename)?.into_inner());
-                for barcode in barcodes {
+                    BufWriter::new(create_output_writer(&barcodes_filename, compress_out)?);
+                for barcode in &sorted_barcodes {
                     barcode_file
-                        .write_all(format!("{}\n", String::from_utf8_lossy(&barcode)).as_bytes())
+                        .write_all(format!("{}\n", String::from_utf8_lossy(barcode)).as_bytes())
                         .context("Failed to write barcodes to file")?;
                 }
-                //matrix file has a header with nrow, ncols, nentries, so we need to push it into a
-                //new file.
-                let matrix_filename = output_prefix.join("matrix.mtx");
-
-                let mut matrix_out = ex::fs::File::create(&matrix_filename)?;
-                matrix_out.write_all(
-                    "%%MatrixMarket matrix coordinate integer general\n%\n".as_bytes(),
-                )?;
-                matrix_out
-                    .write_all(
-                        format!("{} {} {}\n", feature_len, barcode_len, entry_count,).as_bytes(),
-                    )
-                    .context("Failed to write header to matrix file")?;
 
+                // Read back every per-chunk binary triple, remapping its
+                // provisional barcode index to the final one.
                 let sorted_chunk_names: Vec<_> = chunk_names.iter().sorted().collect();
-
-                for chunk_str_id in sorted_chunk_names {
+                let mut triples: Vec<crate::sparse_matrix::Triple> =
+                    Vec::with_capacity(entry_count);
+                for chunk_str_id in &sorted_chunk_names {
                     let temp_filename = matrix_temp_dir.join(chunk_str_id);
-                    let temp_handle = BufReader::new(
-                        ex::fs::File::open(&temp_filename)
+                    let mut temp_handle = BufReader::new(if compress_out {
+                        Box::new(flate2::read::GzDecoder::new(
+                            ex::fs::File::open({
+                                let mut p = temp_filename.as_os_str().to_owned();
+                                p.push(".gz");
+                                PathBuf::from(p)
+                            })
                             .context("Failed to open temporary matrix file")?
                             .into_inner(),
-                    );
-                    for line in temp_handle.lines() {
-                        let line =
-                            line.context("Failed to read line from temporary matrix file")?;
-                        let mut parts = line.split_whitespace();
-                        let feature_idx: usize = parts
-                            .next()
-                            .context("Missing feature index in line")?
-                            .parse()
-                            .context("Failed to parse feature index")?;
-                        let barcode = parts
-                            .next()
-                            .context("Missing barcode in line")?
-                            .as_bytes()
-                            .to_vec();
-                        let value: f64 = parts
-                            .next()
-                            .context("Missing value in line")?
-                            .parse()
-                            .context("Failed to parse value")?;
-
-                        if let Some(&barcode_index) = barcode_to_index.get(&barcode) {
-                            matrix_out.write_all(
-                                format!(
-                                    "{} {} {}\n",
-                                    feature_idx, // we already added +1
-                                    barcode_index,
-                                    value
-                                )
-                                .as_bytes(),
-                            )?;
+                        )) as Box<dyn std::io::Read>
+                    } else {
+                        Box::new(
+                            ex::fs::File::open(&temp_filename)
+                                .context("Failed to open temporary matrix file")?
+                                .into_inner(),
+                        ) as Box<dyn std::io::Read>
+                    });
+                    loop {
+                        let mut buf = [0u8; 12];
+                        match temp_handle.read_exact(&mut buf) {
+                            Ok(()) => {}
+                            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                            Err(e) => {
+                                return Err(e)
+                                    .context("Failed to read triple from temporary matrix file")
+                            }
                         }
+                        let feature_idx = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+                        let provisional_barcode_idx =
+                            u32::from_le_bytes(buf[4..8].try_into().unwrap());
+                        let value = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+                        triples.push(crate::sparse_matrix::Triple {
+                            row: feature_idx,
+                            col: provisional_to_final[provisional_barcode_idx as usize],
+                            value,
+                        });
                     }
                 }
 
+                let matrix_filename = match matrix_format {
+                    crate::config::MatrixFormat::MatrixMarket => {
+                        let matrix_filename = output_prefix.join("matrix.mtx");
+                        let mut matrix_out = create_output_writer(&matrix_filename, compress_out)?;
+                        matrix_out.write_all(
+                            "%%MatrixMarket matrix coordinate integer general\n%\n".as_bytes(),
+                        )?;
+                        matrix_out
+                            .write_all(
+                                format!("{} {} {}\n", feature_len, barcode_len, entry_count)
+                                    .as_bytes(),
+                            )
+                            .context("Failed to write header to matrix file")?;
+                        for t in &triples {
+                            matrix_out
+                                .write_all(
+                                    format!("{} {} {}\n", t.row + 1, t.col + 1, t.value)
+                                        .as_bytes(),
+                                )
+                                .context("Failed to write triple to matrix file")?;
+                        }
+                        matrix_filename
+                    }
+                    crate::config::MatrixFormat::Binary => {
+                        // The binary format's column index needs real
+                        // seeking to write, which an on-the-fly gzip stream
+                        // can't provide (and would also defeat the point of
+                        // its random-access index), so `compress_out` is
+                        // ignored here.
+                        let matrix_filename = output_prefix.join("matrix.bin");
+                        let mut matrix_out = ex::fs::File::create(&matrix_filename)
+                            .context("Failed to create binary matrix file")?
+                            .into_inner();
+                        crate::sparse_matrix::write_binary_matrix(
+                            &mut matrix_out,
+                            feature_len as u64,
+                            barcode_len as u64,
+                            triples,
+                        )
+                        .context("Failed to write binary matrix file")?;
+                        matrix_filename
+                    }
+                };
+
                 if matrix_temp_dir.exists() {
                     ex::fs::remove_dir_all(&matrix_temp_dir)
                         .context("Failed to remove temporary matrix directory")?;
@@ -655,6 +1120,81 @@ impl Output {
         Ok(())
     }
 
+    /// Same data as the `first_column_only`/dual-column TSV branches above,
+    /// written through `typed_format` instead: one record per feature, with
+    /// the id as text, counts as unsigned ints, and the optional
+    /// normalized value as a float-shaped text field (`typed_format` has no
+    /// dedicated float tag, and stringifying it keeps the value exact
+    /// rather than picking a lossy fixed precision).
+    #[allow(clippy::too_many_arguments)]
+    fn write_per_region_typed(
+        output_filename: &Path,
+        counter: &HashMap<String, (f64, f64)>,
+        stat_counter: &HashMap<String, usize>,
+        sorted_keys: &[String],
+        first_column_only: bool,
+        id_attribute: &str,
+        normalization: Option<crate::config::NormalizationMode>,
+        normalized: Option<&Vec<f64>>,
+        counts_format: crate::config::CountsFormat,
+    ) -> Result<()> {
+        let normalization_field = match normalization {
+            Some(crate::config::NormalizationMode::Rpkm) => "rpkm",
+            Some(crate::config::NormalizationMode::Fpkm) => "fpkm",
+            Some(crate::config::NormalizationMode::Tpm) => "tpm",
+            None => "",
+        };
+        // `typed_format` has no dedicated float tag (see `TypedValue`), so a
+        // whole count (the common case - `OverlapWeightMode::Full`, the
+        // default) is written as `UInt` same as before this column could
+        // ever be fractional; only a genuinely fractional count (only
+        // possible under `OverlapWeightMode::Fraction`) falls back to
+        // `Text`, mirroring the `normalized` column below.
+        fn count_value(count: f64) -> crate::typed_format::TypedValue {
+            if count.fract() == 0.0 {
+                crate::typed_format::TypedValue::UInt(count as u64)
+            } else {
+                crate::typed_format::TypedValue::Text(count.to_string())
+            }
+        }
+        let mut records = Vec::with_capacity(sorted_keys.len());
+        for (ii, key) in sorted_keys.iter().enumerate() {
+            let (count_correct, count_reverse) = *counter.get(key).unwrap_or(&(0.0, 0.0));
+            let mut record: crate::typed_format::Record = vec![(
+                id_attribute.to_string(),
+                crate::typed_format::TypedValue::Text(key.clone()),
+            )];
+            if first_column_only {
+                record.push(("count".to_string(), count_value(count_correct)));
+            } else {
+                record.push(("count_correct".to_string(), count_value(count_correct)));
+                record.push(("count_reverse".to_string(), count_value(count_reverse)));
+            }
+            if let Some(values) = normalized {
+                record.push((
+                    normalization_field.to_string(),
+                    crate::typed_format::TypedValue::Text(values[ii].to_string()),
+                ));
+            }
+            records.push(record);
+        }
+
+        let output_file = ex::fs::File::create(output_filename)?;
+        let mut out_buffer = std::io::BufWriter::new(output_file);
+        match counts_format {
+            crate::config::CountsFormat::TypedText => {
+                crate::typed_format::write_text(&mut out_buffer, &records)
+            }
+            crate::config::CountsFormat::TypedBinary => {
+                crate::typed_format::write_binary(&mut out_buffer, &records)
+            }
+            crate::config::CountsFormat::Tsv => unreachable!(),
+        }
+        .context("Failed to write typed counts output")?;
+
+        Self::write_stats(output_filename, stat_counter).context("Failed to write stats file")
+    }
+
     fn write_stats(output_filename: &Path, stat_counter: &HashMap<String, usize>) -> Result<()> {
         let stat_filename = output_filename.with_file_name(format!(
             "{}.stats.tsv",
@@ -673,6 +1213,125 @@ impl Output {
         }
         Ok(())
     }
+
+    /// Resolves `ambiguous_classes` (multi-gene equivalence classes, keyed
+    /// by the sorted set of genes a read's blocks resolved to) via
+    /// `em::resolve` and writes the fractional per-gene abundances to
+    /// `<output_filename>.em_rescue.tsv`. See `Output::PerRegion::em_rescue`.
+    ///
+    /// This is purely additive: `counter` (and therefore `counts.tsv`) is
+    /// never touched by this sidecar, so turning `em_rescue` on never
+    /// changes a user's existing counts, regardless of `multi_region`.
+    /// Actually redistributing a multi-mapper's weight into the reported
+    /// counts themselves is `MultiRegionHandling::Defer` (see
+    /// `ReadToGeneMatcher::defers_ambiguous`/`Output::finish`), a separate,
+    /// orthogonal toggle from this one - it shares `ambiguous_classes` and
+    /// `em::resolve`, but folds the estimate into `counter` instead of (or
+    /// alongside) writing it here.
+    fn write_em_rescue(
+        output_filename: &Path,
+        ambiguous_classes: &HashMap<Vec<String>, usize>,
+    ) -> Result<()> {
+        const TOLERANCE: f64 = 1e-6;
+        const MAX_ITERATIONS: usize = 1000;
+
+        let classes: Vec<crate::em::EquivalenceClass> = ambiguous_classes
+            .iter()
+            .map(|(genes, count)| crate::em::EquivalenceClass {
+                genes: genes.clone(),
+                count: *count,
+            })
+            .collect();
+        let resolved = crate::em::resolve(&classes, TOLERANCE, MAX_ITERATIONS);
+
+        let em_filename = output_filename.with_file_name(format!(
+            "{}.em_rescue.tsv",
+            output_filename.file_name().unwrap().to_string_lossy()
+        ));
+        let output_file = ex::fs::File::create(&em_filename)?;
+        let mut out_buffer = std::io::BufWriter::new(output_file);
+        out_buffer
+            .write_all(b"gene\tem_count\n")
+            .context("Failed to write header to EM rescue file")?;
+        let sorted_keys = resolved.keys().sorted();
+        for key in sorted_keys {
+            out_buffer
+                .write_all(format!("{}\t{}\n", key, resolved.get(key).unwrap()).as_bytes())
+                .context("Failed to write EM rescue counts to file")?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes one length-normalized value per entry in `sorted_keys`, in the
+/// same order, for `NormalizationMode`. `N` (total mapped reads/fragments)
+/// is the sum of whichever count column is being normalized - `count` for
+/// `first_column_only`, `count_correct + count_reverse` otherwise - across
+/// every feature, matching how featureCounts/htseq report library size.
+/// Features with a missing or zero length, or with `N == 0`, get `0.0`
+/// rather than dividing by zero.
+fn normalize_counts(
+    mode: crate::config::NormalizationMode,
+    sorted_keys: &[String],
+    counter: &HashMap<String, (f64, f64)>,
+    feature_lengths: Option<&HashMap<String, u64>>,
+    first_column_only: bool,
+) -> Vec<f64> {
+    let raw_counts: Vec<f64> = sorted_keys
+        .iter()
+        .map(|key| {
+            let (correct, reverse) = counter.get(key).copied().unwrap_or((0.0, 0.0));
+            if first_column_only {
+                correct
+            } else {
+                correct + reverse
+            }
+        })
+        .collect();
+    let total: f64 = raw_counts.iter().sum();
+    let lengths: Vec<f64> = sorted_keys
+        .iter()
+        .map(|key| {
+            feature_lengths
+                .and_then(|lengths| lengths.get(key))
+                .copied()
+                .unwrap_or(0) as f64
+        })
+        .collect();
+
+    match mode {
+        crate::config::NormalizationMode::Rpkm | crate::config::NormalizationMode::Fpkm => {
+            raw_counts
+                .iter()
+                .zip(lengths.iter())
+                .map(|(&count, &length)| {
+                    if length <= 0.0 || total <= 0.0 {
+                        0.0
+                    } else {
+                        count * 1e9 / (length * total)
+                    }
+                })
+                .collect()
+        }
+        crate::config::NormalizationMode::Tpm => {
+            let rates: Vec<f64> = raw_counts
+                .iter()
+                .zip(lengths.iter())
+                .map(|(&count, &length)| if length <= 0.0 { 0.0 } else { count / length })
+                .collect();
+            let rate_sum: f64 = rates.iter().sum();
+            rates
+                .into_iter()
+                .map(|rate| {
+                    if rate_sum <= 0.0 {
+                        0.0
+                    } else {
+                        rate * 1e6 / rate_sum
+                    }
+                })
+                .collect()
+        }
+    }
 }
 
 struct PerPosition {
@@ -681,11 +1340,145 @@ struct PerPosition {
     dedup_storage: crate::deduplication::DedupPerBucket,
 }
 
+/// For `DeduplicationMode::Directional`/`Cluster`/`Adjacency` buckets,
+/// demotes the reads that lost out in the UMI network merge (see
+/// `DedupPerBucket::network_losers`) from `Counted` back to `Duplicate`, now
+/// that every read for this position/bucket has been seen.
+fn apply_directional_losers(block: &mut PerPosition) {
+    let losers = block.dedup_storage.network_losers();
+    if losers.is_empty() {
+        return;
+    }
+    // `this_index` (as handed to `DedupPerBucket::accept_read`) is the position
+    // within whichever of reads_forward/reads_reverse was being filled at the
+    // time, matching the convention `AcceptReadResult::DuplicateButPrefered`
+    // already relies on elsewhere in this module.
+    for reads in [&mut block.reads_forward, &mut block.reads_reverse] {
+        for (index, (read, _org_index)) in reads.iter_mut().enumerate() {
+            if losers.contains(&index) {
+                *read = AnnotatedRead::Duplicate;
+            }
+        }
+    }
+}
+
 struct OutputBamInfo {
     output_bam_path: PathBuf,
     header: rust_htslib::bam::Header,
 }
 
+/// Per-chunk gzipped FASTQ sinks for reads rejected by `write_annotated_reads`,
+/// one per `AnnotatedRead` rejection variant. Filenames follow
+/// `{chunk.str_id()}.{category}.fastq.gz`, one file per chunk per category;
+/// `combine_temporary_fastqs` concatenates these (valid, since gzip streams
+/// concatenate) into the final `{category}.fastq.gz` files.
+struct RejectedFastqWriters {
+    filtered: bio::io::fastq::Writer<Box<dyn Write>>,
+    no_barcode: bio::io::fastq::Writer<Box<dyn Write>>,
+    no_umi: bio::io::fastq::Writer<Box<dyn Write>>,
+    barcode_not_in_whitelist: bio::io::fastq::Writer<Box<dyn Write>>,
+}
+
+impl RejectedFastqWriters {
+    /// Rejection categories, also used as the filename stems for both the
+    /// per-chunk temporary files and the final merged files.
+    const CATEGORIES: [&'static str; 4] =
+        ["filtered", "no_barcode", "no_umi", "barcode_not_in_whitelist"];
+
+    fn new(out_bam_path: &Path, chunk: &Chunk) -> Result<Self> {
+        Ok(RejectedFastqWriters {
+            filtered: Self::open(out_bam_path, chunk, "filtered")?,
+            no_barcode: Self::open(out_bam_path, chunk, "no_barcode")?,
+            no_umi: Self::open(out_bam_path, chunk, "no_umi")?,
+            barcode_not_in_whitelist: Self::open(out_bam_path, chunk, "barcode_not_in_whitelist")?,
+        })
+    }
+
+    fn open(
+        out_bam_path: &Path,
+        chunk: &Chunk,
+        category: &str,
+    ) -> Result<bio::io::fastq::Writer<Box<dyn Write>>> {
+        let path = out_bam_path.join(format!("{}.{category}.fastq", chunk.str_id()));
+        let writer = create_output_writer(&path, true)
+            .with_context(|| format!("Failed to create rejected-read FASTQ {:?}", path))?;
+        Ok(bio::io::fastq::Writer::new(writer))
+    }
+
+    fn write_record(
+        writer: &mut bio::io::fastq::Writer<Box<dyn Write>>,
+        read: &rust_htslib::bam::Record,
+        desc: Option<&str>,
+    ) -> Result<()> {
+        let name = std::str::from_utf8(read.qname()).context("read name wasn't utf8")?;
+        let qual: Vec<u8> = read.qual().iter().map(|q| q + 33).collect();
+        writer
+            .write(name, desc, &read.seq().as_bytes(), &qual)
+            .context("Failed to write rejected read to FASTQ")?;
+        Ok(())
+    }
+
+    fn write_filtered(&mut self, read: &rust_htslib::bam::Record) -> Result<()> {
+        Self::write_record(&mut self.filtered, read, None)
+    }
+
+    fn write_no_barcode(&mut self, read: &rust_htslib::bam::Record) -> Result<()> {
+        Self::write_record(&mut self.no_barcode, read, None)
+    }
+
+    fn write_no_umi(&mut self, read: &rust_htslib::bam::Record) -> Result<()> {
+        Self::write_record(&mut self.no_umi, read, None)
+    }
+
+    fn write_barcode_not_in_whitelist(
+        &mut self,
+        read: &rust_htslib::bam::Record,
+        desc: &str,
+    ) -> Result<()> {
+        Self::write_record(&mut self.barcode_not_in_whitelist, read, Some(desc))
+    }
+}
+
+/// Per-chunk gzipped FASTQ sinks for `write_dedup_fastq`'s surviving
+/// (non-duplicate) `AnnotatedRead::Counted` reads: `r1` for single-end reads
+/// and first-in-pair mates, `r2` for second-in-pair mates. Combined into
+/// `dedup_1.fastq.gz`/`dedup_2.fastq.gz` the same way `RejectedFastqWriters`'
+/// per-chunk files are combined.
+struct DedupFastqWriters {
+    r1: bio::io::fastq::Writer<Box<dyn Write>>,
+    r2: bio::io::fastq::Writer<Box<dyn Write>>,
+}
+
+impl DedupFastqWriters {
+    const CATEGORIES: [&'static str; 2] = ["dedup_1", "dedup_2"];
+
+    fn new(out_dir: &Path, chunk: &Chunk) -> Result<Self> {
+        Ok(DedupFastqWriters {
+            r1: Self::open(out_dir, chunk, Self::CATEGORIES[0])?,
+            r2: Self::open(out_dir, chunk, Self::CATEGORIES[1])?,
+        })
+    }
+
+    fn open(
+        out_dir: &Path,
+        chunk: &Chunk,
+        category: &str,
+    ) -> Result<bio::io::fastq::Writer<Box<dyn Write>>> {
+        let path = out_dir.join(format!("{}.{category}.fastq", chunk.str_id()));
+        let writer = create_output_writer(&path, true)
+            .with_context(|| format!("Failed to create deduplicated-read FASTQ {:?}", path))?;
+        Ok(bio::io::fastq::Writer::new(writer))
+    }
+
+    fn writer_for(&mut self, read: &rust_htslib::bam::Record) -> &mut bio::io::fastq::Writer<Box<dyn Write>> {
+        if read.is_paired() && !read.is_first_in_template() {
+            &mut self.r2
+        } else {
+            &mut self.r1
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 enum Bucket {
     PerPosition,
@@ -714,6 +1507,9 @@ impl Engine {
         cell_barcode: Option<crate::barcodes::CellBarcodes>,
         count_strategy: crate::config::Strategy,
         output: Output,
+        chunk_size: u32,
+        target_reads_per_chunk: Option<u32>,
+        tolerate_corrupt: bool,
     ) -> Result<Self> {
         let feature_entries =  gtf_entries
                 .remove(entry_kind)
@@ -744,6 +1540,13 @@ impl Engine {
                 reference_to_count_trees: feature_trees,
                 reference_to_aggregation_trees: split_trees,
                 count_strategy,
+                chunk_size,
+                target_reads_per_chunk,
+                chunk_error_policy: if tolerate_corrupt {
+                    chunked_genome::ErrorPolicy::SkipAndLog
+                } else {
+                    chunked_genome::ErrorPolicy::Abort
+                },
             }),
             filters,
             dedup_strategy,
@@ -778,6 +1581,38 @@ impl Engine {
         })
     }
 
+    pub fn from_bins(
+        bin_width: u32,
+        filters: Vec<crate::filters::Filter>,
+        dedup_strategy: DeduplicationStrategy,
+        umi_extractor: Option<crate::extractors::UMIExtraction>,
+        cell_barcode: Option<crate::barcodes::CellBarcodes>,
+        count_strategy: crate::config::Strategy,
+        output: Output,
+        chunk_size: u32,
+        target_reads_per_chunk: Option<u32>,
+        tolerate_corrupt: bool,
+    ) -> Self {
+        Engine {
+            matcher: ReadToGeneMatcher::BinMatcher(BinMatcher {
+                bin_width,
+                direction: count_strategy.direction,
+                chunk_size,
+                target_reads_per_chunk,
+                chunk_error_policy: if tolerate_corrupt {
+                    chunked_genome::ErrorPolicy::SkipAndLog
+                } else {
+                    chunked_genome::ErrorPolicy::Abort
+                },
+            }),
+            filters,
+            dedup_strategy,
+            umi_extractor,
+            cell_barcode,
+            output: Arc::new(Mutex::new(output)),
+        }
+    }
+
     pub fn from_bam_tag(
         tag: [u8; 2],
         filters: Vec<crate::filters::Filter>,
@@ -804,6 +1639,7 @@ impl Engine {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn quantify_bam(
         mut self,
         bam_path: impl AsRef<Path>,
@@ -812,12 +1648,52 @@ impl Engine {
         write_output_bam: bool,
         max_skip_len: u32,
         correct_reads_for_clipping: bool,
+        reference_fasta: Option<&Path>,
+        regions: &[String],
+        tolerate_corrupt: bool,
+        max_chunk_size: u32,
+        annotated_bam_format: crate::config::AnnotatedBamFormat,
+        write_rejected_fastq: bool,
+        write_dedup_fastq: bool,
+        prefetch_depth: u32,
     ) -> Result<()> {
+        let annotated_bam_format = match annotated_bam_format {
+            crate::config::AnnotatedBamFormat::Bam => rust_htslib::bam::Format::Bam,
+            crate::config::AnnotatedBamFormat::Cram => rust_htslib::bam::Format::Cram,
+        };
+        let regions: Vec<chunked_genome::RegionQuery> = regions
+            .iter()
+            .map(|r| chunked_genome::RegionQuery::parse(r))
+            .collect::<Result<_>>()
+            .context("Failed to parse input.regions")?;
         //check whether the bam file can be openend
         //and we need it for the chunking
         let bam_filename = bam_path.as_ref();
+        if let ReadToGeneMatcher::TreeMatcher(tm) = &mut self.matcher {
+            if tm.count_strategy.auto_detect_strandedness {
+                let bam_path_str = bam_filename
+                    .to_str()
+                    .context("BAM path wasn't valid utf8")?;
+                tm.count_strategy.direction = detect_strandedness(
+                    bam_path_str,
+                    &tm.reference_to_count_trees,
+                    tm.count_strategy.auto_detect_sample_size,
+                )
+                .context("Failed to auto-detect library strandedness")?;
+            }
+        }
         let index_filename: Option<&Path> = index_path;
-        let bam = crate::io::open_indexed_bam(bam_filename, index_filename.as_ref())?;
+        if index_filename.is_none() && !has_sidecar_index(bam_filename) {
+            bail!(
+                "No .bai/.csi/.crai index found next to {}. Quantification fetches per-region \
+                 chunks via an IndexedReader and runs them in parallel, so an index is required \
+                 (e.g. `samtools index {}`).",
+                bam_filename.display(),
+                bam_filename.display()
+            );
+        }
+        let bam =
+            crate::io::open_indexed_bam(bam_filename, index_filename.as_ref(), reference_fasta)?;
 
         let bam_header = bam.header();
         for filter in self.filters.iter_mut() {
@@ -849,6 +1725,23 @@ impl Engine {
 
             false => None,
         };
+        // Per-chunk temporary dedup-FASTQ files live in their own directory,
+        // independent of `output_bam_info`, so `write_dedup_fastq` works
+        // whether or not `write_annotated_bam` is also set.
+        let dedup_fastq_dir = if write_dedup_fastq {
+            let dir = output_prefix.join("dedup_fastq.temp");
+            if dir.exists() {
+                ex::fs::remove_dir_all(&dir).with_context(|| {
+                    format!("Failed to remove existing directory: {}", dir.display())
+                })?;
+            }
+            std::fs::create_dir_all(&dir).with_context(|| {
+                format!("Failed to create dedup FASTQ output directory: {}", dir.display())
+            })?;
+            Some(dir)
+        } else {
+            None
+        };
         let chunks = {
             let mut chunks = self.matcher.generate_chunks(bam)?;
             if chunks.is_empty() {
@@ -866,6 +1759,20 @@ impl Engine {
                     }
                 }
             }
+            chunked_genome::restrict_chunks_to_regions(chunks, &regions)
+        };
+        if chunks.is_empty() {
+            bail!("No chunks left after applying input.regions. Check that the region coordinates overlap the BAM's references.");
+        }
+        // `PerReference` dedup relies on one chunk per reference to collapse
+        // duplicates correctly, so oversized-chunk splitting only applies to
+        // the (default) `PerPosition` bucket mode.
+        let chunks = if matches!(
+            self.dedup_strategy.bucket,
+            crate::deduplication::DeduplicationBucket::PerPosition
+        ) {
+            chunked_genome::subdivide_oversized_chunks(chunks, max_chunk_size)
+        } else {
             chunks
         };
 
@@ -880,13 +1787,28 @@ impl Engine {
         } else {
             0u32
         };
-        let aggregated = pool.install(|| {
+        let aggregated = std::thread::scope(|scope| {
+            let warmer = if prefetch_depth > 0 {
+                Some(chunked_genome::spawn_prefetch_warmer(
+                    scope,
+                    || crate::io::open_indexed_bam(bam_filename, index_filename, reference_fasta),
+                    chunks.clone(),
+                    prefetch_depth,
+                ))
+            } else {
+                None
+            };
+            pool.install(|| {
             let result: Vec<Result<()>> = chunks
                 .into_par_iter()
                 .map(|chunk| -> Result<()> {
                     {
                         let mut bam =
-                            crate::io::open_indexed_bam(bam_filename, index_filename).unwrap();
+                            crate::io::open_indexed_bam(bam_filename, index_filename, reference_fasta)
+                                .unwrap();
+                        if let Some(warmer) = warmer.as_ref() {
+                            warmer.release_one();
+                        }
                         // Within one chunk, the reads we see will fit the same genes,
                         // over and over,
                         // so interning the strings is a good memory saving measure.
@@ -899,7 +1821,8 @@ impl Engine {
                             .per_chunk();
 
                         let mut idx_to_annotation_decision =
-                            output_bam_info.as_ref().map(|_| HashMap::new());
+                            (output_bam_info.is_some() || dedup_fastq_dir.is_some())
+                                .then(HashMap::new);
 
                         let mut current_pos = 0i32;
 
@@ -915,6 +1838,7 @@ impl Engine {
                         let mut read = bam::Record::new();
                         let mut orig_index = 0;
                         let mut debug_processed_ids: HashSet<i32> = HashSet::new();
+                        let mut corrupt_records = 0usize;
 
                         let bucket_mode = match self.dedup_strategy.bucket {
                             crate::deduplication::DeduplicationBucket::PerPosition => {
@@ -935,16 +1859,19 @@ impl Engine {
                             current_pos,
                             &mut read_catcher,
                             bucket_mode,
+                            tolerate_corrupt,
+                            &mut corrupt_records,
                         )? {
                             let remaining_positions =
                                 read_catcher.split_off(&(current_pos - max_skip_len as i32));
                             let done_positions = read_catcher;
                             read_catcher = remaining_positions;
-                            for (done_pos, block) in done_positions.into_iter() {
+                            for (done_pos, mut block) in done_positions.into_iter() {
                                 if debug_processed_ids.contains(&done_pos) {
                                     panic!("We are processing the same position twice.Bug");
                                 }
                                 debug_processed_ids.insert(done_pos);
+                                apply_directional_losers(&mut block);
                                 self.capture_read_block(
                                     block,
                                     &mut output_catcher,
@@ -954,10 +1881,11 @@ impl Engine {
                             current_pos = next_pos;
                         }
                         //capture eventual remaining blocks
-                        for (done_pos, block) in read_catcher.into_iter() {
+                        for (done_pos, mut block) in read_catcher.into_iter() {
                             if debug_processed_ids.contains(&done_pos) {
                                 panic!("We are processing the same position twice.Bug");
                             }
+                            apply_directional_losers(&mut block);
                             for read in &block.reads_forward {
                                 if let AnnotatedRead::Counted(info) = &read.0 {
                                     assert_eq!(info.corrected_position, done_pos);
@@ -977,6 +1905,10 @@ impl Engine {
                             )?;
                         }
 
+                        if corrupt_records > 0 {
+                            output_catcher.record_corrupt(corrupt_records);
+                        }
+
                         self.output
                             .lock()
                             .expect("Another thread panicked, output no longer available.")
@@ -991,19 +1923,35 @@ impl Engine {
                             Self::write_annotated_reads(
                                 &mut bam,
                                 &chunk,
-                                idx_to_annotation_decision.take().unwrap(),
+                                idx_to_annotation_decision.as_ref().unwrap(),
                                 output_bam_path,
                                 header,
                                 max_skip_len,
                                 &interner,
+                                annotated_bam_format,
+                                reference_fasta,
+                                write_rejected_fastq,
                             )
                             .context("Failed to write output bam")?;
                         }
+
+                        if let Some(dedup_fastq_dir) = dedup_fastq_dir.as_ref() {
+                            Self::write_dedup_fastq_reads(
+                                &mut bam,
+                                &chunk,
+                                idx_to_annotation_decision.as_ref().unwrap(),
+                                dedup_fastq_dir,
+                                max_skip_len,
+                                &interner,
+                            )
+                            .context("Failed to write deduplicated-read FASTQ")?;
+                        }
                     }
                     Ok(())
                 })
                 .collect();
             result
+            })
         });
 
         if aggregated.iter().any(|r| r.is_err()) {
@@ -1011,24 +1959,55 @@ impl Engine {
             bail!("Errors occurred during quantification: {:?}", errors);
         }
 
+        let fold_deferred_into_counts = self.matcher.defers_ambiguous();
         let output = Arc::into_inner(self.output).context("Failed to retrieve output from arc")?;
         let output = output
             .into_inner()
             .context("Failed to unlock output mutex")?;
-        output.finish(&chunk_names)?;
+        output.finish(&chunk_names, fold_deferred_into_counts)?;
 
         if let Some(OutputBamInfo {
             output_bam_path,
             header,
         }) = output_bam_info
         {
-            combine_temporary_bams(&chunk_names, output_bam_path, output_prefix, header)?;
+            if write_rejected_fastq {
+                combine_temporary_fastqs(
+                    &RejectedFastqWriters::CATEGORIES,
+                    &chunk_names,
+                    &output_bam_path,
+                    output_prefix,
+                )?;
+            }
+            combine_temporary_bams(
+                &chunk_names,
+                output_bam_path,
+                output_prefix,
+                header,
+                annotated_bam_format,
+                reference_fasta,
+            )?;
             /* println!(
                 "Output written to: {}",
                 output_prefix.join("annotated.bam").display()
             ); */
         }
 
+        if let Some(dedup_fastq_dir) = dedup_fastq_dir {
+            combine_temporary_fastqs(
+                &DedupFastqWriters::CATEGORIES,
+                &chunk_names,
+                &dedup_fastq_dir,
+                output_prefix,
+            )?;
+            ex::fs::remove_dir_all(&dedup_fastq_dir).with_context(|| {
+                format!(
+                    "Failed to remove temporary dedup FASTQ directory: {}",
+                    dedup_fastq_dir.display()
+                )
+            })?;
+        }
+
         Ok(())
     }
 
@@ -1040,7 +2019,11 @@ impl Engine {
     ) -> Result<()> {
         for block in [read_block.reads_forward, read_block.reads_reverse] {
             output_cacher
-                .count_reads(&block)
+                .count_reads(
+                    &block,
+                    self.matcher.overlap_weight(),
+                    self.matcher.defers_ambiguous(),
+                )
                 .context("Failed to count reads in read block")?;
             if let Some(idx_to_annotated) = idx_to_annotated.as_mut() {
                 for (read, org_index) in block {
@@ -1117,6 +2100,8 @@ impl Engine {
         current_pos: i32,
         read_catcher: &mut BTreeMap<i32, PerPosition>,
         bucket_mode: Bucket,
+        tolerate_corrupt: bool,
+        corrupt_records: &mut usize,
     ) -> Result<Option<i32>> {
         let mut last_read_pos: Option<i32> = None;
         'outer: loop {
@@ -1130,6 +2115,10 @@ impl Engine {
             match bam.read(read) {
                 Some(Ok(result)) => result,
                 Some(Err(e)) => {
+                    if tolerate_corrupt {
+                        *corrupt_records += 1;
+                        continue 'outer;
+                    }
                     bail!(e);
                 }
                 None => return Ok(None),
@@ -1139,7 +2128,7 @@ impl Engine {
                 Bucket::PerPosition => {
                     if max_skip_len > 0 {
                         let rp = read
-                            .corrected_pos(max_skip_len)
+                            .corrected_pos(max_skip_len)?
                             .expect("unaligned read found?");
                         (rp, rp)
                     } else {
@@ -1195,16 +2184,19 @@ impl Engine {
                 }
             }
 
-            let barcode = {
+            let (barcode, barcode_was_corrected) = {
                 match self.cell_barcode.as_ref() {
                     Some(cb) => {
                         let bc = cb.extract(read).context("barcode extraction failed")?; // an error
                         match bc {
                             Some(uncorrected) => {
                                 // if we have a barcode, correct it
-                                let corrected_barcode = cb.correct(&uncorrected);
+                                let corrected_barcode = cb.correct_read(&uncorrected, read);
                                 match corrected_barcode {
-                                    Some(bc) => Some(bc),
+                                    Some(bc) => {
+                                        let was_corrected = bc != uncorrected;
+                                        (Some(bc), was_corrected)
+                                    }
                                     None => {
                                         res.push((
                                             AnnotatedRead::BarcodeNotInWhitelist(
@@ -1224,7 +2216,7 @@ impl Engine {
                             }
                         }
                     }
-                    None => None,
+                    None => (None, false),
                 }
             };
             let umi: Option<Vec<u8>> = {
@@ -1262,6 +2254,7 @@ impl Engine {
                         },
                         umi,
                         barcode,
+                        barcode_was_corrected,
                         mapping_priority: (
                             read.no_of_alignments().try_into().unwrap_or(255),
                             read.mapq(),
@@ -1283,6 +2276,7 @@ impl Engine {
                         },
                         umi,
                         barcode,
+                        barcode_was_corrected,
                         mapping_priority: (
                             read.no_of_alignments().try_into().unwrap_or(255),
                             read.mapq(),
@@ -1298,17 +2292,32 @@ impl Engine {
     fn write_annotated_reads(
         bam: &mut rust_htslib::bam::IndexedReader,
         chunk: &Chunk,
-        mut idx_to_annotated: HashMap<usize, AnnotatedRead>,
+        idx_to_annotated: &HashMap<usize, AnnotatedRead>,
         out_bam_path: &Path,
         header: &rust_htslib::bam::Header,
         max_skip_len: u32,
         interner: &OurInterner,
+        format: rust_htslib::bam::Format,
+        reference_fasta: Option<&Path>,
+        write_rejected_fastq: bool,
     ) -> Result<()> {
         let mut out_bam = rust_htslib::bam::Writer::from_path(
-            out_bam_path.join(format!("{}.bam", chunk.str_id())),
+            out_bam_path.join(format!("{}.{}", chunk.str_id(), temp_extension(format))),
             header,
-            rust_htslib::bam::Format::Bam,
+            format,
         )?;
+        if format == rust_htslib::bam::Format::Cram {
+            let reference_fasta = reference_fasta
+                .context("CRAM output requires input.reference_fasta to be set")?;
+            out_bam
+                .set_reference(reference_fasta)
+                .context("Failed to set CRAM reference on annotated output writer")?;
+        }
+        let mut rejected = if write_rejected_fastq {
+            Some(RejectedFastqWriters::new(out_bam_path, chunk)?)
+        } else {
+            None
+        };
         /* let idx_to_annotated: HashMap<usize, &AnnotatedRead> = annotated_reads
         .iter()
         .map(|(read, org_index)| (*org_index, read))
@@ -1325,11 +2334,14 @@ impl Engine {
         let mut ii = 0;
         while let Some(bam_result) = bam.read(&mut read) {
             bam_result?;
-            if let Some(anno_read) = idx_to_annotated.get_mut(&ii) {
+            if let Some(anno_read) = idx_to_annotated.get(&ii) {
                 match anno_read {
                     AnnotatedRead::NotInRegion => continue,
                     AnnotatedRead::Filtered => {
                         read.replace_aux(b"XF", rust_htslib::bam::record::Aux::U8(1))?;
+                        if let Some(rejected) = rejected.as_mut() {
+                            rejected.write_filtered(&read)?;
+                        }
                     }
                     /* AnnotatedRead::FilteredInQuant => {
                         read.replace_aux(b"XF", rust_htslib::bam::record::Aux::U8(2))?;
@@ -1345,13 +2357,26 @@ impl Engine {
                                 std::str::from_utf8(uncorrected_barcode).unwrap_or("non-utf8"),
                             ),
                         )?;
+                        if let Some(rejected) = rejected.as_mut() {
+                            let desc = format!(
+                                "CR:Z:{}",
+                                std::str::from_utf8(uncorrected_barcode).unwrap_or("non-utf8")
+                            );
+                            rejected.write_barcode_not_in_whitelist(&read, &desc)?;
+                        }
                     }
                     AnnotatedRead::NoBarcode => {
                         read.replace_aux(b"XF", rust_htslib::bam::record::Aux::U8(5))?;
+                        if let Some(rejected) = rejected.as_mut() {
+                            rejected.write_no_barcode(&read)?;
+                        }
                     }
 
                     AnnotatedRead::NoUMI => {
                         read.replace_aux(b"XF", rust_htslib::bam::record::Aux::U8(6))?;
+                        if let Some(rejected) = rejected.as_mut() {
+                            rejected.write_no_umi(&read)?;
+                        }
                     }
                     AnnotatedRead::Counted(info) => {
                         //we have a read that was annotated
@@ -1387,6 +2412,15 @@ impl Engine {
                             //convert back into sam's 1 based coordinates.
                             rust_htslib::bam::record::Aux::I32(info.corrected_position + 1i32),
                         )?;
+                        // symmetric counterpart to XP: the clip-corrected
+                        // alignment end, so reads near a contig boundary can
+                        // be related consistently regardless of strand.
+                        if let Some(corrected_end) = read.corrected_end(max_skip_len)? {
+                            read.replace_aux(
+                                b"XE",
+                                rust_htslib::bam::record::Aux::I32(corrected_end + 1i32),
+                            )?;
+                        }
 
                         if let Some(cell_barcode) = info.barcode.as_ref() {
                             read.replace_aux(
@@ -1405,6 +2439,118 @@ impl Engine {
         }
         Ok(())
     }
+
+    /// Re-fetches `chunk` and, for every surviving (non-duplicate)
+    /// `AnnotatedRead::Counted` read, writes it to `DedupFastqWriters` as a
+    /// FASTQ record - sequence/qualities reverse-complemented when the
+    /// alignment was reverse, with the extracted UMI and assigned feature id
+    /// appended to the description line.
+    fn write_dedup_fastq_reads(
+        bam: &mut rust_htslib::bam::IndexedReader,
+        chunk: &Chunk,
+        idx_to_annotated: &HashMap<usize, AnnotatedRead>,
+        out_dir: &Path,
+        max_skip_len: u32,
+        interner: &OurInterner,
+    ) -> Result<()> {
+        let mut writers = DedupFastqWriters::new(out_dir, chunk)?;
+        bam.fetch((
+            chunk.tid,
+            chunk.start as u64,
+            (chunk.stop + max_skip_len) as u64,
+        ))?;
+        let mut read = bam::Record::new();
+        let mut ii = 0;
+        while let Some(bam_result) = bam.read(&mut read) {
+            bam_result?;
+            if let Some(AnnotatedRead::Counted(info)) = idx_to_annotated.get(&ii) {
+                let name = std::str::from_utf8(read.qname()).context("read name wasn't utf8")?;
+                let mut seq = read.seq().as_bytes();
+                let mut qual: Vec<u8> = read.qual().iter().map(|q| q + 33).collect();
+                if read.is_reverse() {
+                    seq = bio::alphabets::dna::revcomp(&seq);
+                    qual.reverse();
+                }
+                let feature = info
+                    .hits
+                    .correct
+                    .iter()
+                    .sorted()
+                    .map(|gene| interner.resolve(*gene).expect("string de-interning failed"))
+                    .join(",");
+                let umi = info
+                    .umi
+                    .as_ref()
+                    .map(|umi| String::from_utf8_lossy(umi).into_owned())
+                    .unwrap_or_default();
+                let desc = format!("UMI:{umi} feature:{feature}");
+                writers
+                    .writer_for(&read)
+                    .write(name, Some(&desc), &seq, &qual)
+                    .context("Failed to write deduplicated read to FASTQ")?;
+            }
+            ii += 1
+        }
+        Ok(())
+    }
+}
+
+/// Picks the file extension matching an output `rust_htslib::bam::Format`,
+/// for both the per-chunk temporary files and the final merged output.
+fn temp_extension(format: rust_htslib::bam::Format) -> &'static str {
+    match format {
+        rust_htslib::bam::Format::Cram => "cram",
+        _ => "bam",
+    }
+}
+
+/// BAI's linear index uses a fixed 16kb-bin layout that tops out at 2^29
+/// bases (~512 Mb) per reference; beyond that htslib silently can't
+/// represent the position and CSI (which supports arbitrary bin depth) must
+/// be used instead.
+const BAI_MAX_REFERENCE_LENGTH: u64 = 1 << 29;
+
+/// Checks for a `.bai`/`.csi` (or `.crai` for CRAM) index sitting next to `bam_filename`,
+/// mirroring the suffixes htslib itself probes for when no explicit index path is given
+/// (either `foo.bam.bai` or `foo.bai`).
+fn has_sidecar_index(bam_filename: &Path) -> bool {
+    let path_str = bam_filename.as_os_str().to_string_lossy();
+    let stem_without_ext = bam_filename.with_extension("");
+    [".bai", ".csi", ".crai"].iter().any(|suffix| {
+        Path::new(&format!("{path_str}{suffix}")).exists()
+            || Path::new(&format!("{}{suffix}", stem_without_ext.display())).exists()
+    })
+}
+
+/// Concatenates the per-chunk, per-category FASTQ files written by
+/// `RejectedFastqWriters`/`DedupFastqWriters` into one gzipped FASTQ per
+/// category under `output_prefix`. Plain byte concatenation is sufficient
+/// (and is what `gunzip`/`zcat` expect) because each per-chunk file is
+/// itself a complete, valid gzip stream. Chunks that produced no reads for a
+/// category simply have no file and are skipped.
+fn combine_temporary_fastqs(
+    categories: &[&str],
+    chunk_names: &[String],
+    temp_dir: &Path,
+    output_prefix: &Path,
+) -> Result<()> {
+    for category in categories {
+        let out_path = output_prefix.join(format!("{category}.fastq.gz"));
+        let mut out = ex::fs::File::create(&out_path)
+            .with_context(|| format!("Failed to create {:?}", out_path))?
+            .into_inner();
+        for chunk_name in chunk_names {
+            let chunk_path = temp_dir.join(format!("{chunk_name}.{category}.fastq.gz"));
+            if chunk_path.exists() {
+                let mut chunk_file = ex::fs::File::open(&chunk_path)
+                    .with_context(|| format!("Failed to open {:?}", chunk_path))?
+                    .into_inner();
+                std::io::copy(&mut chunk_file, &mut out)
+                    .with_context(|| format!("Failed to append {:?}", chunk_path))?;
+            }
+        }
+    }
+    Ok(())
 }
 
 fn combine_temporary_bams(
@@ -1412,7 +2558,10 @@ fn combine_temporary_bams(
     temp_dir: PathBuf,
     output_prefix: &Path,
     output_header: rust_htslib::bam::Header,
+    format: rust_htslib::bam::Format,
+    reference_fasta: Option<&Path>,
 ) -> Result<()> {
+    let extension = temp_extension(format);
     //write the bam file from the generated chunks.
     // We need to do them in the right order. That means
     // we need to split the chunk_names by their reference,
@@ -1449,22 +2598,34 @@ fn combine_temporary_bams(
                     );
                 }
                 last_pos = pos;
-                res.push(format!("{}.bam", chunk_name));
+                res.push(format!("{chunk_name}.{extension}"));
             }
         }
         res
     };
+    let output_path = output_prefix.join(format!("annotated.{extension}"));
     {
-        let mut writer = rust_htslib::bam::Writer::from_path(
-            output_prefix.join("annotated.bam"),
-            &output_header,
-            rust_htslib::bam::Format::Bam,
-        )
-        .expect("Failed to create BAM writer");
+        let mut writer =
+            rust_htslib::bam::Writer::from_path(&output_path, &output_header, format)
+                .expect("Failed to create BAM writer");
+        if format == rust_htslib::bam::Format::Cram {
+            let reference_fasta = reference_fasta
+                .context("CRAM output requires input.reference_fasta to be set")?;
+            writer
+                .set_reference(reference_fasta)
+                .context("Failed to set CRAM reference on combined output writer")?;
+        }
         for chunk_name in sorted_chunk_names {
             let mut input_bam =
                 rust_htslib::bam::Reader::from_path(temp_dir.join(chunk_name.as_str()))
                     .context("Failed to open a chunk of the output BAM to aggregate")?;
+            if format == rust_htslib::bam::Format::Cram {
+                if let Some(reference_fasta) = reference_fasta {
+                    input_bam
+                        .set_reference(reference_fasta)
+                        .context("Failed to set CRAM reference on chunk reader")?;
+                }
+            }
             let mut read = bam::Record::new();
             while let Some(bam_result) = input_bam.read(&mut read) {
                 bam_result.context("Failed to read BAM record")?;
@@ -1472,13 +2633,19 @@ fn combine_temporary_bams(
             }
         }
     }
-    rust_htslib::bam::index::build(
-        output_prefix.join("annotated.bam"),
-        None,
-        rust_htslib::bam::index::Type::Bai,
-        0,
-    )
-    .context("Failed to build BAM index")?;
+    // Large plant/polyploid references can exceed BAI's 2^29-base linear
+    // index limit; switch to CSI (arbitrary bin depth) when any target does.
+    let hv: rust_htslib::bam::HeaderView = rust_htslib::bam::HeaderView::from_header(&output_header);
+    let index_type = if (0..hv.target_count())
+        .filter_map(|tid| hv.target_len(tid))
+        .any(|len| len > BAI_MAX_REFERENCE_LENGTH)
+    {
+        rust_htslib::bam::index::Type::Csi
+    } else {
+        rust_htslib::bam::index::Type::Bai
+    };
+    rust_htslib::bam::index::build(&output_path, None, index_type, 0)
+        .context("Failed to build output index")?;
     //remove the temporary directory
     ex::fs::remove_dir_all(&temp_dir).with_context(|| {
         format!(
@@ -1489,16 +2656,70 @@ fn combine_temporary_bams(
     Ok(())
 }
 
+/// Honours `Strategy::overlap` and `Strategy::multi_region`. `Union` is
+/// resolved directly in `hits` from a per-gene bitset: `Drop` clears a
+/// multi-gene set, while `CountBoth`, `Resolve` and `Defer` all keep every
+/// candidate gene. `IntersectionStrict`/`IntersectionNonEmpty` go through
+/// `apply_count_strategy` below, which also does `Resolve`'s per-gene
+/// scoring.
 pub struct TreeMatcher {
-    reference_to_count_trees: HashMap<String, (OurTree, Vec<String>)>,
-    reference_to_aggregation_trees: HashMap<String, (OurTree, Vec<String>)>,
+    reference_to_count_trees: HashMap<String, (OurTree, Vec<String>, BitsIndex)>,
+    reference_to_aggregation_trees: HashMap<String, (OurTree, Vec<String>, BitsIndex)>,
     count_strategy: crate::config::Strategy,
+    /// Bp cap on a single `ChunkedGenome` window (`Input::max_chunk_size`).
+    chunk_size: u32,
+    /// `Input::target_reads_per_chunk`: when set, windows over dense loci
+    /// are shrunk below `chunk_size` to keep the estimated read count per
+    /// chunk roughly equal across the genome.
+    target_reads_per_chunk: Option<u32>,
+    /// Derived from `Input::tolerate_corrupt`: whether a chromosome
+    /// `ChunkedGenomeIterator` can't resolve against the BAM header (e.g.
+    /// from a truncated/corrupted index) is skipped-and-logged rather than
+    /// aborting the whole run.
+    chunk_error_policy: chunked_genome::ErrorPolicy,
 }
 
 impl TreeMatcher {
     fn generate_chunks(&self, bam: rust_htslib::bam::IndexedReader) -> Result<Vec<Chunk>> {
-        let cg = ChunkedGenome::new(&self.reference_to_aggregation_trees, bam)?; // can't get the ParallelBridge to work with our lifetimes.
-        Ok(cg.iter().collect())
+        let cg = ChunkedGenome::new_with_policy(
+            &self.reference_to_aggregation_trees,
+            bam,
+            self.chunk_size,
+            self.target_reads_per_chunk,
+            self.chunk_error_policy,
+        )?; // can't get the ParallelBridge to work with our lifetimes.
+        let chunks = cg.iter().collect();
+        let failed_regions = cg.failed_regions();
+        if !failed_regions.is_empty() {
+            eprintln!(
+                "Warning: {} chromosome(s) were skipped because they could not be resolved against the BAM header, results are partial: {:?}",
+                failed_regions.len(),
+                failed_regions
+            );
+        }
+        Ok(chunks)
+    }
+
+    /// The blocks used for feature overlap testing, after applying
+    /// `Strategy::extend_span_by_softclips` (grow the outer blocks by the
+    /// read's leading/trailing soft-clip length) and
+    /// `Strategy::min_block_overlap` (drop blocks shorter than that many
+    /// matched bases).
+    fn effective_blocks(&self, read: &rust_htslib::bam::record::Record) -> Vec<(u32, u32)> {
+        let mut blocks = read.blocks();
+        if self.count_strategy.extend_span_by_softclips {
+            let cigar = read.cigar();
+            if let Some(first) = blocks.first_mut() {
+                first.0 = first.0.saturating_sub(cigar.leading_softclips() as u32);
+            }
+            if let Some(last) = blocks.last_mut() {
+                last.1 += cigar.trailing_softclips() as u32;
+            }
+        }
+        if self.count_strategy.min_block_overlap > 0 {
+            blocks.retain(|(start, stop)| stop - start >= self.count_strategy.min_block_overlap);
+        }
+        blocks
     }
 
     fn hits(
@@ -1511,14 +2732,17 @@ impl TreeMatcher {
         Vec<string_interner::symbol::SymbolU32>,
     )> {
         use crate::config::MatchDirection;
-        let (tree, gene_ids) = self
+        let (tree, gene_ids, bits) = self
             .reference_to_count_trees
             .get(&chunk.chr)
             .expect("Chr not found in trees");
-        let blocks = read.blocks();
+        let blocks = self.effective_blocks(read);
         if let crate::config::OverlapMode::Union = self.count_strategy.overlap {
-            let mut gene_nos_seen_match = Vec::new();
-            let mut gene_nos_seen_reverse = Vec::new();
+            // Indexed by gene_no (not the interned symbol) so membership is a
+            // single bit test/set instead of a linear Vec scan per hit - reads
+            // falling in dense, many-isoform loci no longer pay O(hits^2).
+            let mut bits_seen_match = FixedBitSet::with_capacity(gene_ids.len());
+            let mut bits_seen_reverse = FixedBitSet::with_capacity(gene_ids.len());
             //todo: I don't like having this duplication
             for iv in blocks.iter() {
                 if chunk.interval_outside(iv.0, iv.1) {
@@ -1530,8 +2754,6 @@ impl TreeMatcher {
                     // of our intervals.
                     continue;
                 }
-                //todo: consider using either a bitset for the overlap range,
-                //or no overlap range at all when doing Union.
                 for r in tree.find(iv.0..iv.1) {
                     let entry = r.data();
                     let gene_no = entry.0;
@@ -1542,64 +2764,92 @@ impl TreeMatcher {
                         region_strand,
                     ) {
                         (MatchDirection::Forward, false, Strand::Forward) => {
-                            &mut gene_nos_seen_match
+                            &mut bits_seen_match
                         }
                         (MatchDirection::Forward, false, Strand::Reverse) => {
-                            &mut gene_nos_seen_reverse
+                            &mut bits_seen_reverse
                         }
                         (MatchDirection::Forward, true, Strand::Forward) => {
-                            &mut gene_nos_seen_reverse
+                            &mut bits_seen_reverse
                         }
                         (MatchDirection::Forward, true, Strand::Reverse) => {
-                            &mut gene_nos_seen_match
+                            &mut bits_seen_match
                         }
                         (MatchDirection::Forward, _, Strand::Unstranded) => {
-                            &mut gene_nos_seen_match
+                            &mut bits_seen_match
                         }
 
                         (MatchDirection::Reverse, false, Strand::Forward) => {
-                            &mut gene_nos_seen_reverse
+                            &mut bits_seen_reverse
                         }
                         (MatchDirection::Reverse, false, Strand::Reverse) => {
-                            &mut gene_nos_seen_match
+                            &mut bits_seen_match
                         }
                         (MatchDirection::Reverse, true, Strand::Forward) => {
-                            &mut gene_nos_seen_match
+                            &mut bits_seen_match
                         }
                         (MatchDirection::Reverse, true, Strand::Reverse) => {
-                            &mut gene_nos_seen_reverse
+                            &mut bits_seen_reverse
                         }
                         (MatchDirection::Reverse, _, Strand::Unstranded) => {
-                            &mut gene_nos_seen_match
+                            &mut bits_seen_match
                         }
-                        (MatchDirection::Ignore, _, _) => &mut gene_nos_seen_match,
+                        (MatchDirection::Ignore, _, _) => &mut bits_seen_match,
                     };
-                    let gene_id = interner.get_or_intern(&gene_ids[gene_no as usize]);
-                    if !target.iter().any(|x| *x == gene_id) {
-                        // if we haven't seen this gene yet, add it
-                        target.push(gene_id);
-                    }
+                    target.insert(gene_no as usize);
                 }
             }
-            for gg in [&mut gene_nos_seen_match, &mut gene_nos_seen_reverse] {
-                match self.count_strategy.multi_region {
-                    crate::config::MultiRegionHandling::Drop => {
-                        if gg.len() > 1 {
-                            // if there are multiple genes, drop them
-                            gg.clear();
-                        }
-                    }
-                    crate::config::MultiRegionHandling::CountBoth => {
-                        //do nothing.
+            // `Resolve` needs per-gene overlap lengths/positions to score
+            // candidates, which this branch doesn't track (Union only cares
+            // about membership) - it falls back to `CountBoth`-like
+            // behaviour (keep all candidates) the same way `CountBoth`
+            // itself does below.
+            for bits in [&mut bits_seen_match, &mut bits_seen_reverse] {
+                if let crate::config::MultiRegionHandling::Drop = self.count_strategy.multi_region
+                {
+                    if bits.count_ones(..) > 1 {
+                        // if there are multiple genes, drop them
+                        bits.clear();
                     }
                 }
             }
+            let gene_nos_seen_match: Vec<_> = bits_seen_match
+                .ones()
+                .map(|gene_no| interner.get_or_intern(&gene_ids[gene_no]))
+                .collect();
+            let gene_nos_seen_reverse: Vec<_> = bits_seen_reverse
+                .ones()
+                .map(|gene_no| interner.get_or_intern(&gene_ids[gene_no]))
+                .collect();
             Ok((gene_nos_seen_match, gene_nos_seen_reverse))
         } else {
             let mut gene_nos_seen_match =
                 HashMap::<string_interner::symbol::SymbolU32, Vec<std::ops::Range<u32>>>::new();
             let mut gene_nos_seen_reverse =
                 HashMap::<string_interner::symbol::SymbolU32, Vec<std::ops::Range<u32>>>::new();
+
+            // BITS pre-check: count how many annotation features fall within
+            // the read's overall aligned span (start of its first block to
+            // the end of its last) in O(log n), without ever calling
+            // `tree.find`. If that's zero, no narrower per-block query can
+            // find anything either, so we can skip the whole per-block
+            // accumulation below - the common case for reads outside any
+            // annotated region. (A nonzero count doesn't by itself prove the
+            // read is ambiguous - that still depends on strand splitting and
+            // which blocks the features actually fall in - so it isn't used
+            // to short-circuit `MultiRegionHandling::Drop`; only the
+            // unambiguous zero-overlap case is safe to fast-path here.)
+            let span = blocks
+                .iter()
+                .map(|iv| iv.0)
+                .min()
+                .zip(blocks.iter().map(|iv| iv.1).max());
+            if let Some((span_start, span_end)) = span {
+                if bits.count_overlaps(span_start..span_end) == 0 {
+                    return Ok((Vec::new(), Vec::new()));
+                }
+            }
+
             let mut bases_aligned = 0u32;
             for iv in blocks.iter() {
                 bases_aligned += iv.1 - iv.0;
@@ -1672,15 +2922,24 @@ impl TreeMatcher {
                     }
                 }
             }
+            let position_scores =
+                if let crate::config::MultiRegionHandling::Resolve = self.count_strategy.multi_region
+                {
+                    cigar_position_scores(read, &self.count_strategy)
+                } else {
+                    Vec::new()
+                };
             let gene_nos_seen_match = apply_count_strategy(
                 &self.count_strategy,
                 gene_nos_seen_match,
                 bases_aligned as usize,
+                &position_scores,
             );
             let gene_nos_seen_reverse = apply_count_strategy(
                 &self.count_strategy,
                 gene_nos_seen_reverse,
                 bases_aligned as usize,
+                &position_scores,
             );
 
             Ok((gene_nos_seen_match, gene_nos_seen_reverse))
@@ -1798,39 +3057,184 @@ impl ReferenceMatcher {
     }
 }
 
-fn merged_interval_length(ivs: &mut [std::ops::Range<u32>]) -> usize {
+/// Annotation-free genome-wide coverage binning: every chromosome with reads
+/// is cut into fixed-width `bin_width` windows via
+/// `ChunkedGenome::new_without_tree` (no `OurTree` to snap chunk boundaries
+/// against), and each read is assigned to the bin its start position falls
+/// in, synthesized as a `chr:binstart-binstop` name - useful for coverage
+/// profiling, copy-number-style signal, or quantifying libraries with no
+/// GTF annotation at all.
+pub struct BinMatcher {
+    bin_width: u32,
+    direction: MatchDirection,
+    /// Bp cap on a single `ChunkedGenome` window (`Input::max_chunk_size`).
+    chunk_size: u32,
+    /// `Input::target_reads_per_chunk`, see `TreeMatcher`.
+    target_reads_per_chunk: Option<u32>,
+    /// Derived from `Input::tolerate_corrupt`, see `TreeMatcher`.
+    chunk_error_policy: chunked_genome::ErrorPolicy,
+}
+
+impl BinMatcher {
+    fn generate_chunks(&self, bam: rust_htslib::bam::IndexedReader) -> Result<Vec<Chunk>> {
+        let cg = ChunkedGenome::new_without_tree(
+            bam,
+            self.chunk_size,
+            self.target_reads_per_chunk,
+            self.chunk_error_policy,
+        )?;
+        let chunks = cg.iter().collect();
+        let failed_regions = cg.failed_regions();
+        if !failed_regions.is_empty() {
+            eprintln!(
+                "Warning: {} chromosome(s) were skipped because they could not be resolved against the BAM header, results are partial: {:?}",
+                failed_regions.len(),
+                failed_regions
+            );
+        }
+        Ok(chunks)
+    }
+
+    fn hits(
+        &self,
+        chunk: &Chunk,
+        read: &rust_htslib::bam::Record,
+        interner: &mut OurInterner,
+    ) -> Result<(
+        Vec<string_interner::symbol::SymbolU32>,
+        Vec<string_interner::symbol::SymbolU32>,
+    )> {
+        let mut genes_hit_correct = Vec::new();
+        let mut genes_hit_reverse = Vec::new();
+        let pos = u32::try_from(read.pos().max(0)).unwrap_or(0);
+        let bin_start = (pos / self.bin_width) * self.bin_width;
+        let bin_stop = bin_start + self.bin_width;
+        let name = format!("{}:{}-{}", chunk.chr, bin_start, bin_stop);
+        if match (&self.direction, read.is_reverse()) {
+            (MatchDirection::Ignore, _) => true,
+            (MatchDirection::Forward, true) => false,
+            (MatchDirection::Forward, false) => true,
+            (MatchDirection::Reverse, true) => true,
+            (MatchDirection::Reverse, false) => false,
+        } {
+            genes_hit_correct.push(interner.get_or_intern(&name));
+        } else {
+            genes_hit_reverse.push(interner.get_or_intern(&name));
+        }
+        Ok((genes_hit_correct, genes_hit_reverse))
+    }
+}
+
+/// Merges `ivs` into maximal runs, coalescing intervals separated by up to
+/// `max_gap` bases into one run instead of only merging on true
+/// overlap/touching (`max_gap = 0`). Lets adjacent-exon-style gaps (a tiny
+/// intron, a fragmented feature record) be treated as one contiguous region
+/// for `IntersectionStrict`/`IntersectionNonEmpty` containment tests, and
+/// for `apply_count_strategy` feeding one gene's bridged ranges into its
+/// shared `IntervalSweepMap`. Returns the merged runs sorted by start.
+fn merge_ranges(ivs: &mut [std::ops::Range<u32>], max_gap: u32) -> Vec<std::ops::Range<u32>> {
     if ivs.is_empty() {
-        return 0;
+        return Vec::new();
     }
     ivs.sort_by(|a, b| a.start.cmp(&b.start).then_with(|| a.end.cmp(&b.end)));
-    let mut current_start = ivs[0].start;
-    let mut current_end = ivs[0].end;
-    let mut total = 0;
+    let mut merged = Vec::new();
+    let mut current = ivs[0].clone();
     for iv in ivs.iter().skip(1) {
-        if iv.start <= current_end {
-            // overlap, merge
-            current_end = current_end.max(iv.end);
+        if iv.start <= current.end + max_gap {
+            // overlap, or within the allowed gap - merge
+            current.end = current.end.max(iv.end);
         } else {
-            // no overlap, push the current interval and start a new one
-            total += (current_end - current_start) as usize;
-            current_start = iv.start;
-            current_end = iv.end;
+            // no overlap, push the current run and start a new one
+            merged.push(current.clone());
+            current = iv.clone();
+        }
+    }
+    merged.push(current);
+    merged
+}
+
+/// Total covered length across `merge_ranges`' merged runs.
+fn merged_interval_length(ivs: &mut [std::ops::Range<u32>], max_gap: u32) -> usize {
+    merge_ranges(ivs, max_gap)
+        .iter()
+        .map(|r| (r.end - r.start) as usize)
+        .sum()
+}
+
+/// Walks `read`'s CIGAR in reference order, emitting one `(ref_pos, score)`
+/// entry per aligned-or-deleted reference base (`M`/`=` score
+/// `+match_score`, `X` scores `-diff_score`, `D` scores `-indel_score`), plus
+/// one entry for each insertion, attributed to the reference position it
+/// occurs at (an insertion consumes no reference base, so it can't be
+/// attributed to a single base the way `M`/`X`/`D` are) and scored
+/// `-indel_score` per inserted base. Used by `MultiRegionHandling::Resolve`
+/// to pick the best-scoring gene among several overlap candidates.
+fn cigar_position_scores(
+    read: &rust_htslib::bam::Record,
+    strategy: &crate::config::Strategy,
+) -> Vec<(u32, f64)> {
+    use rust_htslib::bam::record::Cigar;
+    let mut ref_pos = read.pos() as u32;
+    let mut scores = Vec::new();
+    for op in read.cigar().into_iter() {
+        match op {
+            Cigar::Match(len) | Cigar::Equal(len) => {
+                scores.extend((0..len).map(|i| (ref_pos + i, strategy.match_score)));
+                ref_pos += len;
+            }
+            Cigar::Diff(len) => {
+                scores.extend((0..len).map(|i| (ref_pos + i, -strategy.diff_score)));
+                ref_pos += len;
+            }
+            Cigar::Del(len) => {
+                scores.extend((0..len).map(|i| (ref_pos + i, -strategy.indel_score)));
+                ref_pos += len;
+            }
+            Cigar::Ins(len) => {
+                scores.push((ref_pos, -strategy.indel_score * f64::from(len)));
+            }
+            Cigar::RefSkip(len) => {
+                ref_pos += len;
+            }
+            _ => {}
         }
     }
-    total += (current_end - current_start) as usize;
-    total
+    scores
 }
 
+/// Takes the per-gene ranges a read's blocks overlapped (accumulated in a
+/// plain `HashMap` by the caller) and resolves them into the final gene set
+/// per `count_strategy`. Each gene's ranges are first gap-bridged via
+/// `merge_ranges` (unchanged `max_gap_merge` semantics), then every gene's
+/// bridged ranges are fed into one shared `crate::interval_sweep::
+/// IntervalSweepMap` - its single left-to-right `sweep()` hands back each
+/// gene's total covered length directly, instead of this function
+/// separately summing each gene's own merged runs. Resolve's per-position
+/// scoring still needs the per-gene ranges themselves, so those are kept
+/// alongside the swept lengths rather than discarded.
 fn apply_count_strategy(
     count_strategy: &crate::config::Strategy,
     gene_nos: HashMap<string_interner::symbol::SymbolU32, Vec<std::ops::Range<u32>>>,
     bases_aligned: usize,
+    position_scores: &[(u32, f64)],
 ) -> Vec<string_interner::symbol::SymbolU32> {
-    //now merge the intervals, and convert them into length
-    let mut gg_len = gene_nos
+    let mut ranges_by_gene: HashMap<SymbolU32, Vec<std::ops::Range<u32>>> = HashMap::new();
+    let mut sweep = crate::interval_sweep::IntervalSweepMap::new();
+    for (gene, mut v) in gene_nos {
+        let merged = merge_ranges(&mut v, count_strategy.max_gap_merge);
+        for r in &merged {
+            sweep.insert(r.clone(), gene);
+        }
+        ranges_by_gene.insert(gene, merged);
+    }
+    let (lengths, _multi_region) = sweep.sweep();
+    let mut gg = ranges_by_gene
         .into_iter()
-        .map(|(k, mut v)| (k, merged_interval_length(&mut v)))
-        .collect::<HashMap<SymbolU32, usize>>();
+        .map(|(gene, ranges)| {
+            let len = lengths.get(&gene).copied().unwrap_or(0);
+            (gene, (len, ranges))
+        })
+        .collect::<HashMap<SymbolU32, (usize, Vec<std::ops::Range<u32>>)>>();
     match count_strategy.overlap {
         crate::config::OverlapMode::Union => {
             unreachable!();
@@ -1838,13 +3242,13 @@ fn apply_count_strategy(
         }
         crate::config::OverlapMode::IntersectionStrict => {
             //only keep those that are fully contained in the region
-            gg_len.retain(|_, v| *v == bases_aligned);
+            gg.retain(|_, (len, _)| *len == bases_aligned);
         }
         crate::config::OverlapMode::IntersectionNonEmpty => {
-            let any_fully_contained = gg_len.values().any(|v| *v == bases_aligned);
+            let any_fully_contained = gg.values().any(|(len, _)| *len == bases_aligned);
             if any_fully_contained {
                 // only keep those that are fully contained in the region
-                gg_len.retain(|_, v| *v == bases_aligned);
+                gg.retain(|_, (len, _)| *len == bases_aligned);
             } else {
                 // multiple partial overlaps, keep all.
             }
@@ -1852,30 +3256,81 @@ fn apply_count_strategy(
     }
     match count_strategy.multi_region {
         crate::config::MultiRegionHandling::Drop => {
-            if gg_len.len() > 1 {
+            if gg.len() > 1 {
                 // if there are multiple genes, drop them
-                gg_len.clear();
+                gg.clear();
             }
         }
         crate::config::MultiRegionHandling::CountBoth => {
             //do nothing.
         }
+        crate::config::MultiRegionHandling::Defer => {
+            // Keep the full ambiguous gene set, same as `CountBoth` -
+            // `CounterPerChunk::count_reads` is the one that actually
+            // withholds a deferred read's weight from `counter`, based on
+            // `ReadToGeneMatcher::defers_ambiguous`.
+        }
+        crate::config::MultiRegionHandling::Resolve => {
+            if gg.len() > 1 {
+                let scored: Vec<(SymbolU32, f64)> = gg
+                    .iter()
+                    .map(|(gene, (_, ranges))| {
+                        let score: f64 = position_scores
+                            .iter()
+                            .filter(|(pos, _)| ranges.iter().any(|r| r.contains(pos)))
+                            .map(|(_, s)| s)
+                            .sum();
+                        (*gene, score)
+                    })
+                    .collect();
+                let max_score = scored
+                    .iter()
+                    .map(|(_, s)| *s)
+                    .fold(f64::NEG_INFINITY, f64::max);
+                let mut winners = scored.iter().filter(|(_, s)| *s == max_score);
+                let winner = winners.next().map(|(g, _)| *g);
+                if winner.is_some() && winners.next().is_none() {
+                    let winner = winner.unwrap();
+                    gg.retain(|g, _| *g == winner);
+                } else {
+                    // tied for best score - fall back to Drop semantics.
+                    gg.clear();
+                }
+            }
+        }
     }
-    gg_len.into_keys().collect()
+    gg.into_keys().collect()
 }
 #[cfg(test)]
 mod test {
     #[test]
     fn test_merged_interval_lengths() {
         use super::merged_interval_length;
-        assert_eq!(merged_interval_length(&mut []), 0);
-        assert_eq!(merged_interval_length(&mut [0..10, 10..20, 20..30]), 30);
-        assert_eq!(merged_interval_length(&mut [0..10, 5..15, 10..20]), 20);
-        assert_eq!(merged_interval_length(&mut [0..10, 5..15, 10..25]), 25);
-        assert_eq!(merged_interval_length(&mut [0..10, 20..55]), 45);
+        assert_eq!(merged_interval_length(&mut [], 0), 0);
+        assert_eq!(merged_interval_length(&mut [0..10, 10..20, 20..30], 0), 30);
+        assert_eq!(merged_interval_length(&mut [0..10, 5..15, 10..20], 0), 20);
+        assert_eq!(merged_interval_length(&mut [0..10, 5..15, 10..25], 0), 25);
+        assert_eq!(merged_interval_length(&mut [0..10, 20..55], 0), 45);
         assert_eq!(
-            merged_interval_length(&mut [20..30, 45..50, 28..32]),
+            merged_interval_length(&mut [20..30, 45..50, 28..32], 0),
             10 + 5 + 2
         );
     }
+
+    #[test]
+    fn test_merged_interval_lengths_with_gap() {
+        use super::merged_interval_length;
+        // a gap of 5 between 0..10 and 15..20 isn't bridged by max_gap=4...
+        assert_eq!(merged_interval_length(&mut [0..10, 15..20], 4), 10 + 5);
+        // ...but is bridged by max_gap=5, merging into one 0..20 run.
+        assert_eq!(merged_interval_length(&mut [0..10, 15..20], 5), 20);
+        // a bigger gap still isn't bridged by a too-small max_gap.
+        assert_eq!(merged_interval_length(&mut [0..10, 20..55], 5), 10 + 35);
+        // chained gaps: each individual gap is within max_gap, so the whole
+        // run merges even though the outermost ends are far apart.
+        assert_eq!(
+            merged_interval_length(&mut [0..10, 13..20, 23..30], 3),
+            30
+        );
+    }
 }
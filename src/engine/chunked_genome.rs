@@ -1,14 +1,49 @@
-use super::OurTree;
-use anyhow::Result;
+use super::{BitsIndex, OurTree};
+use anyhow::{bail, Context, Result};
 use rust_htslib::bam;
 use rust_htslib::bam::Read;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 
+/// How `ChunkedGenomeIterator` should react when it can't resolve a
+/// chromosome it expected to find in the BAM header (e.g. a truncated/
+/// corrupted BGZF region that desynced the index from the actual
+/// references present). `Abort` (the default, via `ChunkedGenome::new`)
+/// keeps today's fail-fast behavior; `SkipAndLog` logs the chromosome name
+/// and skips it instead, recording it so the caller can surface a
+/// partial-result warning via `ChunkedGenome::failed_regions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    #[default]
+    Abort,
+    SkipAndLog,
+}
+
 pub struct ChunkedGenome<'a> {
-    trees: Option<&'a HashMap<String, (OurTree, Vec<String>)>>,
+    trees: Option<&'a HashMap<String, (OurTree, Vec<String>, BitsIndex)>>,
     bam: bam::IndexedReader,
     chromosomes: Vec<String>,
+    /// Upper bound (bp) on a single chunk's span, used as-is when
+    /// `target_reads_per_chunk` is `None`, and as the ceiling on the
+    /// density-adjusted window otherwise.
+    chunk_size: u32,
+    /// When set, `ChunkedGenomeIterator` shrinks a tid's window below
+    /// `chunk_size` so that, given its average read density, each chunk is
+    /// expected to hold roughly this many reads - keeping parallel workers
+    /// balanced even when reads are clustered (highly expressed loci,
+    /// mitochondria, rRNA) rather than spread evenly across the genome.
+    target_reads_per_chunk: Option<u32>,
+    /// Reads per bp for each tid, estimated from `index_stats`'
+    /// per-reference mapped totals - the same per-tid granularity
+    /// `tids_with_reads` already draws on, used here as the density proxy
+    /// the windowing math needs.
+    tid_density: HashMap<u32, f64>,
+    error_policy: ErrorPolicy,
+    /// Chromosome names skipped under `ErrorPolicy::SkipAndLog`, in the
+    /// order encountered. Interior mutability because
+    /// `ChunkedGenomeIterator` only ever holds a shared `&ChunkedGenome`.
+    failed_regions: RefCell<Vec<String>>,
 }
 
 pub fn tids_with_reads(bam: &mut bam::IndexedReader) -> Result<Vec<u32>> {
@@ -27,13 +62,61 @@ pub fn tids_with_reads(bam: &mut bam::IndexedReader) -> Result<Vec<u32>> {
     Ok(keep_tids)
 }
 
+/// Reads per bp for each tid from `index_stats`, or empty when no
+/// `target_reads_per_chunk` was requested - shared by the tree-based and
+/// tree-free constructors so both windowing paths balance chunks the same
+/// way.
+fn tid_density(
+    bam: &mut bam::IndexedReader,
+    target_reads_per_chunk: Option<u32>,
+) -> Result<HashMap<u32, f64>> {
+    Ok(if target_reads_per_chunk.is_some() {
+        bam.index_stats()?
+            .iter()
+            .filter_map(|(tid, length, mapped_count, _unmapped_count)| {
+                if *tid < 0 || *length <= 0 {
+                    None
+                } else {
+                    let tid: u32 = (*tid).try_into().expect("SAM tid should fit into u32");
+                    Some((tid, *mapped_count as f64 / *length as f64))
+                }
+            })
+            .collect()
+    } else {
+        HashMap::new()
+    })
+}
+
 impl<'a> ChunkedGenome<'a> {
     ///create a new chunked genome for iteration
     ///if you pass in a tree, it is guaranteed that the splits happen
     ///between entries of the tree, not inside.
     pub fn new(
-        trees: &'a HashMap<String, (OurTree, Vec<String>)>,
+        trees: &'a HashMap<String, (OurTree, Vec<String>, BitsIndex)>,
+        bam: bam::IndexedReader,
+        chunk_size: u32,
+        target_reads_per_chunk: Option<u32>,
+    ) -> Result<ChunkedGenome<'a>> {
+        Self::new_with_policy(
+            trees,
+            bam,
+            chunk_size,
+            target_reads_per_chunk,
+            ErrorPolicy::Abort,
+        )
+    }
+
+    /// Same as `new`, but lets the caller opt into `ErrorPolicy::SkipAndLog`
+    /// so a chromosome `ChunkedGenomeIterator` can't resolve against the
+    /// BAM header - e.g. because a truncated/corrupted BGZF region desynced
+    /// the index - is logged and skipped instead of aborting the whole run.
+    /// Skipped names are recorded in `failed_regions`.
+    pub fn new_with_policy(
+        trees: &'a HashMap<String, (OurTree, Vec<String>, BitsIndex)>,
         mut bam: bam::IndexedReader,
+        chunk_size: u32,
+        target_reads_per_chunk: Option<u32>,
+        error_policy: ErrorPolicy,
     ) -> Result<ChunkedGenome<'a>> {
         let keep_tids = tids_with_reads(&mut bam)?;
         let chrs_in_tree_and_bam = trees
@@ -46,25 +129,69 @@ impl<'a> ChunkedGenome<'a> {
             })
             .cloned()
             .collect();
+        let density = tid_density(&mut bam, target_reads_per_chunk)?;
         Ok(ChunkedGenome {
             chromosomes: chrs_in_tree_and_bam,
             trees: Some(trees),
             bam,
+            chunk_size,
+            target_reads_per_chunk,
+            tid_density: density,
+            error_policy,
+            failed_regions: RefCell::new(Vec::new()),
         })
     }
 
-    /* pub fn new_without_tree(bam: bam::IndexedReader) -> ChunkedGenome {
-        ChunkedGenome {
+    /// Chromosome names `ChunkedGenomeIterator` skipped under
+    /// `ErrorPolicy::SkipAndLog`, in the order encountered. Always empty
+    /// under the default `ErrorPolicy::Abort`.
+    pub fn failed_regions(&self) -> Vec<String> {
+        self.failed_regions.borrow().clone()
+    }
+
+    /// Tree-free variant for annotation-less quantification (e.g.
+    /// `BinMatcher`'s genome-wide coverage bins): iterates every chromosome
+    /// with reads the same way `new_with_policy` does, but with no
+    /// `OurTree` to snap chunk boundaries against, so
+    /// `ChunkedGenomeIterator` cuts every chunk at a flat `chunk_size`/
+    /// density-adjusted window instead of a gene boundary.
+    pub fn new_without_tree(
+        mut bam: bam::IndexedReader,
+        chunk_size: u32,
+        target_reads_per_chunk: Option<u32>,
+        error_policy: ErrorPolicy,
+    ) -> Result<ChunkedGenome<'a>> {
+        let keep_tids = tids_with_reads(&mut bam)?;
+        let chromosomes: Vec<String> = bam
+            .header()
+            .target_names()
+            .iter()
+            .enumerate()
+            .filter_map(|(tid, name)| {
+                let tid: u32 = tid.try_into().expect("SAM tid should fit into u32");
+                if keep_tids.iter().any(|x| *x == tid) {
+                    Some(
+                        std::str::from_utf8(name)
+                            .expect("target name should be utf8")
+                            .to_string(),
+                    )
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let density = tid_density(&mut bam, target_reads_per_chunk)?;
+        Ok(ChunkedGenome {
+            chromosomes,
             trees: None,
-            chromosomes: bam
-                .header()
-                .target_names()
-                .iter()
-                .map(|x| str::from_utf8(x).unwrap().to_string())
-                .collect(),
             bam,
-        }
-    } */
+            chunk_size,
+            target_reads_per_chunk,
+            tid_density: density,
+            error_policy,
+            failed_regions: RefCell::new(Vec::new()),
+        })
+    }
 
     pub fn iter(&self) -> ChunkedGenomeIterator {
         ChunkedGenomeIterator {
@@ -86,7 +213,7 @@ pub struct ChunkedGenomeIterator<'a> {
     last_tid: u32,
     last_chr_length: u32,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
     pub chr: String,
     pub tid: u32,
@@ -115,24 +242,245 @@ impl Chunk {
     }
 }
 
+/// A caller-specified genomic interval (0-based, half-open) used to restrict
+/// quantification to a targeted panel instead of scanning the whole BAM, e.g.
+/// `input.regions` in the config. `chr` is matched against `Chunk::chr` the
+/// same way the `Reference` filter already restricts chunks by chromosome
+/// name, so no tid lookup is needed until a chunk actually survives.
+#[derive(Debug, Clone)]
+pub struct RegionQuery {
+    pub chr: String,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl RegionQuery {
+    /// Parses a `"chr:start-end"` string.
+    pub fn parse(s: &str) -> Result<RegionQuery> {
+        let (chr, range) = s
+            .rsplit_once(':')
+            .with_context(|| format!("Region {s:?} is missing a ':' separator"))?;
+        let (start, end) = range
+            .split_once('-')
+            .with_context(|| format!("Region {s:?} is missing a '-' separator"))?;
+        let start: u32 = start
+            .parse()
+            .with_context(|| format!("Invalid region start in {s:?}"))?;
+        let end: u32 = end
+            .parse()
+            .with_context(|| format!("Invalid region end in {s:?}"))?;
+        if start >= end {
+            bail!("Region {s:?} has start >= end");
+        }
+        Ok(RegionQuery {
+            chr: chr.to_string(),
+            start,
+            end,
+        })
+    }
+}
+
+/// Merges overlapping/adjacent regions on the same chromosome, so a chunk
+/// is never clipped against the same interval twice.
+fn merge_regions(regions: &[RegionQuery]) -> Vec<RegionQuery> {
+    let mut by_chr: HashMap<&str, Vec<(u32, u32)>> = HashMap::new();
+    for r in regions {
+        by_chr.entry(r.chr.as_str()).or_default().push((r.start, r.end));
+    }
+    let mut merged = Vec::new();
+    for (chr, mut ivs) in by_chr {
+        ivs.sort_unstable();
+        let mut current = ivs[0];
+        for &(start, end) in &ivs[1..] {
+            if start <= current.1 {
+                current.1 = current.1.max(end);
+            } else {
+                merged.push(RegionQuery {
+                    chr: chr.to_string(),
+                    start: current.0,
+                    end: current.1,
+                });
+                current = (start, end);
+            }
+        }
+        merged.push(RegionQuery {
+            chr: chr.to_string(),
+            start: current.0,
+            end: current.1,
+        });
+    }
+    merged
+}
+
+/// Clips `chunks` down to the parts overlapping `regions`, splitting a chunk
+/// into one sub-chunk per overlapping region. Chunks on chromosomes with no
+/// matching region, or that don't overlap any region, are dropped. Passing
+/// an empty `regions` is a no-op (the whole-genome case).
+pub fn restrict_chunks_to_regions(chunks: Vec<Chunk>, regions: &[RegionQuery]) -> Vec<Chunk> {
+    if regions.is_empty() {
+        return chunks;
+    }
+    let merged = merge_regions(regions);
+    let mut out = Vec::new();
+    for chunk in chunks {
+        for region in merged.iter().filter(|r| r.chr == chunk.chr) {
+            let start = chunk.start.max(region.start);
+            let stop = chunk.stop.min(region.end);
+            if start < stop {
+                out.push(Chunk::new(chunk.chr.clone(), chunk.tid, start, stop));
+            }
+        }
+    }
+    out
+}
+
+/// Splits any chunk whose span exceeds `max_size` into equal-ish sub-chunks,
+/// so a single oversized chromosome/contig doesn't serialize the tail of a
+/// `rayon` run while the other threads sit idle on their (smaller) chunks.
+/// Purely position-based - it does not know about gene boundaries the way
+/// `ChunkedGenomeIterator` does - so it must only be used where that's safe:
+/// callers must skip this for `DeduplicationBucket::PerReference`, which
+/// relies on one chunk per reference to dedup correctly.
+pub fn subdivide_oversized_chunks(chunks: Vec<Chunk>, max_size: u32) -> Vec<Chunk> {
+    if max_size == 0 {
+        return chunks;
+    }
+    let mut out = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let span = chunk.stop - chunk.start;
+        if span <= max_size {
+            out.push(chunk);
+            continue;
+        }
+        let pieces = span.div_ceil(max_size);
+        let piece_size = span.div_ceil(pieces);
+        let mut start = chunk.start;
+        while start < chunk.stop {
+            let stop = (start + piece_size).min(chunk.stop);
+            out.push(Chunk::new(chunk.chr.clone(), chunk.tid, start, stop));
+            start = stop;
+        }
+    }
+    out
+}
+
+/// Lets the real chunk consumer pace `spawn_prefetch_warmer`'s background
+/// lookahead: call `release_one` once per chunk taken up to let the warmer
+/// advance one chunk further. Dropping it ends the warmer (its background
+/// thread sees the channel close on its next `recv` and stops).
+pub struct PrefetchWarmer {
+    /// `mpsc::Sender` is `Send` but not `Sync`, and the real chunk consumer
+    /// calls `release_one` from every rayon worker thread through a shared
+    /// `&PrefetchWarmer`, so the sender needs a `Mutex` around it to be
+    /// shareable that way.
+    release: std::sync::Mutex<std::sync::mpsc::Sender<()>>,
+}
+
+impl PrefetchWarmer {
+    pub fn release_one(&self) {
+        if let Ok(tx) = self.release.lock() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+/// Spawns a background thread, scoped to `scope`, that warms up to
+/// `prefetch_depth` chunks ahead of the real consumer: for each `chunk` (in
+/// order) it opens its own `IndexedReader` via `open_reader`, fetches the
+/// chunk's region and drains every record. This forces htslib to decompress
+/// those BGZF blocks now, so when the real consumer later fetches the same
+/// region the expensive disk read + decompression is already done - the
+/// I/O wait for chunk N+k overlaps the compute for chunk N instead of each
+/// chunk stalling on its own fetch. `prefetch_depth` of `0` still keeps the
+/// warmer one chunk ahead (a window of zero would make it pointless);
+/// callers that don't want any prefetching simply don't call this and keep
+/// the plain synchronous `iter()` fetch-as-you-go path.
+pub fn spawn_prefetch_warmer<'scope, 'env>(
+    scope: &'scope std::thread::Scope<'scope, 'env>,
+    open_reader: impl Fn() -> Result<bam::IndexedReader> + Send + Sync + 'env,
+    chunks: Vec<Chunk>,
+    prefetch_depth: u32,
+) -> PrefetchWarmer {
+    let (permit_tx, permit_rx) = std::sync::mpsc::channel::<()>();
+    for _ in 0..=prefetch_depth {
+        let _ = permit_tx.send(());
+    }
+    scope.spawn(move || {
+        for chunk in chunks {
+            if permit_rx.recv().is_err() {
+                break;
+            }
+            if let Ok(mut reader) = open_reader() {
+                if reader
+                    .fetch((chunk.tid, chunk.start as u64, chunk.stop as u64))
+                    .is_ok()
+                {
+                    let mut rec = bam::Record::new();
+                    while matches!(reader.read(&mut rec), Some(Ok(()))) {}
+                }
+            }
+        }
+    });
+    PrefetchWarmer {
+        release: std::sync::Mutex::new(permit_tx),
+    }
+}
+
 impl Iterator for ChunkedGenomeIterator<'_> {
     type Item = Chunk;
     fn next(&mut self) -> Option<Chunk> {
-        let chunk_size = 10_000_000;
+        let chunk_size = self.cg.chunk_size;
         if self.last_start >= self.last_chr_length {
-            let next_chr = self.it.next()?;
-            let tid = self.cg.bam.header().tid(next_chr.as_bytes()).unwrap();
-            let chr_length = u32::try_from(self.cg.bam.header().target_len(tid).unwrap())
-                .expect("Not u64 contig length aware");
-            self.last_tid = tid;
-            self.last_chr_length = chr_length;
-            self.last_chr = next_chr.to_string();
-            self.last_start = 0;
+            loop {
+                let next_chr = self.it.next()?;
+                let resolved = self
+                    .cg
+                    .bam
+                    .header()
+                    .tid(next_chr.as_bytes())
+                    .and_then(|tid| {
+                        let chr_length = self.cg.bam.header().target_len(tid)?;
+                        u32::try_from(chr_length).ok().map(|len| (tid, len))
+                    });
+                match resolved {
+                    Some((tid, chr_length)) => {
+                        self.last_tid = tid;
+                        self.last_chr_length = chr_length;
+                        self.last_chr = next_chr.to_string();
+                        self.last_start = 0;
+                        break;
+                    }
+                    None if self.cg.error_policy == ErrorPolicy::SkipAndLog => {
+                        eprintln!(
+                            "Skipping chromosome {next_chr:?}: could not resolve tid/length against the BAM header (possibly a corrupted/truncated index)."
+                        );
+                        self.cg.failed_regions.borrow_mut().push(next_chr.clone());
+                    }
+                    None => panic!(
+                        "Could not resolve tid/length for chromosome {next_chr:?} against the BAM header."
+                    ),
+                }
+            }
         }
 
-        let mut stop = self.last_start + chunk_size;
+        // Shrink the window below the bp cap when we know this tid's read
+        // density and have a target read count to hit - a window over a
+        // dense locus (e.g. mitochondria, rRNA) ends up much smaller in bp
+        // than one over a sparse one, so chunks stay roughly equal in the
+        // thing that actually costs rayon workers time: reads processed.
+        let window = match (
+            self.cg.target_reads_per_chunk,
+            self.cg.tid_density.get(&self.last_tid),
+        ) {
+            (Some(target), Some(density)) if *density > 0.0 => {
+                let estimated = (target as f64 / density).round() as u32;
+                estimated.clamp(1, chunk_size)
+            }
+            _ => chunk_size,
+        };
+        let mut stop = self.last_start + window;
         if self.cg.trees.is_some() {
-            let (next_tree, _next_gene_ids) =
+            let (next_tree, _next_gene_ids, _next_bits) =
                 self.cg.trees.as_ref().unwrap().get(&self.last_chr).unwrap();
             loop {
                 ////this has been adjusted not to cut genes in half
@@ -77,6 +77,15 @@ pub struct ReadRegion {
     stop: u16,
 }
 
+impl ReadRegion {
+    /// The `[start, stop)` range this extractor pulls from the read sequence;
+    /// exposed so callers that also need the matching base qualities (e.g.
+    /// cell-barcode correction) don't have to re-parse the region.
+    pub(crate) fn range(&self) -> (u16, u16) {
+        (self.start, self.stop)
+    }
+}
+
 impl UMIExtractor for ReadRegion {
     fn extract(&self, read: &rust_htslib::bam::record::Record) -> Result<Option<Vec<u8>>> {
         if self.stop > read.seq_len() as u16 {
@@ -41,6 +41,14 @@ pub trait ReadFilter: Send + Sync {
     }
 }
 
+/// Read-level filters (mirrors featureCounts' `-Q`/`--primary`/`--ignoreDup`/
+/// proper-pair flags), applied uniformly wherever a quantifier scans BAM
+/// records. There is no separate `index_stats`-based fast path in this
+/// engine for a filter to bypass - `index_stats` only feeds
+/// `ChunkedGenome`'s choice of references with reads (see
+/// `engine::chunked_genome::tids_with_reads`) and its chunk-balancing
+/// window sizing (see `engine::chunked_genome::tid_density`), not a count-without-scanning
+/// shortcut, so filters never need special-casing around one.
 #[derive(serde::Deserialize, Debug, Clone, strum_macros::Display, serde::Serialize)]
 #[serde(tag = "mode")]
 #[enum_dispatch]
@@ -64,6 +72,18 @@ pub enum Filter {
     #[serde(alias = "n_in_umi")]
     #[serde(alias = "NInUMI")]
     NInUmi(NInUmi),
+
+    #[serde(alias = "map_quality")]
+    MapQuality(MapQuality),
+
+    #[serde(alias = "edit_distance")]
+    EditDistance(EditDistance),
+
+    #[serde(alias = "duplicate")]
+    Duplicate(Duplicate),
+
+    #[serde(alias = "proper_pair")]
+    ProperPair(ProperPair),
 }
 
 #[derive(serde::Deserialize, Debug, Clone, serde::Serialize)]
@@ -82,6 +102,8 @@ impl ReadFilter for MultiMapper {
     }
 }
 
+/// featureCounts `--primary`: secondary (0x100) and supplementary (0x800)
+/// alignments are non-primary.
 #[derive(serde::Deserialize, Debug, Clone, serde::Serialize)]
 pub struct NonPrimary {
     action: KeepOrRemove,
@@ -89,10 +111,48 @@ pub struct NonPrimary {
 
 impl ReadFilter for NonPrimary {
     fn remove_read(&self, read: &rust_htslib::bam::record::Record) -> bool {
-        if read.is_secondary() {
-            return self.action == KeepOrRemove::Remove;
+        let hit = read.is_secondary() || read.is_supplementary();
+        match self.action {
+            KeepOrRemove::Keep => !hit,
+            KeepOrRemove::Remove => hit,
+        }
+    }
+}
+
+/// featureCounts `--ignoreDup`: matches reads flagged as PCR/optical
+/// duplicates (SAM flag 0x400), as marked by an upstream dedup tool (e.g.
+/// Picard MarkDuplicates) - distinct from this crate's own UMI-based
+/// `DeduplicationStrategy`. `action = Remove` drops them, `Keep` keeps only
+/// them.
+#[derive(serde::Deserialize, Debug, Clone, serde::Serialize)]
+pub struct Duplicate {
+    action: KeepOrRemove,
+}
+
+impl ReadFilter for Duplicate {
+    fn remove_read(&self, read: &rust_htslib::bam::record::Record) -> bool {
+        let hit = read.is_duplicate();
+        match self.action {
+            KeepOrRemove::Keep => !hit,
+            KeepOrRemove::Remove => hit,
+        }
+    }
+}
+
+/// Matches reads carrying the properly-paired flag (SAM flag 0x2):
+/// `action = Keep` keeps only those reads, `Remove` drops them.
+#[derive(serde::Deserialize, Debug, Clone, serde::Serialize)]
+pub struct ProperPair {
+    action: KeepOrRemove,
+}
+
+impl ReadFilter for ProperPair {
+    fn remove_read(&self, read: &rust_htslib::bam::record::Record) -> bool {
+        let hit = read.is_proper_pair();
+        match self.action {
+            KeepOrRemove::Keep => !hit,
+            KeepOrRemove::Remove => hit,
         }
-        false
     }
 }
 
@@ -214,3 +274,53 @@ impl ReadFilter for NInUmi {
         }
     }
 }
+
+/// Matches reads below a minimum mapping quality (`mapq < min_mapq`):
+/// `action = Remove` drops them, `Keep` keeps only them.
+#[derive(serde::Deserialize, Debug, Clone, serde::Serialize)]
+pub struct MapQuality {
+    pub action: KeepOrRemove,
+    pub min_mapq: u8,
+}
+
+impl ReadFilter for MapQuality {
+    fn remove_read(&self, read: &rust_htslib::bam::record::Record) -> bool {
+        let hit = read.mapq() < self.min_mapq;
+        match self.action {
+            KeepOrRemove::Keep => !hit,
+            KeepOrRemove::Remove => hit,
+        }
+    }
+}
+
+/// Matches reads whose `NM` (edit distance) auxiliary tag exceeds a threshold:
+/// `action = Remove` drops them, `Keep` keeps only them.
+/// Reads without an `NM` tag are never removed, since we have no basis to judge them.
+#[derive(serde::Deserialize, Debug, Clone, serde::Serialize)]
+pub struct EditDistance {
+    pub action: KeepOrRemove,
+    pub max_nm: i64,
+}
+
+impl ReadFilter for EditDistance {
+    fn remove_read(&self, read: &rust_htslib::bam::record::Record) -> bool {
+        let nm = if let Ok(nm) = read.aux(b"NM") {
+            match nm {
+                rust_htslib::bam::record::Aux::U8(v) => v as i64,
+                rust_htslib::bam::record::Aux::U16(v) => v as i64,
+                rust_htslib::bam::record::Aux::U32(v) => v as i64,
+                rust_htslib::bam::record::Aux::I8(v) => v as i64,
+                rust_htslib::bam::record::Aux::I16(v) => v as i64,
+                rust_htslib::bam::record::Aux::I32(v) => v as i64,
+                _ => panic!("NM tag wasn't an integer."),
+            }
+        } else {
+            return false; // no NM tag - nothing to filter on
+        };
+        let hit = nm > self.max_nm;
+        match self.action {
+            KeepOrRemove::Keep => !hit,
+            KeepOrRemove::Remove => hit,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::config::GTFFormat;
 use crate::io::open_file;
 use anyhow::{bail, Context, Result};
 use std::{
@@ -52,6 +53,11 @@ impl GTFEntrys {
 
         for values in self.cat_attributes.values_mut() {
             let mut iter = keep.iter();
+            assert_eq!(
+                values.len(),
+                keep.len(),
+                "Categorical attributes had different length than the rest of the GTFEntries:"
+            );
             values.values.retain(|_| *iter.next().unwrap());
         }
         for values in self.vec_attributes.values_mut() {
@@ -126,12 +132,68 @@ impl Into<i8> for &Strand {
     }
 }
 
+/// Splits a single `key <value>;` (GTF) or `key=<value>` (GFF3) attribute
+/// field into `(key, raw_value)`, leaving GFF3 percent-encoding/comma lists
+/// in `raw_value` for the caller to expand.
+fn split_attribute<'a>(attr_value: &'a str, format: GTFFormat) -> Option<(&'a str, &'a str)> {
+    match format {
+        GTFFormat::Gtf => {
+            let mut kv = attr_value.splitn(2, ' ');
+            let key = kv.next()?;
+            let value = kv.next()?.trim_end().trim_matches('"');
+            Some((key, value))
+        }
+        GTFFormat::Gff => {
+            let mut kv = attr_value.splitn(2, '=');
+            let key = kv.next()?;
+            let value = kv.next()?;
+            Some((key, value))
+        }
+        GTFFormat::AutoDetect => unreachable!("format must be resolved before splitting"),
+    }
+}
+
+/// Percent-decodes a GFF3 attribute value (e.g. `%2C` -> `,`, `%3B` -> `;`).
+fn gff3_percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut ii = 0;
+    while ii < bytes.len() {
+        if bytes[ii] == b'%' && ii + 2 < bytes.len() {
+            if let Ok(hex) = std::str::from_utf8(&bytes[ii + 1..ii + 3]) {
+                if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                    out.push(byte);
+                    ii += 3;
+                    continue;
+                }
+            }
+        }
+        out.push(bytes[ii]);
+        ii += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Sniffs whether a GTF/GFF line's attribute column uses GTF
+/// (`key "value"; key "value";`) or GFF3 (`key=value;key=value`) syntax, by
+/// looking for a bare `=` before the first `;`/space that isn't part of a
+/// quoted value.
+fn detect_attribute_format(attributes_str: &str) -> GTFFormat {
+    let first_chunk = attributes_str.split(';').next().unwrap_or("");
+    if first_chunk.contains('=') && !first_chunk.trim_start().starts_with('"') {
+        GTFFormat::Gff
+    } else {
+        GTFFormat::Gtf
+    }
+}
+
 //A gtf 'parser' (more like 'extract what we need') that doesn't
 // allocate every line at least once
 pub fn parse_minimal(
     filename: &str,
-    accepted_features: HashSet<String>,
-    accepted_tags: HashSet<String>,
+    subformat: GTFFormat,
+    accepted_features: &HashSet<String>,
+    accepted_tags: &HashSet<String>,
 ) -> Result<HashMap<String, GTFEntrys>> {
     use linereader::LineReader; // non allocateding.
     let file = open_file(filename)?;
@@ -162,7 +224,7 @@ pub fn parse_minimal(
             .context("No start")?
             .parse()
             .context("start not int")?;
-        let start = start.checked_sub(1).context("start must be >= 1")?; // GTF is 1-based, convert to 0-based
+        let start = start.checked_sub(1).context("start must be >= 1")?; // GTF/GFF is 1-based, convert to 0-based
         let end: u64 = fields
             .next()
             .context("No end")?
@@ -176,19 +238,33 @@ pub fn parse_minimal(
             .context("failed to parse strand. Allowed +-._")?;
         let _frame = fields.next().context("no frame")?;
         let attributes_str = fields.next().context("No attributes")?;
+        let format = match subformat {
+            GTFFormat::AutoDetect => detect_attribute_format(attributes_str),
+            explicit => explicit,
+        };
         let it = attributes_str
             .split_terminator(';')
             .map(str::trim_start)
             .filter(|x| !x.is_empty());
-        let mut tags = Vec::new();
+        let mut tags: Vec<(&str, String)> = Vec::new();
         for attr_value in it {
-            let mut kv = attr_value.splitn(2, ' ');
-            let key: &str = kv.next().unwrap();
+            let Some((key, raw_value)) = split_attribute(attr_value, format) else {
+                continue;
+            };
             if !accepted_tags.contains(key) {
                 continue;
             }
-            let value: &str = kv.next().unwrap().trim_end().trim_matches('"');
-            tags.push((key, value));
+            match format {
+                GTFFormat::Gff => {
+                    // GFF3 allows a comma-separated list of values for one
+                    // key (e.g. `Parent=a,b,c`); expand each into its own
+                    // (repeated-key) entry, matching how GTF repeats `tag`.
+                    for part in raw_value.split(',') {
+                        tags.push((key, gff3_percent_decode(part)));
+                    }
+                }
+                _ => tags.push((key, raw_value.to_string())),
+            }
         }
         if !tags.is_empty() {
             let entry = result
@@ -200,19 +276,50 @@ pub fn parse_minimal(
             entry.strand.push(strand);
             let mut seen = HashSet::new();
             for (key, value) in tags {
-                if seen.contains(key) {
-                    bail!("doublicate attribute in GTF: {} in line: {}", key, line);
+                if matches!(format, GTFFormat::Gtf) {
+                    if seen.contains(key) {
+                        bail!("doublicate attribute in GTF: {} in line: {}", key, line);
+                    }
                 }
                 seen.insert(key);
-                match entry.vec_attributes.entry(key.to_string()) {
-                    std::collections::hash_map::Entry::Occupied(mut e) => {
-                        e.get_mut().push(value.to_string());
+                // low-cardinality fields (gene_biotype, source, ...) are
+                // interned via Categorical to avoid millions of duplicate
+                // String allocations on genome-scale GTFs; identifier
+                // columns stay in vec_attributes since every row's value is
+                // (close to) unique and interning buys nothing.
+                if key.ends_with("_id") {
+                    match entry.vec_attributes.entry(key.to_string()) {
+                        std::collections::hash_map::Entry::Occupied(mut e) => {
+                            e.get_mut().push(value);
+                        }
+                        std::collections::hash_map::Entry::Vacant(e) => {
+                            e.insert(vector_new_empty_push(entry.count, value));
+                        }
                     }
-                    std::collections::hash_map::Entry::Vacant(e) => {
-                        e.insert(vector_new_empty_push(entry.count, value.to_string()));
+                } else {
+                    match entry.cat_attributes.entry(key.to_string()) {
+                        std::collections::hash_map::Entry::Occupied(mut e) => {
+                            e.get_mut().push(&value);
+                        }
+                        std::collections::hash_map::Entry::Vacant(e) => {
+                            e.insert(Categorical::new_empty_push(entry.count, &value));
+                        }
                     }
                 }
             }
+            // backfill every column this row didn't mention so all
+            // attribute columns stay exactly entry.count+1 long, matching
+            // GTFEntrys::filter's equal-length assumption.
+            for (key, values) in entry.vec_attributes.iter_mut() {
+                if !seen.contains(key.as_str()) {
+                    values.push(String::new());
+                }
+            }
+            for (key, values) in entry.cat_attributes.iter_mut() {
+                if !seen.contains(key.as_str()) {
+                    values.push("");
+                }
+            }
             entry.count += 1;
         }
     }
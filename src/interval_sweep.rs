@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::ops::Range;
+
+/// One non-overlapping run of the sweep line, tagged with every value whose
+/// inserted range covers it.
+#[derive(Debug, Clone)]
+struct Segment<T> {
+    start: u32,
+    end: u32,
+    values: HashSet<T>,
+}
+
+/// A gap-and-overlap-preserving interval map over a single coordinate line
+/// (as in nodit's `NoditMap`): every `insert` cuts any existing segments at
+/// the new range's boundaries and re-splits them, so segments never
+/// partially overlap one another, and a position covered by several
+/// inserted ranges simply carries several values.
+///
+/// Used by `engine::apply_count_strategy` to accumulate every gene's
+/// (gap-bridged) aligned ranges into one structure and read each gene's
+/// total covered length back out of a single left-to-right
+/// [`sweep`](IntervalSweepMap::sweep), instead of sorting and re-merging one
+/// `Vec<Range<u32>>` per gene independently.
+#[derive(Debug, Clone, Default)]
+pub struct IntervalSweepMap<T: Eq + Hash + Clone> {
+    segments: Vec<Segment<T>>,
+}
+
+impl<T: Eq + Hash + Clone> IntervalSweepMap<T> {
+    pub fn new() -> Self {
+        IntervalSweepMap {
+            segments: Vec::new(),
+        }
+    }
+
+    /// Cuts any existing segments overlapping `range` at `range`'s
+    /// boundaries (and at any of their own boundaries that fall inside it),
+    /// then adds `value` to every sub-segment - new or pre-existing -
+    /// covering `range`. A no-op for an empty or inverted range.
+    pub fn insert(&mut self, range: Range<u32>, value: T) {
+        if range.start >= range.end {
+            return;
+        }
+        let mut breakpoints: Vec<u32> = vec![range.start, range.end];
+        for seg in &self.segments {
+            if seg.start > range.start && seg.start < range.end {
+                breakpoints.push(seg.start);
+            }
+            if seg.end > range.start && seg.end < range.end {
+                breakpoints.push(seg.end);
+            }
+        }
+
+        // Re-split any existing segment whose span straddles one of the new
+        // boundaries, so every segment ends up either fully inside or fully
+        // outside `range`.
+        let mut rebuilt = Vec::with_capacity(self.segments.len() + breakpoints.len());
+        for seg in self.segments.drain(..) {
+            let mut cuts: Vec<u32> = breakpoints
+                .iter()
+                .copied()
+                .filter(|&b| b > seg.start && b < seg.end)
+                .collect();
+            if cuts.is_empty() {
+                rebuilt.push(seg);
+                continue;
+            }
+            cuts.sort_unstable();
+            let mut start = seg.start;
+            for cut in cuts {
+                rebuilt.push(Segment {
+                    start,
+                    end: cut,
+                    values: seg.values.clone(),
+                });
+                start = cut;
+            }
+            rebuilt.push(Segment {
+                start,
+                end: seg.end,
+                values: seg.values,
+            });
+        }
+        self.segments = rebuilt;
+
+        // Insert `value` into (or create) every sub-segment covering `range`.
+        breakpoints.sort_unstable();
+        breakpoints.dedup();
+        for w in breakpoints.windows(2) {
+            let (start, end) = (w[0], w[1]);
+            if start < range.start || end > range.end || start >= end {
+                continue;
+            }
+            if let Some(seg) = self
+                .segments
+                .iter_mut()
+                .find(|s| s.start == start && s.end == end)
+            {
+                seg.values.insert(value.clone());
+            } else {
+                let mut values = HashSet::new();
+                values.insert(value.clone());
+                self.segments.push(Segment { start, end, values });
+            }
+        }
+        self.segments.sort_by_key(|s| s.start);
+    }
+
+    /// Single left-to-right sweep over the assembled segments: returns each
+    /// value's total covered length, plus whether at least one position is
+    /// covered by more than one distinct value (more than one gene's
+    /// bridged ranges share a base).
+    pub fn sweep(&self) -> (HashMap<T, usize>, bool) {
+        let mut lengths: HashMap<T, usize> = HashMap::new();
+        let mut multi_region = false;
+        for seg in &self.segments {
+            let len = (seg.end - seg.start) as usize;
+            if seg.values.len() > 1 {
+                multi_region = true;
+            }
+            for value in &seg.values {
+                *lengths.entry(value.clone()).or_insert(0) += len;
+            }
+        }
+        (lengths, multi_region)
+    }
+
+    /// Total length covered by at least one value.
+    pub fn covered_length(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|s| (s.end - s.start) as usize)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_single_value_length_matches_inserted_ranges() {
+        let mut map = IntervalSweepMap::new();
+        map.insert(0..10, "a");
+        map.insert(20..30, "a");
+        let (lengths, multi_region) = map.sweep();
+        assert_eq!(lengths.get("a"), Some(&20));
+        assert!(!multi_region);
+        assert_eq!(map.covered_length(), 20);
+    }
+
+    #[test]
+    fn test_overlapping_values_split_into_shared_segment() {
+        let mut map = IntervalSweepMap::new();
+        map.insert(0..10, "a");
+        map.insert(5..15, "b");
+        let (lengths, multi_region) = map.sweep();
+        // each value's own total length is unaffected by the split.
+        assert_eq!(lengths.get("a"), Some(&10));
+        assert_eq!(lengths.get("b"), Some(&10));
+        assert!(multi_region);
+        // [0,10) union [5,15) covers [0,15) once each.
+        assert_eq!(map.covered_length(), 15);
+    }
+
+    #[test]
+    fn test_insert_order_does_not_matter() {
+        let mut forward = IntervalSweepMap::new();
+        forward.insert(0..10, "a");
+        forward.insert(5..20, "b");
+        forward.insert(15..25, "a");
+
+        let mut backward = IntervalSweepMap::new();
+        backward.insert(15..25, "a");
+        backward.insert(5..20, "b");
+        backward.insert(0..10, "a");
+
+        assert_eq!(forward.sweep().0, backward.sweep().0);
+        assert_eq!(forward.covered_length(), backward.covered_length());
+    }
+
+    #[test]
+    fn test_disjoint_values_are_not_multi_region() {
+        let mut map = IntervalSweepMap::new();
+        map.insert(0..10, "a");
+        map.insert(10..20, "b");
+        let (_, multi_region) = map.sweep();
+        assert!(!multi_region);
+    }
+}
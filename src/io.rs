@@ -1,4 +1,4 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use std::io::Read;
 use std::path::{Path, PathBuf};
 
@@ -9,10 +9,36 @@ pub fn open_file(filename: impl AsRef<Path>) -> Result<Box<dyn Read + Send>> {
     Ok(wrapped.0)
 }
 
+/// Whether htslib has its own way to resolve a CRAM reference without us
+/// passing one explicitly: `REF_PATH`/`REF_CACHE` are the same env vars
+/// `samtools`/htslib consult on their own, so a CRAM opened without
+/// `reference_fasta` can still decode correctly if the caller's environment
+/// sets either of them.
+fn has_cram_ref_env_fallback() -> bool {
+    std::env::var_os("REF_PATH").is_some() || std::env::var_os("REF_CACHE").is_some()
+}
+
 pub fn open_indexed_bam(
     filename: impl AsRef<Path>,
     index_filename: Option<impl AsRef<Path>>,
+    reference_fasta: Option<impl AsRef<Path>>,
 ) -> Result<rust_htslib::bam::IndexedReader> {
+    use rust_htslib::bam::Read as _;
+    // htslib itself sniffs BAM/SAM/CRAM from magic bytes, not the extension,
+    // but we still use the extension here as a cheap heuristic to fail fast
+    // with a clear error instead of letting htslib silently hand back
+    // empty/garbage sequences for CRAM records it can't decode.
+    let looks_like_cram = filename
+        .as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cram"));
+    if looks_like_cram && reference_fasta.is_none() && !has_cram_ref_env_fallback() {
+        bail!(
+            "{:?} looks like a CRAM file, but no reference FASTA was given and neither REF_PATH nor REF_CACHE is set in the environment. CRAM records cannot be decoded without a reference - set `reference_fasta` or export REF_PATH/REF_CACHE.",
+            filename.as_ref()
+        );
+    }
     let mut reader = if let Some(index_filename) = index_filename {
         let filename: &Path = filename.as_ref();
         let filename = filename.to_owned();
@@ -24,5 +50,100 @@ pub fn open_indexed_bam(
         rust_htslib::bam::IndexedReader::from_path(&filename)
             .context(format!("Failed to open BAM file: {:?}", &filename.as_ref()))?
     };
+    // CRAM files need a reference to decode records against; BAM/SAM ignore this.
+    if let Some(reference_fasta) = reference_fasta {
+        reader
+            .set_reference(reference_fasta.as_ref())
+            .with_context(|| {
+                format!(
+                    "Failed to set CRAM reference {:?} for {:?}",
+                    reference_fasta.as_ref(),
+                    filename.as_ref()
+                )
+            })?;
+    }
     Ok(reader)
 }
+
+/// Abstracts over the different ways a stream of alignment records can be
+/// consumed, so callers that only care about `bam::Record`s don't have to
+/// care what container format backs them. In practice this is a thin wrapper
+/// around `rust_htslib::bam::Reader`: htslib sniffs BAM/SAM/CRAM from the
+/// file's magic bytes and header, not its extension, so the same reader
+/// already handles plain (and bgzipped) SAM text without a separate parser.
+pub trait RecordSource {
+    fn header(&self) -> &rust_htslib::bam::HeaderView;
+
+    /// Reads the next record into `record`, `None` at EOF, mirroring
+    /// `rust_htslib::bam::Read::read`'s return shape.
+    fn next_record(&mut self, record: &mut rust_htslib::bam::Record) -> Option<Result<()>>;
+}
+
+/// Opens a BAM, SAM, or bgzipped-SAM file for sequential (non-indexed)
+/// reading. There is no separate `SamReader`: htslib's format auto-detection
+/// means it would just wrap the same `rust_htslib::bam::Reader`.
+pub struct BamReader {
+    inner: rust_htslib::bam::Reader,
+}
+
+impl BamReader {
+    pub fn from_path(filename: impl AsRef<Path>) -> Result<BamReader> {
+        use rust_htslib::bam::Read as _;
+        let inner = rust_htslib::bam::Reader::from_path(filename.as_ref())
+            .with_context(|| format!("Failed to open alignment file: {:?}", filename.as_ref()))?;
+        Ok(BamReader { inner })
+    }
+}
+
+impl RecordSource for BamReader {
+    fn header(&self) -> &rust_htslib::bam::HeaderView {
+        use rust_htslib::bam::Read as _;
+        self.inner.header()
+    }
+
+    fn next_record(&mut self, record: &mut rust_htslib::bam::Record) -> Option<Result<()>> {
+        use rust_htslib::bam::Read as _;
+        self.inner
+            .read(record)
+            .map(|res| res.context("Failed to read alignment record"))
+    }
+}
+
+/// Re-emits records annotated with the feature they were assigned to, via an
+/// `XF:Z:<feature>` tag (matching the tag htseq-count/featureCounts use for
+/// the same purpose) - e.g. for debugging which reads contributed to a
+/// feature's count. Output format (BAM/SAM/CRAM) is chosen by `format`.
+pub struct AnnotatingWriter {
+    inner: rust_htslib::bam::Writer,
+}
+
+impl AnnotatingWriter {
+    pub fn new(
+        path: impl AsRef<Path>,
+        header: &rust_htslib::bam::Header,
+        format: rust_htslib::bam::Format,
+    ) -> Result<AnnotatingWriter> {
+        let inner = rust_htslib::bam::Writer::from_path(path.as_ref(), header, format)
+            .with_context(|| format!("Failed to open output alignment file: {:?}", path.as_ref()))?;
+        Ok(AnnotatingWriter { inner })
+    }
+
+    pub fn write_annotated(
+        &mut self,
+        record: &mut rust_htslib::bam::Record,
+        feature: &str,
+    ) -> Result<()> {
+        use crate::bam_ext::BamRecordExtensions;
+        use rust_htslib::bam::Read as _;
+        record
+            .replace_aux(
+                b"XF",
+                rust_htslib::bam::record::Aux::String(feature),
+            )
+            .context("Failed to set XF tag")?;
+        self.inner
+            .write(record)
+            .context("Failed to write annotated record")?;
+        Ok(())
+    }
+}
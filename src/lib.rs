@@ -1,23 +1,75 @@
-use anyhow::{Context, Result};
-use std::path::Path;
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
+mod ailist;
 mod bam_ext;
 mod barcodes;
 mod categorical;
 mod config;
 mod deduplication;
+mod em;
 mod engine;
 mod extractors;
 mod filters;
 mod gtf;
+mod interval_sweep;
 mod io;
 mod quantification;
+mod sparse_matrix;
+mod splice_junctions;
+mod three_prime;
+mod typed_format;
 
 use config::Config;
 
-pub fn run(toml_file: &Path) -> Result<()> {
-    let raw_config = ex::fs::read_to_string(toml_file)
+/// Reads `toml_file`, textually expanding any `%include <path>` lines (one
+/// directive per line, path optionally quoted) before TOML parsing happens.
+/// Included paths are resolved relative to the directory of the file that
+/// contains the directive, so fragments can `%include` each other regardless
+/// of where the top-level config lives. `active_includes` tracks the include
+/// chain currently being resolved so a file that (directly or transitively)
+/// includes itself is reported instead of recursing forever.
+fn load_config_with_includes(toml_file: &Path, active_includes: &mut HashSet<PathBuf>) -> Result<String> {
+    let canonical = toml_file
+        .canonicalize()
+        .with_context(|| format!("Could not resolve path: {}", toml_file.display()))?;
+    if !active_includes.insert(canonical.clone()) {
+        bail!(
+            "Cyclic %include detected: {} is already being included",
+            toml_file.display()
+        );
+    }
+
+    let raw = ex::fs::read_to_string(toml_file)
         .with_context(|| format!("Could not read toml file: {}", toml_file.to_string_lossy()))?;
+    let including_dir = toml_file.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut expanded = String::new();
+    for line in raw.lines() {
+        match line.trim_start().strip_prefix("%include") {
+            Some(rest) => {
+                let include_path = rest.trim().trim_matches('"').trim_matches('\'');
+                if include_path.is_empty() {
+                    bail!("%include directive in {} is missing a path", toml_file.display());
+                }
+                let resolved = including_dir.join(include_path);
+                expanded.push_str(&load_config_with_includes(&resolved, active_includes)?);
+                expanded.push('\n');
+            }
+            None => {
+                expanded.push_str(line);
+                expanded.push('\n');
+            }
+        }
+    }
+
+    active_includes.remove(&canonical);
+    Ok(expanded)
+}
+
+pub fn run(toml_file: &Path) -> Result<()> {
+    let raw_config = load_config_with_includes(toml_file, &mut HashSet::new())?;
     let mut parsed = toml::from_str::<Config>(&raw_config)
         .with_context(|| format!("Could not parse toml file: {}", toml_file.to_string_lossy()))?;
     parsed.check().context("Error in configuration")?;
@@ -1,9 +1,148 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::{Context, Result};
+use itertools::izip;
 use rust_htslib::bam::Read;
 use crate::{config::{Input, Output}, deduplication::DeduplicationStrategy, engine, extractors::UMIExtraction};
 
+/// Sums `end - start` per `aggr_id_attribute` value across every entry of
+/// `feature`, giving each feature's effective length in bp for
+/// RPKM/FPKM/TPM normalization. A gene modelled as several exon rows gets
+/// its exons' lengths summed, the same quantity featureCounts/htseq use.
+fn gtf_feature_lengths(
+    gtf_entries: &crate::gtf::GTFEntrys,
+    aggr_id_attribute: &str,
+) -> Result<HashMap<String, u64>> {
+    let ids = gtf_entries
+        .vec_attributes
+        .get(aggr_id_attribute)
+        .context("No aggr_id_attribute found in GTF entries")?;
+    let mut lengths: HashMap<String, u64> = HashMap::new();
+    for (id, start, end) in izip!(ids.iter(), gtf_entries.start.iter(), gtf_entries.end.iter()) {
+        *lengths.entry(id.clone()).or_default() += end.saturating_sub(*start);
+    }
+    Ok(lengths)
+}
+
+/// Picks the counts-table filename for `format`: typed output gets its own
+/// extension rather than overwriting/pretending to be `counts.tsv`.
+fn counts_filename(format: crate::config::CountsFormat) -> &'static str {
+    match format {
+        crate::config::CountsFormat::Tsv => "counts.tsv",
+        crate::config::CountsFormat::TypedText => "counts.txt.ne",
+        crate::config::CountsFormat::TypedBinary => "counts.ne",
+    }
+}
+
+/// Resolves `cfg`'s GTF feature rows into one [`ThreePrimeWindow`] per
+/// transcript id (min `start`/max `end` across that id's rows, on whichever
+/// chromosome/strand its first row reports), opens the BAM just far enough to
+/// translate each window's chromosome name into this BAM's numeric `tid`, then
+/// hands off to [`crate::three_prime::quantify_three_prime`] for the actual
+/// read scan. A transcript on a chromosome absent from the BAM header is
+/// silently dropped, the same "no window to assign to" outcome as a
+/// transcript whose reads simply never got any 3'-end hits.
+fn quantify_three_prime_source(
+    input: &Input,
+    cfg: &crate::config::ThreePrimeConfig,
+    output: &Output,
+) -> Result<()> {
+    ex::fs::create_dir_all(&output.directory)?;
+
+    let accepted_features: HashSet<String> = [cfg.feature.clone()].into_iter().collect();
+    let accepted_tags: HashSet<String> = [cfg.id_attribute.clone()].into_iter().collect();
+    let gtf_entries = crate::gtf::parse_minimal(
+        &cfg.filename,
+        cfg.subformat,
+        &accepted_features,
+        &accepted_tags,
+    )?;
+    let entries = gtf_entries.get(cfg.feature.as_str()).with_context(|| {
+        format!(
+            "No GTF entries found for feature {}. Perhaps set subformat to GFF/GTF?",
+            cfg.feature
+        )
+    })?;
+    let ids = entries
+        .vec_attributes
+        .get(cfg.id_attribute.as_str())
+        .context("No id_attribute found in GTF entries")?;
+
+    struct TranscriptSpan {
+        chrom: String,
+        strand_forward: bool,
+        start: i64,
+        end: i64,
+    }
+    let mut spans: HashMap<String, TranscriptSpan> = HashMap::new();
+    for (id, seqname_value, start, end, strand) in izip!(
+        ids.iter(),
+        entries.seqname.values.iter(),
+        entries.start.iter(),
+        entries.end.iter(),
+        entries.strand.iter()
+    ) {
+        let start = *start as i64;
+        let end = *end as i64;
+        match spans.entry(id.clone()) {
+            std::collections::hash_map::Entry::Vacant(e) => {
+                e.insert(TranscriptSpan {
+                    chrom: entries.seqname.cat_from_value(*seqname_value),
+                    strand_forward: !matches!(strand, crate::gtf::Strand::Minus),
+                    start,
+                    end,
+                });
+            }
+            std::collections::hash_map::Entry::Occupied(mut e) => {
+                let span = e.get_mut();
+                span.start = span.start.min(start);
+                span.end = span.end.max(end);
+            }
+        }
+    }
+
+    let bam_reader = crate::io::BamReader::from_path(input.bam.as_str())?;
+    let header = crate::io::RecordSource::header(&bam_reader);
+
+    let windows: Vec<crate::three_prime::ThreePrimeWindow> = spans
+        .into_iter()
+        .filter_map(|(gene_id, span)| {
+            let tid = header.tid(span.chrom.as_bytes()).ok()?;
+            let transcript_end = if span.strand_forward {
+                span.end
+            } else {
+                span.start
+            };
+            Some(crate::three_prime::ThreePrimeWindow::new(
+                gene_id,
+                tid as i32,
+                span.strand_forward,
+                transcript_end,
+                cfg.downstream_extension,
+            ))
+        })
+        .collect();
+
+    let counts = crate::three_prime::quantify_three_prime(
+        &input.bam,
+        &windows,
+        cfg.umi_tag,
+        cfg.bucket_width,
+        cfg.internal_priming,
+    )
+    .context("Error in 3'-tag quantification")?;
+
+    let mut sorted_genes: Vec<&String> = counts.keys().collect();
+    sorted_genes.sort();
+    let mut writer =
+        std::io::BufWriter::new(ex::fs::File::create(output.directory.join("counts.tsv"))?);
+    use std::io::Write;
+    for gene in sorted_genes {
+        writeln!(writer, "{}\t{}", gene, counts[gene])?;
+    }
+    Ok(())
+}
+
 pub fn quantify(
     input: &Input,
     filters: Vec<crate::filters::Filter>,
@@ -17,7 +156,28 @@ pub fn quantify(
     // For now, we just return Ok to indicate success
     //
 
+    if matches!(input.source, crate::config::Source::SpliceJunctions) {
+        // Junction tabulation is a fundamentally different counting unit
+        // (introns, not genes/bins/references), so it bypasses the
+        // GTF/engine-based pipeline below entirely.
+        ex::fs::create_dir_all(&output.directory)?;
+        return crate::splice_junctions::write_splice_junctions(
+            &input.bam,
+            output.directory.join("splice_junctions.tsv"),
+        )
+        .context("Error in splice-junction quantification");
+    }
+
+    if let crate::config::Source::ThreePrime(ref three_prime_config) = input.source {
+        // 3'-end assignment is a direct coordinate-window lookup, not a
+        // block-overlap problem, so this bypasses the GTF/engine-based
+        // counting pipeline below entirely.
+        return quantify_three_prime_source(input, three_prime_config, output);
+    }
+
     let our_engine = match input.source {
+        crate::config::Source::SpliceJunctions => unreachable!("handled above"),
+        crate::config::Source::ThreePrime(_) => unreachable!("handled above"),
         crate::config::Source::GTF(ref gtf_config) => {
             let aggr_id_attribute = gtf_config
                 .aggr_id_attribute
@@ -27,7 +187,7 @@ pub fn quantify(
 
             let gtf_entries = input.read_gtf(gtf_config.duplicate_handling, aggr_id_attribute)?;
 
-            let sorted_output_keys = {
+            let (sorted_output_keys, feature_lengths) = {
                 let entries =
                             gtf_entries
                                 .get(gtf_config.feature.as_str())
@@ -45,18 +205,28 @@ pub fn quantify(
                     .collect();
                 let mut keys: Vec<String> = keys.into_iter().map(|x| x.to_string()).collect();
                 keys.sort();
-                keys
+                let feature_lengths = gtf_feature_lengths(entries, aggr_id_attribute)?;
+                (keys, feature_lengths)
             };
 
             let output = if cell_barcode.is_some() {
-                engine::Output::new_singlecell(output.directory.clone(), Some(sorted_output_keys))?
+                engine::Output::new_singlecell(
+                    output.directory.clone(),
+                    Some(sorted_output_keys),
+                    output.compress_out,
+                    output.matrix_format,
+                )?
             } else {
-                engine::Output::new_per_region(
-                    output.directory.join("counts.tsv"),
+                engine::Output::new_per_region_normalized(
+                    output.directory.join(counts_filename(output.counts_format)),
                     output.only_correct
                         || matches!(strategy.direction, crate::config::MatchDirection::Ignore),
                     Some(sorted_output_keys),
                     aggr_id_attribute.to_string(),
+                    Some(feature_lengths),
+                    output.normalize,
+                    output.counts_format,
+                    output.write_em_rescue,
                 )
             };
 
@@ -71,11 +241,18 @@ pub fn quantify(
                 cell_barcode,
                 strategy.clone(),
                 output,
+                input.max_chunk_size,
+                input.target_reads_per_chunk,
+                input.tolerate_corrupt,
             )?
         }
         crate::config::Source::BamReferences => {
-            let bam = rust_htslib::bam::Reader::from_path(input.bam.as_str())
+            let mut bam = rust_htslib::bam::Reader::from_path(input.bam.as_str())
                 .context("Failed to open BAM file")?;
+            if let Some(reference_fasta) = input.reference_fasta.as_ref() {
+                bam.set_reference(reference_fasta)
+                    .context("Failed to set CRAM reference")?;
+            }
             let header = bam.header();
             let references: Result<Vec<(String, u64)>> = header
                 .target_names()
@@ -95,16 +272,29 @@ pub fn quantify(
             let references = references?;
             let sorted_output_keys: Vec<String> =
                 references.iter().map(|(name, _)| name.clone()).collect();
+            let feature_lengths: HashMap<String, u64> = references
+                .iter()
+                .map(|(name, len)| (name.clone(), *len))
+                .collect();
 
             let output = if cell_barcode.is_some() {
-                engine::Output::new_singlecell(output.directory.clone(), Some(sorted_output_keys))?
+                engine::Output::new_singlecell(
+                    output.directory.clone(),
+                    Some(sorted_output_keys),
+                    output.compress_out,
+                    output.matrix_format,
+                )?
             } else {
-                engine::Output::new_per_region(
-                    output.directory.join("counts.tsv"),
+                engine::Output::new_per_region_normalized(
+                    output.directory.join(counts_filename(output.counts_format)),
                     output.only_correct
                         || matches!(strategy.direction, crate::config::MatchDirection::Ignore),
                     Some(sorted_output_keys),
                     "reference".to_string(),
+                    Some(feature_lengths),
+                    output.normalize,
+                    output.counts_format,
+                    output.write_em_rescue,
                 )
             };
 
@@ -122,16 +312,25 @@ pub fn quantify(
         crate::config::Source::BamTag(crate::config::BamTag { tag }) => {
 
             let output = if cell_barcode.is_some() {
-                engine::Output::new_singlecell(output.directory.clone(), None)?
+                engine::Output::new_singlecell(
+                    output.directory.clone(),
+                    None,
+                    output.compress_out,
+                    output.matrix_format,
+                )?
             } else {
-                engine::Output::new_per_region(
-                    output.directory.join("counts.tsv"),
+                engine::Output::new_per_region_normalized(
+                    output.directory.join(counts_filename(output.counts_format)),
                     output.only_correct
                         || matches!(strategy.direction, crate::config::MatchDirection::Ignore),
                     None,
                     std::str::from_utf8(&tag)
                         .context("Bam tag name was not valid utf8")?
                         .to_string(),
+                    None,
+                    None,
+                    output.counts_format,
+                    output.write_em_rescue,
                 )
             };
             engine::Engine::from_bam_tag(
@@ -143,6 +342,41 @@ pub fn quantify(
                 output,
             )
         }
+
+        crate::config::Source::Bins(crate::config::BinsConfig { bin_width }) => {
+            let output = if cell_barcode.is_some() {
+                engine::Output::new_singlecell(
+                    output.directory.clone(),
+                    None,
+                    output.compress_out,
+                    output.matrix_format,
+                )?
+            } else {
+                engine::Output::new_per_region_normalized(
+                    output.directory.join(counts_filename(output.counts_format)),
+                    output.only_correct
+                        || matches!(strategy.direction, crate::config::MatchDirection::Ignore),
+                    None,
+                    "bin".to_string(),
+                    None,
+                    None,
+                    output.counts_format,
+                    output.write_em_rescue,
+                )
+            };
+            engine::Engine::from_bins(
+                bin_width,
+                filters,
+                dedup_strategy,
+                umi_extraction,
+                cell_barcode,
+                strategy.clone(),
+                output,
+                input.max_chunk_size,
+                input.target_reads_per_chunk,
+                input.tolerate_corrupt,
+            )
+        }
     };
 
     our_engine.quantify_bam(
@@ -152,6 +386,14 @@ pub fn quantify(
         output.write_annotated_bam,
         input.max_skip_length,
         input.correct_reads_for_clipping,
+        input.reference_fasta.as_deref(),
+        &input.regions,
+        input.tolerate_corrupt,
+        input.max_chunk_size,
+        output.annotated_bam_format.clone(),
+        output.write_rejected_fastq,
+        output.write_dedup_fastq,
+        input.prefetch_depth,
     )?;
 
     Ok(())
@@ -0,0 +1,244 @@
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// One non-zero entry of a sparse feature x barcode matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Triple {
+    pub row: u32, // feature index, 0-based
+    pub col: u32, // barcode index, 0-based
+    pub value: u32,
+}
+
+const MAGIC: &[u8; 8] = b"MBFSPMX1";
+
+/// Writes a self-describing little-endian binary sparse matrix, inspired by
+/// enclone's mirror_sparse_matrix format: a fixed header, the `triples`
+/// sorted by `(col, row)`, a per-column offset index (so a reader can seek
+/// straight to one barcode's column without scanning the whole file), and an
+/// 8-byte footer pointing at where that index starts. `triples` does not
+/// need to arrive pre-sorted; it is sorted in memory before writing.
+///
+/// This is the binary counterpart to the MatrixMarket text writer in
+/// `engine::Output::finish` - same data, compact fixed-width encoding, and
+/// random column access instead of a single sequential text scan. Selected
+/// via `Output::SingleCell`'s `matrix_format: MatrixFormat::Binary`
+/// (`crate::config::MatrixFormat`).
+pub fn write_binary_matrix<W: Write + Seek>(
+    out: &mut W,
+    nrows: u64,
+    ncols: u64,
+    mut triples: Vec<Triple>,
+) -> Result<()> {
+    triples.sort_by_key(|t| (t.col, t.row));
+
+    out.write_all(MAGIC).context("Failed to write magic")?;
+    out.write_all(&nrows.to_le_bytes())?;
+    out.write_all(&ncols.to_le_bytes())?;
+    out.write_all(&(triples.len() as u64).to_le_bytes())?;
+
+    // per-column triple-index offsets; column c's entries live in
+    // triples[index[c]..index[c+1]]
+    let mut index = vec![0u64; ncols as usize + 1];
+    let mut next_col = 0usize;
+    for (i, t) in triples.iter().enumerate() {
+        while next_col <= t.col as usize {
+            index[next_col] = i as u64;
+            next_col += 1;
+        }
+        out.write_all(&t.row.to_le_bytes())?;
+        out.write_all(&t.col.to_le_bytes())?;
+        out.write_all(&t.value.to_le_bytes())?;
+    }
+    while next_col <= ncols as usize {
+        index[next_col] = triples.len() as u64;
+        next_col += 1;
+    }
+
+    let index_offset = out.stream_position().context("Failed to get stream position")?;
+    for offset in &index {
+        out.write_all(&offset.to_le_bytes())?;
+    }
+    out.write_all(&index_offset.to_le_bytes())
+        .context("Failed to write index footer")?;
+    Ok(())
+}
+
+pub struct BinaryMatrixReader<R: Read + Seek> {
+    reader: R,
+    pub nrows: u64,
+    pub ncols: u64,
+    pub nnz: u64,
+    triples_start: u64,
+    index: Vec<u64>,
+}
+
+impl<R: Read + Seek> BinaryMatrixReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).context("Failed to read magic")?;
+        if &magic != MAGIC {
+            bail!("Not a recognized binary sparse matrix file (bad magic)");
+        }
+        let nrows = read_u64(&mut reader)?;
+        let ncols = read_u64(&mut reader)?;
+        let nnz = read_u64(&mut reader)?;
+        let triples_start = reader.stream_position().context("Failed to get stream position")?;
+
+        reader.seek(SeekFrom::End(-8)).context("Failed to seek to footer")?;
+        let index_offset = read_u64(&mut reader)?;
+
+        reader
+            .seek(SeekFrom::Start(index_offset))
+            .context("Failed to seek to column index")?;
+        let mut index = Vec::with_capacity(ncols as usize + 1);
+        for _ in 0..=ncols {
+            index.push(read_u64(&mut reader)?);
+        }
+
+        Ok(BinaryMatrixReader {
+            reader,
+            nrows,
+            ncols,
+            nnz,
+            triples_start,
+            index,
+        })
+    }
+
+    /// Reads every non-zero entry belonging to column `col`, without
+    /// scanning the rest of the matrix.
+    pub fn read_column(&mut self, col: u64) -> Result<Vec<Triple>> {
+        if col >= self.ncols {
+            bail!("Column {} out of bounds (ncols={})", col, self.ncols);
+        }
+        let start = self.index[col as usize];
+        let stop = self.index[col as usize + 1];
+        self.reader
+            .seek(SeekFrom::Start(self.triples_start + start * 12))
+            .context("Failed to seek to column data")?;
+        let mut out = Vec::with_capacity((stop - start) as usize);
+        for _ in start..stop {
+            let mut buf = [0u8; 12];
+            self.reader.read_exact(&mut buf).context("Failed to read triple")?;
+            out.push(Triple {
+                row: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+                col: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+                value: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            });
+        }
+        Ok(out)
+    }
+
+    /// Reads every non-zero entry in the matrix, in on-disk (column-major)
+    /// order.
+    pub fn read_all(&mut self) -> Result<Vec<Triple>> {
+        self.reader
+            .seek(SeekFrom::Start(self.triples_start))
+            .context("Failed to seek to triple data")?;
+        let mut out = Vec::with_capacity(self.nnz as usize);
+        for _ in 0..self.nnz {
+            let mut buf = [0u8; 12];
+            self.reader.read_exact(&mut buf).context("Failed to read triple")?;
+            out.push(Triple {
+                row: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+                col: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+                value: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            });
+        }
+        Ok(out)
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf).context("Failed to read u64")?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn triple(row: u32, col: u32, value: u32) -> Triple {
+        Triple { row, col, value }
+    }
+
+    #[test]
+    fn test_round_trips_unsorted_triples_in_column_major_order() {
+        let triples = vec![
+            triple(2, 1, 7),
+            triple(0, 0, 1),
+            triple(1, 0, 2),
+            triple(0, 2, 9),
+        ];
+        let mut buf = Cursor::new(Vec::new());
+        write_binary_matrix(&mut buf, 3, 3, triples).unwrap();
+        buf.set_position(0);
+
+        let mut reader = BinaryMatrixReader::new(buf).unwrap();
+        assert_eq!(reader.nrows, 3);
+        assert_eq!(reader.ncols, 3);
+        assert_eq!(reader.nnz, 4);
+
+        let all = reader.read_all().unwrap();
+        // written in (col, row) sorted order, not insertion order.
+        assert_eq!(
+            all,
+            vec![
+                triple(0, 0, 1),
+                triple(1, 0, 2),
+                triple(2, 1, 7),
+                triple(0, 2, 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_column_returns_only_that_columns_entries() {
+        let triples = vec![
+            triple(0, 0, 1),
+            triple(1, 0, 2),
+            triple(0, 1, 3),
+            triple(2, 2, 4),
+        ];
+        let mut buf = Cursor::new(Vec::new());
+        write_binary_matrix(&mut buf, 3, 3, triples).unwrap();
+        buf.set_position(0);
+
+        let mut reader = BinaryMatrixReader::new(buf).unwrap();
+        assert_eq!(
+            reader.read_column(0).unwrap(),
+            vec![triple(0, 0, 1), triple(1, 0, 2)]
+        );
+        assert_eq!(reader.read_column(1).unwrap(), vec![triple(0, 1, 3)]);
+        assert_eq!(reader.read_column(2).unwrap(), vec![triple(2, 2, 4)]);
+    }
+
+    #[test]
+    fn test_empty_column_reads_back_empty() {
+        let triples = vec![triple(0, 0, 1), triple(0, 2, 2)];
+        let mut buf = Cursor::new(Vec::new());
+        write_binary_matrix(&mut buf, 1, 3, triples).unwrap();
+        buf.set_position(0);
+
+        let mut reader = BinaryMatrixReader::new(buf).unwrap();
+        assert_eq!(reader.read_column(1).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_column_out_of_bounds_errors() {
+        let mut buf = Cursor::new(Vec::new());
+        write_binary_matrix(&mut buf, 1, 2, vec![triple(0, 0, 1)]).unwrap();
+        buf.set_position(0);
+
+        let mut reader = BinaryMatrixReader::new(buf).unwrap();
+        assert!(reader.read_column(2).is_err());
+    }
+
+    #[test]
+    fn test_bad_magic_is_rejected() {
+        let buf = Cursor::new(vec![0u8; 32]);
+        assert!(BinaryMatrixReader::new(buf).is_err());
+    }
+}
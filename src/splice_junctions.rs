@@ -0,0 +1,153 @@
+use crate::bam_ext::BamRecordExtensions;
+use crate::io::{BamReader, RecordSource};
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Strand of a splice junction, derived from the `XS` tag when present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JunctionStrand {
+    Forward,
+    Reverse,
+    Unknown,
+}
+
+impl JunctionStrand {
+    fn from_xs_tag(read: &bam::Record) -> JunctionStrand {
+        match read.aux(b"XS") {
+            Ok(bam::record::Aux::Char(b'+')) => JunctionStrand::Forward,
+            Ok(bam::record::Aux::Char(b'-')) => JunctionStrand::Reverse,
+            Ok(bam::record::Aux::String(s)) if s == "+" => JunctionStrand::Forward,
+            Ok(bam::record::Aux::String(s)) if s == "-" => JunctionStrand::Reverse,
+            _ => JunctionStrand::Unknown,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            JunctionStrand::Forward => "+",
+            JunctionStrand::Reverse => "-",
+            JunctionStrand::Unknown => ".",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_junction_strand_as_str() {
+        assert_eq!(JunctionStrand::Forward.as_str(), "+");
+        assert_eq!(JunctionStrand::Reverse.as_str(), "-");
+        assert_eq!(JunctionStrand::Unknown.as_str(), ".");
+    }
+
+    #[test]
+    fn test_junction_tally_default_is_zeroed() {
+        let tally = JunctionTally::default();
+        assert_eq!(tally.unique_count, 0);
+        assert_eq!(tally.multi_count, 0);
+        assert_eq!(tally.max_overhang, 0);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct JunctionTally {
+    unique_count: u32,
+    multi_count: u32,
+    max_overhang: u32,
+}
+
+type JunctionKey = (i32, u32, u32, JunctionStrand);
+
+/// Tabulates splice junctions (introns) spanned by reads in `bam_path`,
+/// keyed by `(tid, donor, acceptor, strand)`. For every `N` gap in a read's
+/// CIGAR, records whether the read was uniquely mapped (`NH`/`IH` == 1) and
+/// the overhang on each side (the shorter of the two flanking matched-base
+/// runs), tracking the maximum overhang seen per junction.
+pub fn count_splice_junctions(bam_path: impl AsRef<Path>) -> Result<HashMap<JunctionKey, JunctionTally>> {
+    let mut reader = BamReader::from_path(bam_path.as_ref())?;
+    let mut junctions: HashMap<JunctionKey, JunctionTally> = HashMap::new();
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.next_record(&mut record) {
+        result.context("Failed to read BAM record")?;
+        if record.is_unmapped() || record.is_secondary() {
+            continue;
+        }
+        let strand = JunctionStrand::from_xs_tag(&record);
+        let blocks: Vec<(u32, u32)> = record.blocks_iter().collect();
+        let unique = record.no_of_alignments() <= 1;
+        for (donor, acceptor) in record.introns_iter() {
+            // overhang: the shorter of the two matched runs flanking this N gap.
+            let left_overhang = blocks
+                .iter()
+                .filter(|(_, stop)| *stop == donor)
+                .map(|(start, stop)| stop - start)
+                .max()
+                .unwrap_or(0);
+            let right_overhang = blocks
+                .iter()
+                .filter(|(start, _)| *start == acceptor)
+                .map(|(start, stop)| stop - start)
+                .max()
+                .unwrap_or(0);
+            let overhang = left_overhang.min(right_overhang);
+            let entry = junctions
+                .entry((record.tid(), donor, acceptor, strand))
+                .or_default();
+            if unique {
+                entry.unique_count += 1;
+            } else {
+                entry.multi_count += 1;
+            }
+            entry.max_overhang = entry.max_overhang.max(overhang);
+        }
+    }
+    Ok(junctions)
+}
+
+/// Writes the junctions found by `count_splice_junctions` as a tab-separated
+/// SJ table: `chrom, intron_start, intron_end, strand, unique_count,
+/// multi_count, max_overhang`.
+pub fn write_splice_junctions(
+    bam_path: impl AsRef<Path>,
+    output_path: impl AsRef<Path>,
+) -> Result<()> {
+    let reader = BamReader::from_path(bam_path.as_ref())?;
+    let header = reader.header().clone();
+    drop(reader);
+    let junctions = count_splice_junctions(bam_path)?;
+
+    let mut rows: Vec<_> = junctions.into_iter().collect();
+    rows.sort_by_key(|((tid, start, end, _strand), _)| (*tid, *start, *end));
+
+    let mut out = std::io::BufWriter::new(
+        ex::fs::File::create(output_path.as_ref())
+            .with_context(|| format!("Failed to create output file: {:?}", output_path.as_ref()))?,
+    );
+    out.write_all(b"chrom\tintron_start\tintron_end\tstrand\tunique_count\tmulti_count\tmax_overhang\n")
+        .context("Failed to write SJ table header")?;
+    for ((tid, start, end, strand), tally) in rows {
+        let chrom = std::str::from_utf8(header.tid2name(tid as u32))
+            .unwrap_or("?")
+            .to_string();
+        out.write_all(
+            format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                chrom,
+                start,
+                end,
+                strand.as_str(),
+                tally.unique_count,
+                tally.multi_count,
+                tally.max_overhang
+            )
+            .as_bytes(),
+        )
+        .context("Failed to write SJ table row")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use crate::bam_ext::BamRecordExtensions;
+use crate::io::{BamReader, RecordSource};
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// The 3'-most genomic coordinate of an alignment, taking strand into
+/// account: the end of the spliced span for `+` reads, the start for `-`
+/// reads. Mirrors the convention 3'-tag RNA-seq pipelines (e.g. Drop-seq,
+/// 10x 3') use to assign a read to a gene by its poly-A-proximal end rather
+/// than by full overlap.
+pub fn three_prime_position(read: &bam::Record) -> Option<i64> {
+    if read.is_unmapped() {
+        return None;
+    }
+    if read.is_reverse() {
+        Some(read.pos())
+    } else {
+        let blocks = read.blocks();
+        blocks.last().map(|(_, stop)| i64::from(*stop))
+    }
+}
+
+/// A gene's annotated 3' window: `[transcript_end, transcript_end +
+/// downstream_extension)` on `+` strand genes (mirrored for `-` strand), used
+/// to catch reads that run past the annotated poly-A site.
+#[derive(Debug, Clone)]
+pub struct ThreePrimeWindow {
+    pub gene_id: String,
+    pub tid: i32,
+    pub strand_forward: bool,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl ThreePrimeWindow {
+    pub fn new(
+        gene_id: String,
+        tid: i32,
+        strand_forward: bool,
+        transcript_end: i64,
+        downstream_extension: i64,
+    ) -> Self {
+        let (start, end) = if strand_forward {
+            (transcript_end, transcript_end + downstream_extension)
+        } else {
+            (transcript_end - downstream_extension, transcript_end)
+        };
+        ThreePrimeWindow {
+            gene_id,
+            tid,
+            strand_forward,
+            start,
+            end,
+        }
+    }
+
+    pub fn contains(&self, tid: i32, pos: i64) -> bool {
+        self.tid == tid && pos >= self.start && pos < self.end
+    }
+}
+
+/// Approximates internal-priming detection from the read's own soft-clipped
+/// tail rather than a genome FASTA lookup (this crate has no generic
+/// reference-sequence reader): the soft-clipped bases immediately past the
+/// alignment's 3' end are themselves the sequence "downstream" of it. A read
+/// with no (or a short) soft clip there simply has fewer bases to inspect,
+/// so this under-detects internal priming on cleanly-aligned reads compared
+/// to a true genome lookup.
+fn downstream_clip_bases(read: &bam::Record, window_len: usize) -> Vec<u8> {
+    let seq = read.seq();
+    let seq_len = seq.len();
+    let cigar = read.cigar();
+    if read.is_reverse() {
+        let clip_len = cigar.leading_softclips() as usize;
+        let take = window_len.min(clip_len);
+        let start = clip_len - take;
+        (start..clip_len).map(|i| seq[i]).collect()
+    } else {
+        let clip_len = cigar.trailing_softclips() as usize;
+        let take = window_len.min(clip_len);
+        let start = seq_len - clip_len;
+        (start..start + take).map(|i| seq[i]).collect()
+    }
+}
+
+/// Convenience wrapper around [`is_internal_priming`] that pulls its
+/// downstream bases from `read`'s own soft clip. See
+/// [`downstream_clip_bases`].
+fn is_read_internally_primed(read: &bam::Record, cfg: &crate::config::InternalPrimingConfig) -> bool {
+    let bases = downstream_clip_bases(read, cfg.window_len);
+    is_internal_priming(&bases, cfg.min_a_bases, read.is_reverse())
+}
+
+/// Finds every window a read's 3' position falls in. Multiple
+/// overlapping windows are all returned, so callers can apply whatever
+/// multi-gene handling policy (drop/count-both) they use elsewhere.
+pub fn assign_to_windows<'a>(
+    tid: i32,
+    three_prime_pos: i64,
+    windows: &'a [ThreePrimeWindow],
+) -> Vec<&'a ThreePrimeWindow> {
+    windows
+        .iter()
+        .filter(|w| w.contains(tid, three_prime_pos))
+        .collect()
+}
+
+/// Internal-priming filter: discards reads whose 3' end likely reflects
+/// priming off a genomic A-rich stretch rather than a true poly-A tail.
+/// Looks at the `window_len` genomic bases immediately downstream of the 3'
+/// end (on the read's strand) and flags a hit if at least `min_a_bases` of
+/// them are `A` (or `T` on the reverse strand, since the downstream sequence
+/// there is read off the complementary strand).
+pub fn is_internal_priming(downstream_bases: &[u8], min_a_bases: usize, is_reverse: bool) -> bool {
+    let target = if is_reverse { b'T' } else { b'A' };
+    let hits = downstream_bases
+        .iter()
+        .filter(|&&b| b.to_ascii_uppercase() == target)
+        .count();
+    hits >= min_a_bases
+}
+
+/// Buckets a molecule's position for UMI dedup: reads are considered the
+/// same molecule if they share `(gene, position bucket, UMI)`, where the
+/// bucket groups 3' positions within `bucket_width` bases of each other
+/// (PCR/sequencing can shift the observed 3' end by a few bases).
+pub fn position_bucket(three_prime_pos: i64, bucket_width: i64) -> i64 {
+    if bucket_width <= 1 {
+        three_prime_pos
+    } else {
+        three_prime_pos.div_euclid(bucket_width)
+    }
+}
+
+/// Deduplicates reads assigned to 3'-tag genes, keyed on
+/// `(gene, position_bucket, umi)`; returns the number of distinct molecules
+/// per gene.
+pub fn count_molecules(
+    hits: &[(String, i64, Vec<u8>)], // (gene_id, three_prime_pos, umi)
+    bucket_width: i64,
+) -> HashMap<String, usize> {
+    let mut seen: HashSet<(String, i64, Vec<u8>)> = HashSet::new();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (gene_id, pos, umi) in hits {
+        let key = (gene_id.clone(), position_bucket(*pos, bucket_width), umi.clone());
+        if seen.insert(key) {
+            *counts.entry(gene_id.clone()).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// Scans `bam_path` end-to-end, assigning each mapped primary read to the
+/// single `ThreePrimeWindow` (built from the configured transcript feature,
+/// already resolved to this BAM's `tid` numbering by the caller) its 3' end
+/// falls in - reads whose 3' end falls in zero or more than one window are
+/// dropped, mirroring `MultiRegionHandling::Drop`. A read with no `umi_tag`
+/// aux value is dropped (there is nothing to dedup it on); everything that
+/// passes is deduplicated via `count_molecules`.
+pub fn quantify_three_prime(
+    bam_path: impl AsRef<Path>,
+    windows: &[ThreePrimeWindow],
+    umi_tag: [u8; 2],
+    bucket_width: i64,
+    internal_priming: Option<crate::config::InternalPrimingConfig>,
+) -> Result<HashMap<String, usize>> {
+    let mut reader = BamReader::from_path(bam_path.as_ref())?;
+    let mut hits: Vec<(String, i64, Vec<u8>)> = Vec::new();
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.next_record(&mut record) {
+        result.context("Failed to read BAM record")?;
+        if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        let Some(pos) = three_prime_position(&record) else {
+            continue;
+        };
+        if let Some(cfg) = internal_priming {
+            if is_read_internally_primed(&record, &cfg) {
+                continue;
+            }
+        }
+        let matches = assign_to_windows(record.tid(), pos, windows);
+        if matches.len() != 1 {
+            continue;
+        }
+        let umi = match record.aux(&umi_tag) {
+            Ok(bam::record::Aux::String(s)) => s.as_bytes().to_vec(),
+            _ => continue,
+        };
+        hits.push((matches[0].gene_id.clone(), pos, umi));
+    }
+    Ok(count_molecules(&hits, bucket_width))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn window(gene_id: &str, tid: i32, strand_forward: bool, transcript_end: i64, downstream_extension: i64) -> ThreePrimeWindow {
+        ThreePrimeWindow::new(gene_id.to_string(), tid, strand_forward, transcript_end, downstream_extension)
+    }
+
+    #[test]
+    fn test_forward_window_extends_downstream_of_transcript_end() {
+        let w = window("g1", 0, true, 1000, 50);
+        assert_eq!(w.start, 1000);
+        assert_eq!(w.end, 1050);
+        assert!(w.contains(0, 1000));
+        assert!(w.contains(0, 1049));
+        assert!(!w.contains(0, 1050));
+        assert!(!w.contains(1, 1010));
+    }
+
+    #[test]
+    fn test_reverse_window_extends_upstream_of_transcript_end() {
+        let w = window("g1", 0, false, 1000, 50);
+        assert_eq!(w.start, 950);
+        assert_eq!(w.end, 1000);
+        assert!(w.contains(0, 950));
+        assert!(!w.contains(0, 1000));
+    }
+
+    #[test]
+    fn test_assign_to_windows_returns_all_overlapping_windows() {
+        let windows = vec![
+            window("g1", 0, true, 1000, 50),
+            window("g2", 0, true, 1020, 50),
+            window("g3", 1, true, 1000, 50),
+        ];
+        let hits = assign_to_windows(0, 1030, &windows);
+        assert_eq!(hits.len(), 2);
+        assert!(hits.iter().any(|w| w.gene_id == "g1"));
+        assert!(hits.iter().any(|w| w.gene_id == "g2"));
+    }
+
+    #[test]
+    fn test_assign_to_windows_empty_when_nothing_overlaps() {
+        let windows = vec![window("g1", 0, true, 1000, 50)];
+        assert!(assign_to_windows(0, 2000, &windows).is_empty());
+    }
+
+    #[test]
+    fn test_is_internal_priming_counts_a_bases_on_forward_strand() {
+        assert!(is_internal_priming(b"AAAAT", 4, false));
+        assert!(!is_internal_priming(b"AAAAT", 5, false));
+    }
+
+    #[test]
+    fn test_is_internal_priming_counts_t_bases_on_reverse_strand() {
+        assert!(is_internal_priming(b"TTTTA", 4, true));
+        assert!(!is_internal_priming(b"AAAAT", 2, true));
+    }
+
+    #[test]
+    fn test_position_bucket_groups_nearby_positions() {
+        assert_eq!(position_bucket(103, 10), position_bucket(107, 10));
+        assert_ne!(position_bucket(103, 10), position_bucket(113, 10));
+    }
+
+    #[test]
+    fn test_position_bucket_disabled_when_width_not_above_one() {
+        assert_eq!(position_bucket(103, 1), 103);
+        assert_eq!(position_bucket(103, 0), 103);
+    }
+
+    #[test]
+    fn test_count_molecules_dedups_by_gene_bucket_and_umi() {
+        let hits = vec![
+            ("g1".to_string(), 100, b"AAAA".to_vec()),
+            ("g1".to_string(), 103, b"AAAA".to_vec()), // same bucket+umi, not a new molecule
+            ("g1".to_string(), 100, b"CCCC".to_vec()), // different umi, new molecule
+            ("g2".to_string(), 100, b"AAAA".to_vec()),
+        ];
+        let counts = count_molecules(&hits, 10);
+        assert_eq!(counts["g1"], 2);
+        assert_eq!(counts["g2"], 1);
+    }
+}
@@ -0,0 +1,195 @@
+//! A small, self-describing, netencode-inspired framing for the counts
+//! table: every scalar is written as `<tag><len>:<payload>`, where `tag` is
+//! a single ASCII letter naming the value's type and `len` is the decimal
+//! byte length of `payload`. A record is a list of `(name, value)` pairs, a
+//! table a list of records - so a parser never has to guess a column's
+//! type or split on whitespace/tabs, unlike plain TSV.
+//!
+//! Two sinks share the same value model: [`write_text`] produces a
+//! human-readable debug form (handy in a terminal or a diff), [`write_binary`]
+//! the compact wire form. Both are unambiguous and round-trip losslessly -
+//! only the representation of the length-prefixed framing differs from
+//! plain TSV, not the values themselves.
+
+use anyhow::Result;
+use std::io::Write;
+
+/// One typed scalar a [`Record`] field can hold.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedValue {
+    /// Tag `n`: a non-negative integer (read counts, lengths, ...).
+    UInt(u64),
+    /// Tag `i`: a signed integer.
+    Int(i64),
+    /// Tag `t`: UTF-8 text (feature ids, strand symbols, ...).
+    Text(String),
+    /// Tag `b`: arbitrary bytes, for fields that aren't valid UTF-8.
+    Bytes(Vec<u8>),
+}
+
+impl TypedValue {
+    fn tag(&self) -> u8 {
+        match self {
+            TypedValue::UInt(_) => b'n',
+            TypedValue::Int(_) => b'i',
+            TypedValue::Text(_) => b't',
+            TypedValue::Bytes(_) => b'b',
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        match self {
+            TypedValue::UInt(v) => v.to_string().into_bytes(),
+            TypedValue::Int(v) => v.to_string().into_bytes(),
+            TypedValue::Text(v) => v.as_bytes().to_vec(),
+            TypedValue::Bytes(v) => v.clone(),
+        }
+    }
+
+    /// Writes `<tag><len>:<payload>`, identical for the text and binary
+    /// sinks - the framing is already plain ASCII for the length prefix,
+    /// and `payload` is written as raw bytes either way.
+    fn write(&self, out: &mut impl Write) -> Result<()> {
+        let payload = self.payload();
+        out.write_all(&[self.tag()])?;
+        out.write_all(payload.len().to_string().as_bytes())?;
+        out.write_all(b":")?;
+        out.write_all(&payload)?;
+        Ok(())
+    }
+}
+
+/// One row of the counts table: an ordered list of named, typed fields.
+pub type Record = Vec<(String, TypedValue)>;
+
+/// Writes `record` as a `r<len>:` framed container whose payload is the
+/// concatenation of each field's `t<len>:name` key followed by its typed
+/// value - a map, the way netencode encodes one.
+fn write_record(out: &mut impl Write, record: &Record) -> Result<()> {
+    let mut payload = Vec::new();
+    for (name, value) in record {
+        TypedValue::Text(name.clone()).write(&mut payload)?;
+        value.write(&mut payload)?;
+    }
+    out.write_all(b"r")?;
+    out.write_all(payload.len().to_string().as_bytes())?;
+    out.write_all(b":")?;
+    out.write_all(&payload)?;
+    Ok(())
+}
+
+/// Writes `records` as a single `l<len>:` framed list of records - the
+/// whole counts table in one self-contained, length-prefixed value.
+pub fn write_binary(out: &mut impl Write, records: &[Record]) -> Result<()> {
+    let mut payload = Vec::new();
+    for record in records {
+        write_record(&mut payload, record)?;
+    }
+    out.write_all(b"l")?;
+    out.write_all(payload.len().to_string().as_bytes())?;
+    out.write_all(b":")?;
+    out.write_all(&payload)?;
+    Ok(())
+}
+
+/// Writes `records` in the same tagged/length-prefixed framing as
+/// [`write_binary`], but one record per line with fields separated by a
+/// space - same lossless encoding, easier to eyeball while debugging.
+pub fn write_text(out: &mut impl Write, records: &[Record]) -> Result<()> {
+    for record in records {
+        let mut first = true;
+        for (name, value) in record {
+            if !first {
+                out.write_all(b" ")?;
+            }
+            first = false;
+            TypedValue::Text(name.clone()).write(out)?;
+            value.write(out)?;
+        }
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_uint_framing() {
+        let mut out = Vec::new();
+        TypedValue::UInt(42).write(&mut out).unwrap();
+        assert_eq!(out, b"n2:42");
+    }
+
+    #[test]
+    fn test_int_framing_includes_sign() {
+        let mut out = Vec::new();
+        TypedValue::Int(-7).write(&mut out).unwrap();
+        assert_eq!(out, b"i2:-7");
+    }
+
+    #[test]
+    fn test_text_framing_uses_byte_length_not_char_count() {
+        let mut out = Vec::new();
+        TypedValue::Text("héllo".to_string()).write(&mut out).unwrap();
+        // 'é' is 2 bytes in UTF-8, so the length prefix is 6, not 5.
+        assert_eq!(out, "t6:héllo".as_bytes());
+    }
+
+    #[test]
+    fn test_bytes_framing_passes_through_raw_bytes() {
+        let mut out = Vec::new();
+        TypedValue::Bytes(vec![0, 255, 1]).write(&mut out).unwrap();
+        assert_eq!(out, [b'b', b'3', b':', 0, 255, 1]);
+    }
+
+    #[test]
+    fn test_write_record_wraps_fields_in_r_frame() {
+        let record: Record = vec![
+            ("gene".to_string(), TypedValue::Text("ACTB".to_string())),
+            ("count".to_string(), TypedValue::UInt(5)),
+        ];
+        let mut payload = Vec::new();
+        for (name, value) in &record {
+            TypedValue::Text(name.clone()).write(&mut payload).unwrap();
+            value.write(&mut payload).unwrap();
+        }
+        let mut expected = Vec::new();
+        expected.extend_from_slice(b"r");
+        expected.extend_from_slice(payload.len().to_string().as_bytes());
+        expected.extend_from_slice(b":");
+        expected.extend_from_slice(&payload);
+
+        let mut out = Vec::new();
+        write_record(&mut out, &record).unwrap();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_write_binary_wraps_records_in_l_frame() {
+        let records: Vec<Record> = vec![vec![("n".to_string(), TypedValue::UInt(1))]];
+        let mut out = Vec::new();
+        write_binary(&mut out, &records).unwrap();
+        assert!(out.starts_with(b"l"));
+        assert!(out.ends_with(b"t1:nn1:1"));
+    }
+
+    #[test]
+    fn test_write_text_separates_records_by_newline_and_fields_by_space() {
+        let records: Vec<Record> = vec![
+            vec![
+                ("gene".to_string(), TypedValue::Text("A".to_string())),
+                ("count".to_string(), TypedValue::UInt(3)),
+            ],
+            vec![("gene".to_string(), TypedValue::Text("B".to_string()))],
+        ];
+        let mut out = Vec::new();
+        write_text(&mut out, &records).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "t4:genet1:A t5:countn1:3");
+        assert_eq!(lines[1], "t4:genet1:B");
+    }
+}